@@ -10,13 +10,34 @@ pub(crate) const MulticallEXECUTOR: &str = "0x3312eCF4aa80937bdca0fc19E2E7De1798
 pub(crate) const ETH_ADDRESS: &str = "0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE";
 pub(crate) const WETH_ADDRESS: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
 pub(crate) const CETH_ADDRESS: &str = "0x4Ddc2D193948926D02f9B1fE9e1daa0718270ED5";
+pub(crate) const WBTC_ADDRESS: &str = "0x2260FAC5E5542a773Aa44fBCfeDf7C193bc2C599";
+pub(crate) const DAI_ADDRESS: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+pub(crate) const USDC_ADDRESS: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+pub(crate) const USDT_ADDRESS: &str = "0xdAC17F958D2ee523a2206206994597C13D831ec7";
 pub(crate) const SUSHI_TOKEN: &str = "0x6b3595068778dd592e39a122f4f5a5cf09c90fe2";
+pub(crate) const STETH_ADDRESS: &str = "0xae7ab96520DE3A18E5e111B5EaAb095312D7fE84";
+pub(crate) const WSTETH_ADDRESS: &str = "0x7f39C581F595B53c5cb19bD0b3f8dA6c935E2Ca0";
 
 // Sushiswap
 
 pub(crate) const SUSHI_MASTER_CHEF: &str = "0xc2edad668740f1aa35e4d8f227fb8e17dca888cd";
 pub(crate) const SUSHI_ROUTER: &str = "0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F";
 
+// Compound
+
+pub(crate) const COMPOUND_COMPTROLLER: &str = "0x3d9819210A31b4961b30EF54bE2aeD79B9c9Cd3B";
+
+// Vaults
+
+// ERC-4626 tokenized vaults (sDAI)
+pub(crate) const ERC4626_VAULTS: &[&str] = &["0x83F20F44975D03b1b09e64809B757c47f942BEeA"];
+// Yearn V2 vaults (yvWETH, yvDAI, yvUSDC)
+pub(crate) const YEARN_VAULTS: &[&str] = &[
+    "0xa258C4606Ca8206D8aA700cE2143D7db854D168c",
+    "0xdA816459F1AB5631232FE5e97a05BBBb94970c95",
+    "0xa354F35829Ae975e850e23e9615b11Da1B3dC4DE",
+];
+
 // Alpha Homora V1
 
 pub(crate) const AH_V1_ETH_SUSHI: &str = "0x3c2bbb353b48d54b619db8ac6aa642627fb800e3";
@@ -38,6 +59,27 @@ pub(crate) const AH_V1_ETH_YFI: &str = "0x6d0eb60d814a21e2bed483c71879777c9217aa
 pub(crate) const AH_V1_ETH_K3PR: &str = "0x795d3655d0d7ecbf26dd33b1a7676017bb0ee611";
 pub(crate) const AH_V1_ETH_BOR: &str = "0x6a279df44b5717e89b51645e287c734bd3086c1f";
 pub(crate) const AH_V1_ETH_OBTC: &str = "0x1001ec1b6fc2438e8be6ffa338d3380237c0399a";
+pub(crate) const AH_V1_ETH_GOBLINS: &[&str] = &[
+    AH_V1_ETH_SUSHI,
+    AH_V1_ETH_USDT,
+    AH_V1_ETH_DAI,
+    AH_V1_ETH_LINK,
+    AH_V1_ETH_USDC,
+    AH_V1_ETH_WBTC,
+    AH_V1_ETH_BAND,
+    AH_V1_ETH_AAVE,
+    AH_V1_ETH_COMP,
+    AH_V1_ETH_SNX,
+    AH_V1_ETH_SUSD,
+    AH_V1_ETH_UMA,
+    AH_V1_ETH_REN,
+    AH_V1_ETH_YAM,
+    AH_V1_ETH_CRV,
+    AH_V1_ETH_YFI,
+    AH_V1_ETH_K3PR,
+    AH_V1_ETH_BOR,
+    AH_V1_ETH_OBTC,
+];
 
 // Uniswap v2 Arbitrage
 
@@ -57,6 +99,25 @@ pub(crate) const FACTORY_ADDRESSES: &[&str] = &[
     ZEUS_FACTORY_ADDRESS,
     LUA_FACTORY_ADDRESS,
 ];
+// Init code hashes of the pair contracts, for deriving pair addresses
+pub(crate) const UNISWAP_INIT_CODE_HASH: &str =
+    "0x96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f";
+pub(crate) const SUSHISWAP_INIT_CODE_HASH: &str =
+    "0xe18a34eb0e04b04f7a0ac29a6e80748dca96319b42c520edaec1e5a8ae5c9ea1";
+pub(crate) const UNISWAP_ROUTER: &str = "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D";
+// Router, factory and pair init code hash
+pub(crate) const ROUTERS: &[(&str, &str, &str)] = &[
+    (
+        UNISWAP_ROUTER,
+        UNISWAP_FACTORY_ADDRESS,
+        UNISWAP_INIT_CODE_HASH,
+    ),
+    (
+        SUSHI_ROUTER,
+        SUSHISWAP_FACTORY_ADDRESS,
+        SUSHISWAP_INIT_CODE_HASH,
+    ),
+];
 pub(crate) const BLACKLISTED_TOKENS: &[&str] = &[
     "0x0698dda3c390ff92722f9eed766d8b1727621df9",
     "0x9EA3b5b4EC044b70375236A281986106457b20EF",
@@ -85,14 +146,41 @@ pub(crate) const BLACKLISTED_POOLS: &[&str] = &[
     "0x4E9e73C0170f09e709573127c4AB02e57b868178",
     "0x459e4eEAFB9e5d7299Bbbcd5b6Ab36667FfE3597",
 ];
+// Tokens taking a fee on transfer, so pairs receive less than was sent
+pub(crate) const FEE_ON_TRANSFER_TOKENS: &[&str] = &[
+    "0x45804880De22913dAFE09f4980848ECE6EcbAf78",
+    "0xa7DE087329BFcda5639247F96140f9DABe3DeED1",
+];
+// Tokens whose balances change without transfers
+pub(crate) const REBASING_TOKENS: &[&str] =
+    &["0xD46bA6D942050d489DBd938a2C909A5d5039A161", STETH_ADDRESS];
+// Tokens an admin can pause or freeze
+pub(crate) const PAUSABLE_TOKENS: &[&str] = &[
+    "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+    "0xdAC17F958D2ee523a2206206994597C13D831ec7",
+];
 pub(crate) const ORIGIN_TOKENS: &[&str] = &[
     "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2",
-    //"0x2260FAC5E5542a773Aa44fBCfeDf7C193bc2C599",
-    //"0x6B175474E89094C44Da98b954EedeAC495271d0F",
-    //"0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
-    //"0xdAC17F958D2ee523a2206206994597C13D831ec7",
+    "0x2260FAC5E5542a773Aa44fBCfeDf7C193bc2C599",
+    "0x6B175474E89094C44Da98b954EedeAC495271d0F",
+    "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+    "0xdAC17F958D2ee523a2206206994597C13D831ec7",
 ];
 
 // Flash Loan Providers
 
 pub(crate) const APE_BANK: &str = "0x00000000454a11ca3a574738c0aab442b62d5d45";
+pub(crate) const AAVE_LENDING_POOL: &str = "0x7d2768dE32b0b80b7a3454c06BdAc94A69DDc7A9";
+// Aave aTokens by underlying, which hold the liquidity that can be flash loaned
+pub(crate) const AAVE_A_TOKENS: &[(&str, &str)] = &[
+    (WETH_ADDRESS, "0x030bA81f1c18d280636F32af80b9AAd02Cf0854e"),
+    (WBTC_ADDRESS, "0x9ff58f4fFB29fA2266Ab25e75e2A8b3503311656"),
+    (DAI_ADDRESS, "0x028171bCA77440897B824Ca71D1c56caC55b68A3"),
+    (USDC_ADDRESS, "0xBcca60bB61934080951369a648Fb03DF4F96263C"),
+    (USDT_ADDRESS, "0x3Ed3B47Dd13EC9a98b44e6204A523E766B225811"),
+];
+pub(crate) const BALANCER_VAULT: &str = "0xBA12222222228d8Ba445958a75a0704d566BF2C8";
+pub(crate) const DYDX_SOLO_MARGIN: &str = "0x1E0447b19BB6EcFdAe1e4AE1694b0C3659614e4e";
+// dYdX market ids by token
+pub(crate) const DYDX_MARKETS: &[(&str, u64)] =
+    &[(WETH_ADDRESS, 0), (USDC_ADDRESS, 2), (DAI_ADDRESS, 3)];
@@ -0,0 +1,318 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use web3::contract::{Contract, Options};
+use web3::transports::WebSocket;
+use web3::types::{Address, U256, U64};
+use web3::Web3;
+
+use crate::bidding::{BidContext, BidHistory, BiddingStrategy};
+use crate::ensure_reward::EnsureReward;
+use crate::flashbots::{Bundle, BundleGenerator};
+use crate::gas::{GasPrice, MinerPayment, PaymentMode};
+use crate::markets::MarketGraph;
+use crate::sushiswap::MasterChef;
+use crate::utilities::{RewardsMeta, Transaction};
+use crate::{address_book, constants, utilities};
+
+/// Gas a reinvest is expected to use, for bidding before it is estimated
+const REINVEST_GAS: u64 = 400_000;
+
+/// Basis points denominator for reinvestBountyBps
+const BPS: u64 = 10000;
+
+/// An Alpha Homora V1 sushiswap goblin
+#[derive(Debug, Clone)]
+pub struct Goblin {
+    contract: Contract<WebSocket>,
+    pid: U256,
+    reinvest_bounty_bps: U256,
+}
+
+impl Goblin {
+    pub async fn new(transport: &Web3<WebSocket>, address: Address) -> anyhow::Result<Goblin> {
+        let contract = Contract::from_json(
+            transport.eth(),
+            address,
+            include_bytes!("protocols/alpha_homora/v1/abis/sushi_goblin.json"),
+        )?;
+        let pid: U256 = contract
+            .query("pid", (), None, Options::default(), None)
+            .await?;
+        let reinvest_bounty_bps: U256 = contract
+            .query("reinvestBountyBps", (), None, Options::default(), None)
+            .await?;
+        Ok(Goblin {
+            contract,
+            pid,
+            reinvest_bounty_bps,
+        })
+    }
+
+    pub fn address(&self) -> Address {
+        self.contract.address()
+    }
+
+    /// The sushi bounty paid to the caller of reinvest(), if the goblin could be queried
+    pub async fn bounty(
+        &self,
+        master_chef: &MasterChef,
+        sushi: &Contract<WebSocket>,
+        block_number: U64,
+    ) -> Option<U256> {
+        let (pending_reward, sushi_balance) = futures::join!(
+            master_chef.pending_reward_balance(self.pid, self.address()),
+            utilities::sushi_balance(sushi, self.address(), block_number)
+        );
+        match (pending_reward, sushi_balance) {
+            (Ok(pending_reward), Ok(sushi_balance)) => Some(reinvest_bounty(
+                pending_reward,
+                sushi_balance,
+                self.reinvest_bounty_bps,
+            )),
+            (Err(error), _) | (_, Err(error)) => {
+                warn!(
+                    "Failed to get the sushi of goblin {}: {:?}",
+                    self.address(),
+                    error
+                );
+                None
+            }
+        }
+    }
+}
+
+/// The bounty for reinvesting a goblin's sushi
+///
+/// Reinvest harvests the pending rewards into the goblin, and pays out a share of the
+/// goblin's whole sushi balance.
+fn reinvest_bounty(pending_reward: U256, sushi_balance: U256, reinvest_bounty_bps: U256) -> U256 {
+    (pending_reward + sushi_balance) * reinvest_bounty_bps / U256::from(BPS)
+}
+
+/// This engine collects reinvest bounties from Alpha Homora V1 goblins
+pub struct GoblinReinvestEngine {
+    goblins: Vec<Goblin>,
+    master_chef: MasterChef,
+    sushi: Contract<WebSocket>,
+    // When set, the miner is paid by a reward assertion rather than gas price
+    ensure_reward: Option<EnsureReward>,
+    bidding: Arc<dyn BiddingStrategy>,
+}
+
+impl GoblinReinvestEngine {
+    pub async fn new(
+        transport: &Web3<WebSocket>,
+        ensure_reward: Option<Address>,
+        bidding: Arc<dyn BiddingStrategy>,
+    ) -> GoblinReinvestEngine {
+        let mut goblins = vec![];
+        for address in address_book::AH_V1_ETH_GOBLINS {
+            match Goblin::new(transport, address.parse().unwrap()).await {
+                Ok(goblin) => goblins.push(goblin),
+                Err(error) => warn!("Failed to load goblin {}: {:?}", address, error),
+            }
+        }
+        info!("Tracking {} Alpha Homora V1 goblins.", goblins.len());
+        let sushi = Contract::from_json(
+            transport.eth(),
+            address_book::SUSHI_TOKEN.parse().unwrap(),
+            include_bytes!("abis/IERC20.json"),
+        )
+        .unwrap();
+        GoblinReinvestEngine {
+            goblins,
+            master_chef: MasterChef::new(transport),
+            // A sushi reward is asserted, so the deployment must be one checking sushi
+            ensure_reward: ensure_reward
+                .map(|address| EnsureReward::new(transport, address, sushi.address())),
+            sushi,
+            bidding,
+        }
+    }
+
+    /// Value an amount of sushi in wei using the best sushi -> weth market in the graph
+    fn sushi_to_eth(&self, markets: &MarketGraph, amount: &U256) -> U256 {
+        let weth: Address = address_book::WETH_ADDRESS.parse().unwrap();
+        match markets.graph.edge_weight(self.sushi.address(), weth) {
+            Some(token_markets) => {
+                token_markets
+                    .best_ask_market(&self.sushi.address(), &weth, amount)
+                    .1
+            }
+            None => constants::ZERO_U256,
+        }
+    }
+
+    async fn take_reinvest(
+        &self,
+        goblin: &Goblin,
+        bounty: U256,
+        bounty_eth: U256,
+        account: &Address,
+        gas_price: &GasPrice,
+        history: &BidHistory,
+    ) -> Option<(U256, Transaction)> {
+        let bid = self.bidding.bid(&BidContext {
+            profit: bounty_eth,
+            gas: U256::from(REINVEST_GAS),
+            gas_price,
+            route: &[goblin.address()],
+            history,
+        })?;
+        let miner_payment = bid.payment;
+        // Reinvest is EOA only, so without a reward assertion the miner is paid through priority fee
+        let gas_payment = match self.ensure_reward {
+            Some(_) => gas_price.payment(constants::ZERO_U256),
+            None => MinerPayment {
+                mode: PaymentMode::PriorityFee,
+                ..gas_price.payment(miner_payment)
+            },
+        };
+        let tx = utilities::generate_contract_transaction(
+            &goblin.contract,
+            "reinvest",
+            (),
+            account,
+            true,
+            gas_payment,
+        )
+        .await?;
+        Some((
+            bounty,
+            Transaction {
+                raw_profit: bounty_eth,
+                taken_profit: bounty_eth - miner_payment,
+                delta_coinbase: constants::ZERO_U256,
+                estimated_gas: tx.gas * U256::from(90) / U256::from(100),
+                parameters: tx,
+                signed: None,
+                signed_parameters: None,
+                external: false,
+                route: vec![],
+                bid: Some(bid),
+            },
+        ))
+    }
+}
+
+#[async_trait]
+impl BundleGenerator for GoblinReinvestEngine {
+    async fn generate(
+        &self,
+        markets: &MarketGraph,
+        _transport: &Web3<WebSocket>,
+        account: &Address,
+        gas_price: &GasPrice,
+        block_number: &U64,
+    ) -> Option<Bundle> {
+        let bounties = futures::future::join_all(
+            self.goblins
+                .iter()
+                .map(|goblin| goblin.bounty(&self.master_chef, &self.sushi, *block_number)),
+        )
+        .await;
+        let mut transaction_futures = vec![];
+        for (goblin, bounty) in self.goblins.iter().zip(bounties) {
+            // Skipped for this block if it couldn't be valued
+            let bounty = match bounty {
+                Some(bounty) => bounty,
+                None => continue,
+            };
+            let bounty_eth = self.sushi_to_eth(markets, &bounty);
+            debug!(
+                "Goblin {} reinvest bounty of {} sushi worth Ξ{}",
+                goblin.address(),
+                utilities::to_ether(&bounty),
+                utilities::to_ether(&bounty_eth)
+            );
+            if bounty_eth > constants::ZERO_U256 {
+                transaction_futures.push(self.take_reinvest(
+                    goblin,
+                    bounty,
+                    bounty_eth,
+                    account,
+                    gas_price,
+                    &markets.bids,
+                ));
+            }
+        }
+        let mut reinvests: Vec<(U256, Transaction)> =
+            futures::future::join_all(transaction_futures)
+                .await
+                .into_iter()
+                .flatten()
+                // The bounty must cover the gas at the bottom of the block
+                .filter(|(_, tx)| tx.estimated_gas * gas_price.low < tx.raw_profit)
+                .collect();
+        if reinvests.is_empty() {
+            return None;
+        }
+        // Largest bounties first
+        reinvests.sort_by_key(|(_, tx)| std::cmp::Reverse(tx.raw_profit));
+        let transactions = match &self.ensure_reward {
+            Some(ensure_reward) => {
+                let mut rewards = RewardsMeta {
+                    reward_token: self.sushi.address(),
+                    reward_amount: constants::ZERO_U256,
+                };
+                let mut miner_payment = constants::ZERO_U256;
+                let mut transactions = vec![];
+                for (bounty, tx) in reinvests {
+                    rewards.reward_amount += bounty;
+                    miner_payment += tx.raw_profit - tx.taken_profit;
+                    transactions.push(tx);
+                }
+                ensure_reward
+                    .bundle(transactions, &rewards, account, miner_payment, gas_price)
+                    .await?
+            }
+            None => reinvests.into_iter().map(|(_, tx)| tx).collect(),
+        };
+        let bundle = Bundle {
+            bundle_hash: None,
+            transactions,
+            block: *block_number,
+            base_fee: gas_price.base_fee,
+            executor: *account,
+        };
+        if bundle.effective_gas() > gas_price.low {
+            info!(
+                "Found {} goblin reinvest bounties worth Ξ{}",
+                bundle.transactions.len(),
+                utilities::to_ether(&(bundle.taken_profit() + bundle.miner_payment()))
+            );
+            return Some(bundle);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reinvest_bounty_shares_all_sushi() {
+        let ether = U256::exp10(18);
+        // A 1% bounty on the harvested rewards and the sushi already held
+        assert_eq!(
+            reinvest_bounty(ether * 3, ether * 7, U256::from(100)),
+            ether / 10
+        );
+        assert_eq!(
+            reinvest_bounty(ether, constants::ZERO_U256, U256::from(250)),
+            ether / 40
+        );
+        // Bounties round down to the wei
+        assert_eq!(
+            reinvest_bounty(U256::from(9999), constants::ZERO_U256, U256::one()),
+            constants::ZERO_U256
+        );
+        assert_eq!(
+            reinvest_bounty(ether, ether, constants::ZERO_U256),
+            constants::ZERO_U256
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use web3::contract::{Contract, Options};
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockId, BlockNumber, TransactionParameters, U256};
+use web3::Web3;
+
+use crate::evm::{Call, Multicall};
+use crate::flash_loans::FlashLoanProvider;
+use crate::gas::MinerPayment;
+use crate::{address_book, constants, evm, flash_loans, utilities};
+
+/// Have ApeBank call largeApeCallback on the executor
+const FLAG_LARGE_CALLBACK: u64 = 0x800;
+
+/// largeApeCallback(address,uint256,uint256,uint256,uint256,uint256,bytes)
+pub(crate) const LARGE_APE_CALLBACK: [u8; 4] = [0xb3, 0xab, 0x09, 0x95];
+
+/// A token ApeBank will lend
+#[derive(Clone, Debug)]
+struct Loanable {
+    contract: Contract<WebSocket>,
+    // The flag to borrow the token
+    flag: u64,
+    // ApeBank stores balances in multiples of this
+    multiplier: U256,
+}
+
+/// Flash loans from ApeBank
+///
+/// ApeBank lends its whole balance of each flagged token to the executor, which runs the
+/// multicall in largeApeCallback and returns the borrowed amounts. The bank requires strictly
+/// more back than it lent, so the multicall must send it one extra unit of each token.
+#[derive(Clone, Debug)]
+pub struct ApeBank {
+    contract: Contract<WebSocket>,
+    bundle_executor: Address,
+    loanable: HashMap<Address, Loanable>,
+}
+
+impl ApeBank {
+    pub fn new(transport: &Web3<WebSocket>) -> ApeBank {
+        let contract = Contract::from_json(
+            transport.eth(),
+            address_book::APE_BANK.parse().unwrap(),
+            include_bytes!("abis/ApeBank.json"),
+        )
+        .unwrap();
+        let mut loanable = HashMap::new();
+        for (token, flag, multiplier) in [
+            (address_book::WETH_ADDRESS, 0x2, U256::exp10(14)),
+            (address_book::WBTC_ADDRESS, 0x4, U256::exp10(3)),
+            (address_book::DAI_ADDRESS, 0x8, U256::exp10(18)),
+            (address_book::USDC_ADDRESS, 0x10, U256::exp10(6)),
+            (address_book::USDT_ADDRESS, 0x20, U256::exp10(6)),
+        ] {
+            let address: Address = token.parse().unwrap();
+            let contract =
+                Contract::from_json(transport.eth(), address, include_bytes!("abis/IERC20.json"))
+                    .unwrap();
+            loanable.insert(
+                address,
+                Loanable {
+                    contract,
+                    flag,
+                    multiplier,
+                },
+            );
+        }
+        ApeBank {
+            contract,
+            bundle_executor: address_book::MulticallEXECUTOR.parse().unwrap(),
+            loanable,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.contract.address()
+    }
+
+    /// Can the token be borrowed from ApeBank?
+    pub fn lends(&self, token: &Address) -> bool {
+        self.loanable.contains_key(token)
+    }
+
+    /// The amount of a token which would be lent
+    pub async fn available(&self, token: &Address) -> U256 {
+        let loanable = match self.loanable.get(token) {
+            Some(loanable) => loanable,
+            None => return constants::ZERO_U256,
+        };
+        let balance = loanable
+            .contract
+            .query::<U256, _, _, _>(
+                "balanceOf",
+                self.address(),
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await
+            .unwrap_or_default();
+        // The bank lends its stored balance, which is rounded down to the multiplier
+        (balance / loanable.multiplier).saturating_sub(constants::ONE_U256) * loanable.multiplier
+    }
+
+    /// The extra unit of the token the executor must return on top of the loan
+    pub fn repay_premium(&self, token: &Address) -> Result<Call> {
+        let loanable = self
+            .loanable
+            .get(token)
+            .ok_or_else(|| anyhow::anyhow!("ApeBank does not lend {}", token))?;
+        Call::from_contract(
+            &loanable.contract,
+            "transfer",
+            (self.address(), constants::ONE_U256),
+            evm::Type::Call,
+            None,
+        )
+    }
+
+    /// Build a flashApe transaction borrowing the tokens and running the multicall
+    pub async fn flash_loan(
+        &self,
+        tokens: &[Address],
+        multicall: &Multicall,
+        account: &Address,
+        estimate_gas: bool,
+        miner_payment: MinerPayment,
+    ) -> Option<TransactionParameters> {
+        let mut flags = FLAG_LARGE_CALLBACK;
+        for token in tokens {
+            flags |= self.loanable.get(token)?.flag;
+        }
+        // The callback reads the multicall as an abi encoded uint256[]
+        let program = flash_loans::encode_program(multicall);
+        utilities::generate_contract_transaction(
+            &self.contract,
+            "flashApe",
+            (self.bundle_executor, U256::from(flags), program),
+            account,
+            estimate_gas,
+            miner_payment,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl FlashLoanProvider for ApeBank {
+    fn name(&self) -> &'static str {
+        "ApeBank"
+    }
+
+    fn lends(&self, token: &Address) -> bool {
+        ApeBank::lends(self, token)
+    }
+
+    async fn available(&self, token: &Address) -> U256 {
+        ApeBank::available(self, token).await
+    }
+
+    // The loan is always the whole balance, so the fee is just the extra unit
+    fn fee(&self, _token: &Address, _amount: &U256) -> U256 {
+        constants::ONE_U256
+    }
+
+    fn callback_selector(&self) -> [u8; 4] {
+        LARGE_APE_CALLBACK
+    }
+
+    fn repay(&self, token: &Address, _amount: &U256) -> Result<Vec<Call>> {
+        Ok(vec![self.repay_premium(token)?])
+    }
+
+    async fn flash_loan(
+        &self,
+        token: &Address,
+        _amount: &U256,
+        multicall: &Multicall,
+        account: &Address,
+        estimate_gas: bool,
+        miner_payment: MinerPayment,
+    ) -> Option<TransactionParameters> {
+        ApeBank::flash_loan(
+            self,
+            &[*token],
+            multicall,
+            account,
+            estimate_gas,
+            miner_payment,
+        )
+        .await
+    }
+}
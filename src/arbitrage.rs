@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt;
 
 use async_trait::async_trait;
@@ -7,17 +8,27 @@ use web3::transports::WebSocket;
 use web3::types::{Address, BlockId, BlockNumber, TransactionParameters, U256, U64};
 use web3::{ethabi, Web3};
 
+use crate::bidding::{Bid, BidContext, BidHistory, BiddingStrategy};
 use crate::constants::{ETHER, FINNEY};
 use crate::evm::{Call, Multicall, MulticallHeader};
+use crate::flash_loans::{FlashLoanProvider, FlashLoanProviders};
 use crate::flashbots::{Bundle, BundleGenerator};
-use crate::gas::GasPrice;
+use crate::gas::{GasPrice, MinerPayment};
 use crate::markets::{Market, MarketGraph};
+use crate::overlay::MarketOverlay;
+use crate::tokens::TokenRegistry;
 use crate::utilities::Transaction;
 use crate::{address_book, constants, utilities};
 use rayon::prelude::*;
 use std::sync::{Arc, Mutex};
 use web3::contract::tokens::Tokenize;
 
+/// Gas limit across all the arbitrages packed into a bundle
+const BUNDLE_GAS_BUDGET: u64 = 3_000_000;
+
+/// Gas an arbitrage is expected to use, for bidding before it is estimated
+const ARBITRAGE_GAS: u64 = 250_000;
+
 /// Details about a crossed bid/ask market.
 #[derive(Clone, Debug)]
 pub struct CrossedMarketDetails<'a, T: Market + ?Sized> {
@@ -27,6 +38,8 @@ pub struct CrossedMarketDetails<'a, T: Market + ?Sized> {
     intermediary_token: Address,
     ask_market: &'a T,
     bid_market: &'a T,
+    // The amount of the origin token worth one ether
+    unit: U256,
 }
 
 /// Implementation of crossed market details for an ethmarket
@@ -36,6 +49,7 @@ impl<'a, T: Market + ?Sized> CrossedMarketDetails<'a, T> {
         intermediary_token: Address,
         bid_market: &'a T,
         ask_market: &'a T,
+        unit: U256,
     ) -> CrossedMarketDetails<'a, T> {
         let profit = constants::ZERO_U256;
         let volume = constants::ZERO_U256;
@@ -46,6 +60,7 @@ impl<'a, T: Market + ?Sized> CrossedMarketDetails<'a, T> {
             intermediary_token,
             ask_market,
             bid_market,
+            unit,
         }
     }
 
@@ -53,32 +68,14 @@ impl<'a, T: Market + ?Sized> CrossedMarketDetails<'a, T> {
     pub fn optimize_volume(&self) -> (U256, U256) {
         // First a log test sizing to isolate the maxima
         // TODO(Figure out a way to do this without a log scale to reduce iterations)
-        let test_sizes = vec![
-            FINNEY * 10,
-            FINNEY * 100,
-            FINNEY * 200,
-            FINNEY * 300,
-            FINNEY * 400,
-            FINNEY * 500,
-            FINNEY * 600,
-            FINNEY * 700,
-            FINNEY * 800,
-            FINNEY * 900,
-            ETHER,
-            ETHER * 2,
-            ETHER * 3,
-            ETHER * 4,
-            ETHER * 5,
-            ETHER * 6,
-            ETHER * 7,
-            ETHER * 8,
-            ETHER * 9,
-            ETHER * 10,
-            ETHER * 20,
-            ETHER * 30,
-            ETHER * 40,
-            ETHER * 50,
-        ];
+        // In thousandths of the unit, so the sizes are comparable across origin tokens
+        let test_sizes: Vec<U256> = vec![
+            10, 100, 200, 300, 400, 500, 600, 700, 800, 900, 1000, 2000, 3000, 4000, 5000, 6000,
+            7000, 8000, 9000, 10000, 20000, 30000, 40000, 50000,
+        ]
+        .into_iter()
+        .map(|millis: u64| self.unit * U256::from(millis) / U256::from(1000))
+        .collect();
         let mut low = test_sizes[0];
         let mut high = test_sizes[2];
         for i in 1..(test_sizes.len() - 2) {
@@ -105,9 +102,9 @@ impl<'a, T: Market + ?Sized> CrossedMarketDetails<'a, T> {
         // TODO(Size these correctly, I am losing orders on the previous version because of )
         // imprecision.
         // This is the step size for volumes to use in our search
-        let step = U256::from(1000000000_u64);
+        let step = std::cmp::max(self.unit / U256::exp10(9), constants::ONE_U256);
         // Because this is a profit precision, it can be smaller
-        let precision = U256::from(10_u64);
+        let precision = std::cmp::max(self.unit / U256::exp10(17), constants::ONE_U256);
         // Binary gradient ascent with precision?
         loop {
             profit_low = self.order_profit(&low);
@@ -139,6 +136,60 @@ impl<'a, T: Market + ?Sized> CrossedMarketDetails<'a, T> {
         (self.order_profit(&mid), mid)
     }
 
+    /// Size the order for profit from a probe order, returning the crossed market if worth taking
+    pub fn optimized(mut self) -> Option<CrossedMarketDetails<'a, T>> {
+        let probe = self.unit / U256::from(100);
+        self.profit = self.order_profit(&probe);
+        self.volume = probe;
+        let optimal_order = self.optimize_volume();
+        self.profit = optimal_order.0;
+        self.volume = optimal_order.1;
+        if self.profit() > FINNEY {
+            return Some(self);
+        }
+        None
+    }
+
+    /// Cap the order at what the executor can fund, returning the crossed market if still
+    /// worth taking
+    pub fn funded(mut self, executor_state: &ExecutorState) -> Option<CrossedMarketDetails<'a, T>> {
+        let capital = executor_state.capital(&self.origin_token);
+        if self.volume > capital && !self.flash_swappable() {
+            self.volume = capital;
+            self.profit = self.order_profit(&capital);
+        }
+        if self.profit() > FINNEY {
+            return Some(self);
+        }
+        None
+    }
+
+    /// The profit converted to wei, for comparison across origin tokens and miner payment
+    pub fn profit(&self) -> U256 {
+        if self.unit.is_zero() {
+            return constants::ZERO_U256;
+        }
+        self.profit * ETHER / self.unit
+    }
+
+    /// Can the order be funded by a flash swap out of the ask market?
+    pub fn flash_swappable(&self) -> bool {
+        matches!(
+            self.ask_market
+                .flash_sell_tokens(&self.origin_token, &self.volume),
+            Ok(Some(_))
+        )
+    }
+
+    /// The markets and intermediary token of the crossed market, blamed if taking it fails
+    pub fn route(&self) -> Vec<Address> {
+        vec![
+            self.ask_market.failure_address(),
+            self.bid_market.failure_address(),
+            self.intermediary_token,
+        ]
+    }
+
     pub fn order_profit(&self, order_size: &U256) -> U256 {
         let tokens_out = self.ask_market.get_tokens_out(
             &self.origin_token,
@@ -161,9 +212,10 @@ impl<'a, T: Market + ?Sized> CrossedMarketDetails<'a, T> {
 
 impl<'a, T: Market + ?Sized> fmt::Display for CrossedMarketDetails<'a, T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Profit: Ξ{} Volume: Ξ{} Token: {}\n Buy from: {}\n {} => {}\n Sell to: {} \n {} => {}\n\n",
-               utilities::to_ether(&self.profit),
-               utilities::to_ether(&self.volume),
+        write!(f, "Profit: Ξ{} Volume: {} of {} Token: {}\n Buy from: {}\n {} => {}\n Sell to: {} \n {} => {}\n\n",
+               utilities::to_ether(&self.profit()),
+               self.volume,
+               self.origin_token,
                self.intermediary_token,
                self.ask_market.market_address(),
                self.origin_token,
@@ -175,33 +227,121 @@ impl<'a, T: Market + ?Sized> fmt::Display for CrossedMarketDetails<'a, T> {
     }
 }
 
+impl<'a, T: Market + ?Sized> CrossedMarketDetails<'a, T> {
+    /// Describe the crossed market with token symbols and whole token amounts
+    pub fn describe(&self, tokens: &TokenRegistry) -> String {
+        format!(
+            "Profit: Ξ{} Volume: {} Token: {}\n Buy from: {}\n {} => {}\n Sell to: {} \n {} => {}\n\n",
+            utilities::to_ether(&self.profit()),
+            tokens.format_amount(&self.origin_token, &self.volume),
+            tokens.symbol(&self.intermediary_token),
+            self.ask_market.market_address(),
+            tokens.symbol(&self.origin_token),
+            tokens.symbol(&self.intermediary_token),
+            self.bid_market.market_address(),
+            tokens.symbol(&self.intermediary_token),
+            tokens.symbol(&self.origin_token)
+        )
+    }
+}
+
+/// Balances of the executor and its funding sources
+#[derive(Debug, Clone)]
+pub struct ExecutorState {
+    // Amount of each origin token each flash loan provider would lend
+    pub loan_liquidity: HashMap<Address, Vec<U256>>,
+    pub weth_balance: U256,
+    pub eth_balance: U256,
+    pub chi_balance: U256,
+    pub free_cost: U256,
+    // Executor balance of each origin token
+    pub token_balances: HashMap<Address, U256>,
+}
+
+impl ExecutorState {
+    /// The executor balance of the token
+    pub fn balance(&self, token: &Address) -> U256 {
+        self.token_balances.get(token).copied().unwrap_or_default()
+    }
+
+    /// The amount of the token an arbitrage can trade, with a flash loan if needed
+    pub fn capital(&self, token: &Address) -> U256 {
+        self.balance(token).saturating_add(
+            self.loan_liquidity(token)
+                .iter()
+                .max()
+                .copied()
+                .unwrap_or_default(),
+        )
+    }
+
+    /// The amount of the token each flash loan provider would lend
+    pub fn loan_liquidity(&self, token: &Address) -> &[U256] {
+        self.loan_liquidity
+            .get(token)
+            .map(|liquidity| liquidity.as_slice())
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Debug, Clone)]
 /// This engine finds simple a -> b -> a arbitrages
 pub struct CrossedMarketArbitrageEngine {
     bundle_executor_contract: Contract<WebSocket>,
-    ape_bank: Contract<WebSocket>,
+    flash_loans: FlashLoanProviders,
+    origin_tokens: Vec<Address>,
+    bidding: Arc<dyn BiddingStrategy>,
 }
 
 impl CrossedMarketArbitrageEngine {
-    pub async fn new(transport: &Web3<WebSocket>) -> CrossedMarketArbitrageEngine {
+    pub async fn new(
+        transport: &Web3<WebSocket>,
+        bidding: Arc<dyn BiddingStrategy>,
+    ) -> CrossedMarketArbitrageEngine {
         let bundle_executor_contract = Contract::from_json(
             transport.eth(),
             address_book::MulticallEXECUTOR.parse().unwrap(),
             include_bytes!("abis/Multicall.json"),
         )
         .unwrap();
-        let ape_bank = Contract::from_json(
-            transport.eth(),
-            address_book::APE_BANK.parse().unwrap(),
-            include_bytes!("abis/ApeBank.json"),
-        )
-        .unwrap();
+        let flash_loans = FlashLoanProviders::new(transport);
+        let origin_tokens = address_book::ORIGIN_TOKENS
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
         CrossedMarketArbitrageEngine {
             bundle_executor_contract,
-            ape_bank,
+            flash_loans,
+            origin_tokens,
+            bidding,
         }
     }
 
+    /// The amount of each origin token worth one ether, priced by the best weth market
+    ///
+    /// Tokens without a weth market fall back to one whole token by their decimals.
+    pub fn origin_units(&self, markets: &MarketGraph) -> Vec<(Address, U256)> {
+        let weth: Address = address_book::WETH_ADDRESS.parse().unwrap();
+        self.origin_tokens
+            .iter()
+            .map(|origin| {
+                if origin.0 == weth.0 {
+                    return (*origin, ETHER);
+                }
+                let unit = match markets.graph.edge_weight(weth, *origin) {
+                    Some(token_markets) => token_markets.best_ask_market(&weth, origin, &ETHER).1,
+                    None => constants::ZERO_U256,
+                };
+                if unit.is_zero() {
+                    let decimals = markets.tokens.decimals(origin).unwrap_or(18);
+                    (*origin, U256::exp10(decimals as usize))
+                } else {
+                    (*origin, unit)
+                }
+            })
+            .collect()
+    }
+
     pub fn evaluate_markets<'a>(
         &self,
         markets: &'a MarketGraph,
@@ -209,15 +349,15 @@ impl CrossedMarketArbitrageEngine {
         let mut crossed_markets: Vec<CrossedMarketDetails<dyn Market>> = vec![];
         let par_crossed_markets = Arc::new(Mutex::new(&mut crossed_markets));
 
-        let cent = constants::FINNEY * 10;
         let mut edges = vec![];
-        for address in address_book::ORIGIN_TOKENS {
-            // TODO(Case here for test size based on decimal points).
-            let origin: Address = address.parse().unwrap();
-
+        for (origin, unit) in self.origin_units(markets) {
             for edge in markets.graph.edges(origin) {
-                if edge.2.markets.len() >= 2 {
-                    edges.push((edge.0, edge.1));
+                // Tokens which don't deliver their quote can't be arbitraged from reserves
+                if edge.2.markets.len() >= 2
+                    && markets.tokens.quotable(&edge.1)
+                    && !markets.failures.is_banned(&edge.1)
+                {
+                    edges.push((edge.0, edge.1, unit));
                 }
             }
         }
@@ -233,73 +373,184 @@ impl CrossedMarketArbitrageEngine {
                     edge.1,
                     token_markets.markets.len()
                 );
+                // Leave out markets which keep failing
+                let live_markets: Vec<&dyn Market> = token_markets
+                    .markets
+                    .iter()
+                    .map(|market| market.as_ref())
+                    .filter(|market| !markets.failures.is_banned(&market.failure_address()))
+                    .collect();
+                if live_markets.len() < 2 {
+                    return;
+                }
+                // Probe with a hundredth of the unit of the origin token
+                let cent = edge.2 / U256::from(100);
                 // Buy tokens from origin
-                let best_ask = token_markets.best_ask_market(&edge.0, &edge.1, &cent);
+                let best_ask = live_markets
+                    .iter()
+                    .map(|market| (*market, market.get_tokens_out(&edge.0, &edge.1, &cent)))
+                    .max_by_key(|(_, offer)| *offer)
+                    .unwrap();
                 // Sell tokens to get back to origin
-                let best_bid = token_markets.best_bid_market(&edge.1, &edge.0, &cent);
+                let best_bid = live_markets
+                    .iter()
+                    .map(|market| (*market, market.get_tokens_in(&edge.1, &edge.0, &cent)))
+                    .min_by_key(|(_, bid)| *bid)
+                    .unwrap();
                 // If the output from buying is greater than the input for selling...
                 if best_ask.1 > best_bid.1 {
-                    let mut crossed_market =
-                        CrossedMarketDetails::new(edge.0, edge.1, best_bid.0, best_ask.0);
-                    let profit = crossed_market.order_profit(&cent);
-                    crossed_market.profit = profit;
-                    crossed_market.volume = cent;
-                    let optimal_order = crossed_market.optimize_volume();
-                    crossed_market.profit = optimal_order.0;
-                    crossed_market.volume = optimal_order.1;
-                    if crossed_market.profit > constants::FINNEY {
+                    if let Some(crossed_market) =
+                        CrossedMarketDetails::new(edge.0, edge.1, best_bid.0, best_ask.0, edge.2)
+                            .optimized()
+                    {
                         par_crossed_markets.lock().unwrap().push(crossed_market)
                     }
                 }
             })
             .collect();
         // Sort best crossed markets by profit
-        crossed_markets.sort_by(|a, b| b.profit.cmp(&a.profit));
+        crossed_markets.sort_by(|a, b| b.profit().cmp(&a.profit()));
         // Return crossed market(s)
         for market in crossed_markets.iter() {
-            debug!("{}", market)
+            debug!("{}", market.describe(&markets.tokens))
         }
         crossed_markets
     }
 
+    /// Query the balances of the executor relevant to taking crossed markets
+    pub async fn executor_state(&self) -> ExecutorState {
+        let weth: Address = address_book::ORIGIN_TOKENS[0].parse().unwrap();
+        let weth_balance = self
+            .bundle_executor_contract
+            .query::<U256, _, _, _>(
+                "balanceOf",
+                weth,
+                None,
+                Default::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await
+            .unwrap();
+
+        // Get eth balance of executor
+        let eth: Address = address_book::ETH_ADDRESS.parse().unwrap();
+        let eth_balance = self
+            .bundle_executor_contract
+            .query::<U256, _, _, _>(
+                "balanceOf",
+                eth,
+                None,
+                Default::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await
+            .unwrap();
+        let free_cost = self
+            .bundle_executor_contract
+            .query::<U256, _, _, _>(
+                "freeCost",
+                (),
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await
+            .unwrap();
+        let chi_balance = self
+            .bundle_executor_contract
+            .query::<U256, _, _, _>(
+                "gasTokenBalance",
+                (),
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await
+            .unwrap();
+        let mut token_balances = HashMap::new();
+        let mut loan_liquidity = HashMap::new();
+        for origin in self.origin_tokens.iter() {
+            let balance = self
+                .bundle_executor_contract
+                .query::<U256, _, _, _>(
+                    "balanceOf",
+                    *origin,
+                    None,
+                    Options::default(),
+                    BlockId::from(BlockNumber::Latest),
+                )
+                .await
+                .unwrap_or_default();
+            token_balances.insert(*origin, balance);
+            loan_liquidity.insert(*origin, self.flash_loans.liquidity(origin).await);
+        }
+        ExecutorState {
+            loan_liquidity,
+            weth_balance,
+            eth_balance,
+            chi_balance,
+            free_cost,
+            token_balances,
+        }
+    }
+
+    /// Build the transaction taking a crossed market, with the bid paying for it
+    ///
+    /// A bid without a transaction means the gas estimate reverted, anything else which stops
+    /// the arbitrage being taken returns neither.
+    #[allow(clippy::too_many_arguments)]
     pub async fn take_crossed_market<T: Market + ?Sized>(
         &self,
         crossed_market: &CrossedMarketDetails<'_, T>,
         account: &Address,
-        ape_weth_balance: U256,
-        weth_balance: &U256,
-        eth_balance: &U256,
-        chi_balance: &U256,
-        free_cost: &U256,
-    ) -> (Option<TransactionParameters>, U256) {
+        executor_state: &ExecutorState,
+        gas_price: &GasPrice,
+        history: &BidHistory,
+        desired_block: u64,
+        estimate_gas: bool,
+    ) -> (Option<TransactionParameters>, Option<Bid>) {
+        let weth: Address = address_book::WETH_ADDRESS.parse().unwrap();
+        let weth_balance = &executor_state.weth_balance;
+        let eth_balance = &executor_state.eth_balance;
+        let origin_balance = executor_state.balance(&crossed_market.origin_token);
         debug!("Generating calls for {}", crossed_market);
+        // Without the inventory, borrow it from the ask market if it flash swaps, or flash loan it
+        let flash_swap = if origin_balance < crossed_market.volume {
+            crossed_market
+                .ask_market
+                .flash_sell_tokens(&crossed_market.origin_token, &crossed_market.volume)
+                .unwrap_or(None)
+        } else {
+            None
+        };
+        let mut flash_loan = None;
+        let mut loan_fee = constants::ZERO_U256;
+        if origin_balance < crossed_market.volume && flash_swap.is_none() {
+            // Then we'll need a flash loan of the shortfall from the cheapest provider
+            let amount = crossed_market.volume - origin_balance;
+            let provider = match self.flash_loans.choose(
+                &crossed_market.origin_token,
+                &amount,
+                executor_state.loan_liquidity(&crossed_market.origin_token),
+            ) {
+                Some(provider) => provider,
+                None => return (None, None),
+            };
+            debug!(
+                "Flash loan of {} from {} calling back 0x{}",
+                amount,
+                provider.name(),
+                hex::encode(provider.callback_selector())
+            );
+            loan_fee =
+                provider.fee(&crossed_market.origin_token, &amount) * ETHER / crossed_market.unit;
+            flash_loan = Some((provider, amount));
+        }
+
         // This will be flattened into a vector of calls later
         let mut calls: Vec<Vec<Call>> = vec![];
         // TODO(Move all the blocking work to rayon)
 
-        // TODO(Handle prepare, setting up approvals, etc)
-        // Send tokens to first market if needed
-        // TODO(Handle error gracefully)
-        let to_first_market = crossed_market
-            .ask_market
-            .to_first_market(&crossed_market.origin_token, &crossed_market.volume)
-            .unwrap();
-        // Push calls if any
-        if let Some(call) = to_first_market {
-            calls.push(call)
-        }
-
-        // Perform origin to intermediary transit, sending funds to the next contract
-        let buy_call = crossed_market
-            .ask_market
-            .sell_tokens(
-                &crossed_market.origin_token,
-                &crossed_market.volume,
-                &crossed_market.bid_market.market_address(),
-            )
-            .unwrap();
-        calls.push(buy_call);
-
         // Calculate the amount out of the first market to swap out of the 2nd market
         let inter = crossed_market.ask_market.get_tokens_out(
             &crossed_market.origin_token,
@@ -307,6 +558,44 @@ impl CrossedMarketArbitrageEngine {
             &crossed_market.volume,
         );
 
+        // TODO(Handle prepare, setting up approvals, etc)
+        // TODO(Handle error gracefully)
+        match flash_swap {
+            Some(flash_swap) => {
+                // The intermediary comes to the executor, and the ask market is repaid last
+                calls.push(flash_swap);
+                let to_second_market = crossed_market
+                    .bid_market
+                    .to_first_market(&crossed_market.intermediary_token, &inter)
+                    .unwrap();
+                if let Some(call) = to_second_market {
+                    calls.push(call)
+                }
+            }
+            None => {
+                // Send tokens to first market if needed
+                let to_first_market = crossed_market
+                    .ask_market
+                    .to_first_market(&crossed_market.origin_token, &crossed_market.volume)
+                    .unwrap();
+                // Push calls if any
+                if let Some(call) = to_first_market {
+                    calls.push(call)
+                }
+
+                // Perform origin to intermediary transit, sending funds to the next contract
+                let buy_call = crossed_market
+                    .ask_market
+                    .sell_tokens(
+                        &crossed_market.origin_token,
+                        &crossed_market.volume,
+                        &crossed_market.bid_market.market_address(),
+                    )
+                    .unwrap();
+                calls.push(buy_call);
+            }
+        }
+
         // Perform intermediary to origin transit, sending funds back to contract
         let sell_call = crossed_market
             .bid_market
@@ -321,58 +610,313 @@ impl CrossedMarketArbitrageEngine {
         // Flatten vector of vector of calls
         let calls: Vec<Call> = calls.into_iter().flatten().collect();
 
-        // Calculate miner payment
-
-        // The percentage is that of the most "unique" edge
-        // TODO(Handle case where no percentage is returned (None))
-        let miner_payment_percentage = std::cmp::min(
-            crossed_market.bid_market.miner_reward_percentage().unwrap(),
-            crossed_market.ask_market.miner_reward_percentage().unwrap(),
-        );
-
-        let mut ape = false;
-        if weth_balance < &crossed_market.volume {
-            // Then we'll need a flash loan
-            ape = true;
+        // The loan fee comes out of the profit before the miner is paid
+        if crossed_market.profit() <= loan_fee {
+            return (None, None);
         }
-
-        // TODO()
-        let miner_payment = (crossed_market.profit * miner_payment_percentage) / U256::from(100);
+        let bid = match self.bidding.bid(&BidContext {
+            profit: crossed_market.profit() - loan_fee,
+            gas: U256::from(ARBITRAGE_GAS),
+            gas_price,
+            route: &crossed_market.route(),
+            history,
+        }) {
+            Some(bid) => bid,
+            None => return (None, None),
+        };
+        let miner_payment = gas_price.payment(bid.payment);
+        let coinbase_transfer = miner_payment.coinbase_transfer();
 
         // Check if we need to convert some of the origin token to eth
         let mut pay_with_weth = false;
-        if eth_balance < &miner_payment {
+        if eth_balance < &coinbase_transfer {
+            // Profits in other origin tokens can't pay the miner, so it has to come from inventory
+            if crossed_market.origin_token.0 != weth.0 && weth_balance < &coinbase_transfer {
+                // Nothing wrong with the route, so it isn't reported as a revert
+                debug!(
+                    "Not enough WETH or ETH to pay the coinbase for {}",
+                    crossed_market
+                );
+                return (None, None);
+            }
             pay_with_weth = true
         }
 
-        // We aren't sandwiching or backrunning, so we don't care about the desired block so much
-        let desired_block = constants::ZERO_U256;
-
-        // Build multicall header
-        let mch: MulticallHeader = MulticallHeader::new(
-            pay_with_weth,
-            false,
-            miner_payment.as_u128(),
-            desired_block.as_u64(),
+        // Estimated unpinned, as nodes estimate against the latest block rather than the desired one
+        let mut multicall = Multicall::new(
+            MulticallHeader::new(pay_with_weth, false, coinbase_transfer.as_u128(), 0),
+            calls,
         );
+        if let Some((provider, amount)) = flash_loan {
+            // The loan is repaid from the proceeds, plus the fee
+            match provider.repay(&crossed_market.origin_token, &amount) {
+                Ok(repay_calls) => multicall.extend(repay_calls),
+                Err(error) => {
+                    debug!(
+                        "Failed to repay flash loan from {}: {:?}",
+                        provider.name(),
+                        error
+                    );
+                    return (None, None);
+                }
+            }
+        }
+        let token = &crossed_market.origin_token;
+        let mut tx = match self
+            .build_transaction(
+                flash_loan,
+                token,
+                &multicall,
+                account,
+                estimate_gas,
+                miner_payment,
+            )
+            .await
+        {
+            Some(tx) => tx,
+            None => return (None, Some(bid)),
+        };
+        // Pinned to the target block for signing, so it can't be replayed in an uncle or later
+        multicall.pin(desired_block);
+        let pinned = match self
+            .build_transaction(flash_loan, token, &multicall, account, false, miner_payment)
+            .await
+        {
+            Some(pinned) => pinned,
+            None => return (None, None),
+        };
+        // The block number costs a little more calldata than the zeros it replaces
+        tx.gas += U256::from(utilities::calldata_gas(&pinned.data.0))
+            .saturating_sub(U256::from(utilities::calldata_gas(&tx.data.0)));
+        tx.data = pinned.data;
+        (Some(tx), Some(bid))
+    }
+
+    /// The transaction running the multicall, through the flash loan if there is one
+    async fn build_transaction(
+        &self,
+        flash_loan: Option<(&dyn FlashLoanProvider, U256)>,
+        token: &Address,
+        multicall: &Multicall,
+        account: &Address,
+        estimate_gas: bool,
+        miner_payment: MinerPayment,
+    ) -> Option<TransactionParameters> {
+        match flash_loan {
+            Some((provider, amount)) => {
+                provider
+                    .flash_loan(
+                        token,
+                        &amount,
+                        multicall,
+                        account,
+                        estimate_gas,
+                        miner_payment,
+                    )
+                    .await
+            }
+            None => {
+                utilities::generate_contract_transaction(
+                    &self.bundle_executor_contract,
+                    "ostium",
+                    multicall.encode_parameters(),
+                    account,
+                    estimate_gas,
+                    miner_payment,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Greedily pack crossed markets into one bundle, in order of profit per gas
+    ///
+    /// Arbitrages through markets an earlier arbitrage was taken through are re-sized against
+    /// the reserves it left, and their gas estimated again. Arbitrages which are no longer
+    /// profitable, or which don't fit the gas budget, are dropped.
+    #[allow(clippy::too_many_arguments)]
+    async fn pack_crossed_markets<'a>(
+        &self,
+        markets: &'a MarketGraph,
+        crossed_markets: &[CrossedMarketDetails<'a, dyn Market + 'a>],
+        crossed_market_results: Vec<(usize, Transaction)>,
+        account: &Address,
+        executor_state: &ExecutorState,
+        gas_price: &GasPrice,
+        desired_block: u64,
+    ) -> Vec<Transaction> {
+        let mut packer = Packer::new(markets);
+        let mut packed = vec![];
+        for (crossed_market_idx, transaction) in crossed_market_results {
+            let crossed_market = &crossed_markets[crossed_market_idx];
+            let (transaction, volume) = if packer.conflicts(crossed_market) {
+                let crossed_market = match packer.resize(crossed_market, executor_state) {
+                    Some(crossed_market) => crossed_market,
+                    None => continue,
+                };
+                // Estimated against the chain without the earlier arbitrages, which leaves
+                // the smaller re-sized order at least as much room
+                let (tx, bid) = self
+                    .take_crossed_market(
+                        &crossed_market,
+                        account,
+                        executor_state,
+                        gas_price,
+                        &markets.bids,
+                        desired_block,
+                        true,
+                    )
+                    .await;
+                match (tx, bid) {
+                    (Some(tx), Some(bid)) => (
+                        crossed_market_transaction(&crossed_market, tx, bid, gas_price),
+                        crossed_market.volume,
+                    ),
+                    _ => continue,
+                }
+            } else {
+                (transaction, crossed_market.volume)
+            };
+            if !packer.fits(&transaction.parameters.gas) {
+                continue;
+            }
+            debug!(
+                "Packing arbitrage {} of {} into bundle",
+                packed.len() + 1,
+                crossed_markets.len()
+            );
+            let modelled = packer.take(crossed_market, &volume, &transaction.parameters.gas);
+            packed.push(transaction);
+            if !modelled {
+                // The markets can't model the swap, so nothing after this can be re-evaluated
+                break;
+            }
+        }
+        packed
+    }
+}
+
+/// The arbitrages packed into a bundle so far, applied to an overlay of the graph
+///
+/// Later arbitrages through the same markets see the reserves left by the earlier ones.
+struct Packer<'a> {
+    overlay: MarketOverlay<'a>,
+    gas_used: U256,
+}
 
-        // Encode transaction parameters
-        let multicall = Multicall::new(mch, calls);
-        let params = multicall.encode_parameters();
-        let tx;
-        tx = utilities::generate_contract_transaction(
-            &self.bundle_executor_contract,
-            "ostium",
-            params,
-            account,
-            true,
-            miner_payment,
+impl<'a> Packer<'a> {
+    fn new(markets: &'a MarketGraph) -> Packer<'a> {
+        Packer {
+            overlay: MarketOverlay::new(markets),
+            gas_used: constants::ZERO_U256,
+        }
+    }
+
+    /// Does the arbitrage trade through a market an earlier arbitrage was taken through?
+    fn conflicts<T: Market + ?Sized>(&self, crossed_market: &CrossedMarketDetails<'_, T>) -> bool {
+        self.overlay
+            .is_pending(&crossed_market.ask_market.market_address())
+            || self
+                .overlay
+                .is_pending(&crossed_market.bid_market.market_address())
+    }
+
+    /// Re-size an arbitrage against the overlay, if it's still worth taking
+    fn resize<T: Market + ?Sized>(
+        &self,
+        crossed_market: &CrossedMarketDetails<'_, T>,
+        executor_state: &ExecutorState,
+    ) -> Option<CrossedMarketDetails<'_, dyn Market + '_>> {
+        let origin = crossed_market.origin_token;
+        let intermediary = crossed_market.intermediary_token;
+        let ask_market = self.overlay.market(
+            &origin,
+            &intermediary,
+            &crossed_market.ask_market.market_address(),
+        )?;
+        let bid_market = self.overlay.market(
+            &origin,
+            &intermediary,
+            &crossed_market.bid_market.market_address(),
+        )?;
+        CrossedMarketDetails::new(
+            origin,
+            intermediary,
+            bid_market,
+            ask_market,
+            crossed_market.unit,
         )
-        .await;
-        (tx, miner_payment)
+        .optimized()?
+        .funded(executor_state)
+    }
+
+    /// Is there room left in the gas budget for the transaction?
+    fn fits(&self, gas: &U256) -> bool {
+        self.gas_used + gas <= U256::from(BUNDLE_GAS_BUDGET)
+    }
+
+    /// Take the arbitrage at the volume, returning false if the overlay can't model it
+    fn take<T: Market + ?Sized>(
+        &mut self,
+        crossed_market: &CrossedMarketDetails<'_, T>,
+        volume: &U256,
+        gas: &U256,
+    ) -> bool {
+        self.gas_used += *gas;
+        let origin = crossed_market.origin_token;
+        let intermediary = crossed_market.intermediary_token;
+        let ask_address = crossed_market.ask_market.market_address();
+        let bid_address = crossed_market.bid_market.market_address();
+        self.overlay
+            .get_tokens_out(&ask_address, &origin, &intermediary, volume)
+            .and_then(|tokens_out| {
+                self.overlay
+                    .swap(&ask_address, &origin, &intermediary, volume)?;
+                self.overlay
+                    .swap(&bid_address, &intermediary, &origin, &tokens_out)
+            })
+            .is_some()
+    }
+}
+
+/// Aggregate the profit and loss of a crossed market transaction
+pub fn crossed_market_transaction<T: Market + ?Sized>(
+    crossed_market: &CrossedMarketDetails<'_, T>,
+    tx: TransactionParameters,
+    bid: Bid,
+    gas_price: &GasPrice,
+) -> Transaction {
+    let profit = crossed_market.profit();
+    Transaction {
+        raw_profit: profit,
+        taken_profit: profit - bid.payment,
+        delta_coinbase: gas_price.payment(bid.payment).coinbase_transfer(),
+        // TODO(Get this from a local simulation before submitting to filter better)
+        estimated_gas: tx.gas * U256::from(90) / U256::from(100),
+        parameters: tx,
+        signed: None,
+        signed_parameters: None,
+        external: false,
+        route: crossed_market.route(),
+        bid: Some(bid),
     }
 }
 
+/// Sort desc by what each transaction bids the miner per gas
+///
+/// The bid is what the miner is paid however it's paid, where delta_coinbase is zero when
+/// paying through the priority fee.
+fn sort_by_bid_per_gas(results: &mut [(usize, Transaction)]) {
+    let bid_per_gas = |transaction: &Transaction| {
+        transaction
+            .bid
+            .as_ref()
+            .map(|bid| bid.payment / transaction.estimated_gas)
+            .unwrap_or_default()
+    };
+    results.sort_by_key(|(_, transaction)| std::cmp::Reverse(bid_per_gas(transaction)));
+}
+
 //
 //
 #[async_trait]
@@ -380,82 +924,21 @@ impl BundleGenerator for CrossedMarketArbitrageEngine {
     async fn generate(
         &self,
         markets: &MarketGraph,
-        transport: &Web3<WebSocket>,
+        _transport: &Web3<WebSocket>,
         account: &Address,
         gas_price: &GasPrice,
         block_number: &U64,
     ) -> Option<Bundle> {
         // TODO(Make this async)
         // These simulations could all run in parallel
-        // TODO(Support multiple origin tokens, non weth)
-        let weth: Address = address_book::ORIGIN_TOKENS[0].parse().unwrap();
-        let weth_contract = Contract::from_json(
-            transport.eth(),
-            address_book::WETH_ADDRESS.parse().unwrap(),
-            include_bytes!("abis/WETH9.json"),
-        )
-        .unwrap();
-        let weth_balance: &U256 = &self
-            .bundle_executor_contract
-            .query::<U256, _, _, _>(
-                "balanceOf",
-                weth,
-                None,
-                Default::default(),
-                BlockId::from(BlockNumber::Latest),
-            )
-            .await
-            .unwrap();
+        let executor_state = self.executor_state().await;
 
-        // Get eth balance of executor
-        let eth: Address = address_book::ETH_ADDRESS.parse().unwrap();
-        let eth_balance: &U256 = &self
-            .bundle_executor_contract
-            .query::<U256, _, _, _>(
-                "balanceOf",
-                eth,
-                None,
-                Default::default(),
-                BlockId::from(BlockNumber::Latest),
-            )
-            .await
-            .unwrap();
-        let ape_bank: Address = address_book::APE_BANK.parse().unwrap();
-        let ape_weth_balance = weth_contract
-            .query::<U256, _, _, _>(
-                "balanceOf",
-                ape_bank,
-                None,
-                Options::default(),
-                BlockId::from(BlockNumber::Latest),
-            )
-            .await
-            .unwrap();
-        let free_cost = &self
-            .bundle_executor_contract
-            .query::<U256, _, _, _>(
-                "freeCost",
-                (),
-                None,
-                Options::default(),
-                BlockId::from(BlockNumber::Latest),
-            )
-            .await
-            .unwrap();
-        let chi_balance = &self
-            .bundle_executor_contract
-            .query::<U256, _, _, _>(
-                "gasTokenBalance",
-                (),
-                None,
-                Options::default(),
-                BlockId::from(BlockNumber::Latest),
-            )
-            .await
-            .unwrap();
-
-        // This stores all the crossed markets found in the graph
-        let sorted_crossed_markets = self.evaluate_markets(markets);
+        // This stores all the crossed markets found in the graph, sized to what can be funded
+        let sorted_crossed_markets: Vec<CrossedMarketDetails<dyn Market>> = self
+            .evaluate_markets(markets)
+            .into_iter()
+            .filter_map(|crossed_market| crossed_market.funded(&executor_state))
+            .collect();
 
         // This stores all the crossed markets and generated transactions
         let mut crossed_market_transaction_futures = vec![];
@@ -465,57 +948,193 @@ impl BundleGenerator for CrossedMarketArbitrageEngine {
             crossed_market_transaction_futures.push(self.take_crossed_market(
                 crossed_market,
                 account,
-                ape_weth_balance,
-                weth_balance,
-                eth_balance,
-                chi_balance,
-                free_cost,
+                &executor_state,
+                gas_price,
+                &markets.bids,
+                block_number.as_u64() + 1,
+                true,
             ));
         }
-        let crossed_market_transactions: Vec<(Option<TransactionParameters>, U256)> =
+        let crossed_market_transactions: Vec<(Option<TransactionParameters>, Option<Bid>)> =
             futures::future::join_all(crossed_market_transaction_futures).await;
         let mut crossed_market_results: Vec<(usize, Transaction)> = vec![];
         for (crossed_market_idx, tx_tup) in crossed_market_transactions.into_iter().enumerate() {
-            if let Some(tx) = tx_tup.0 {
-                let gas_estimate = tx.gas * U256::from(90) / U256::from(100);
-                let profit = sorted_crossed_markets[crossed_market_idx].profit;
-                crossed_market_results.push((
+            let crossed_market = &sorted_crossed_markets[crossed_market_idx];
+            match tx_tup {
+                (Some(tx), Some(bid)) => crossed_market_results.push((
                     crossed_market_idx,
-                    utilities::Transaction {
-                        raw_profit: profit,
-                        taken_profit: profit - tx_tup.1,
-                        delta_coinbase: tx_tup.1,
-                        // TODO(Get this from a local simulation before submitting to filter better)
-                        estimated_gas: gas_estimate,
-                        parameters: tx,
-                        signed: None,
-                    },
-                ))
+                    crossed_market_transaction(crossed_market, tx, bid, gas_price),
+                )),
+                // The gas estimate reverted, the only failure returned with a bid
+                (None, Some(_)) => markets
+                    .failures
+                    .record(&crossed_market.route(), block_number.as_u64()),
+                // Not worth bidding on
+                _ => (),
             }
         }
         if crossed_market_results.is_empty() {
             return None;
         }
-        // Sort desc by profit per gas
-        crossed_market_results.sort_by(|cm1, cm2| {
-            (cm2.1.delta_coinbase / cm2.1.estimated_gas)
-                .cmp(&(cm1.1.delta_coinbase / cm1.1.estimated_gas))
-        });
+        sort_by_bid_per_gas(&mut crossed_market_results);
         // TODO(tranche these by gas price and chose)
         // A sneaky move to get stuff the simple-arbitrage kids are not might be to grab the 2nd slot
-        let final_txns = vec![crossed_market_results[0].1.clone()];
+        let final_txns = self
+            .pack_crossed_markets(
+                markets,
+                &sorted_crossed_markets,
+                crossed_market_results,
+                account,
+                &executor_state,
+                gas_price,
+                block_number.as_u64() + 1,
+            )
+            .await;
         let bundle = Bundle {
             bundle_hash: None,
             transactions: final_txns,
             block: *block_number,
+            base_fee: gas_price.base_fee,
+            executor: *account,
         };
         if bundle.effective_gas() > gas_price.low {
             info!(
-                "Found Arbitrage: {}",
-                sorted_crossed_markets[crossed_market_results[0].0]
+                "Found {} arbitrage(s) paying Ξ{} to the miner",
+                bundle.transactions.len(),
+                utilities::to_ether(&bundle.miner_payment())
             );
             return Some(bundle);
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gas::PaymentMode;
+    use crate::markets::testing::{graph, ConstantProduct};
+    use crate::markets::TokenPair;
+
+    fn pair(address: u8, token: u8, reserve_w: u64, reserve_x: u64) -> ConstantProduct {
+        ConstantProduct {
+            address: Address::repeat_byte(address),
+            tokens: TokenPair {
+                i: Address::repeat_byte(0xee),
+                j: Address::repeat_byte(token),
+            },
+            reserve_i: ETHER * reserve_w,
+            reserve_j: ETHER * reserve_x,
+        }
+    }
+
+    fn executor_state() -> ExecutorState {
+        ExecutorState {
+            loan_liquidity: HashMap::new(),
+            weth_balance: ETHER * 10_000_u64,
+            eth_balance: constants::ZERO_U256,
+            chi_balance: constants::ZERO_U256,
+            free_cost: constants::ZERO_U256,
+            token_balances: vec![(Address::repeat_byte(0xee), ETHER * 10_000_u64)]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn conflicting_arbitrages_are_resized() {
+        // Two markets crossed on one token, and two on another
+        let (ask, bid) = (pair(2, 1, 1000, 1100), pair(3, 1, 1100, 1000));
+        let (other_ask, other_bid) = (pair(4, 2, 1000, 1100), pair(5, 2, 1100, 1000));
+        let markets = graph(vec![
+            ask.clone(),
+            bid.clone(),
+            other_ask.clone(),
+            other_bid.clone(),
+        ]);
+        let state = executor_state();
+        let mut packer = Packer::new(&markets);
+        let crossed_market =
+            CrossedMarketDetails::new(ask.tokens.i, ask.tokens.j, &bid, &ask, ETHER)
+                .optimized()
+                .unwrap();
+        let other = CrossedMarketDetails::new(
+            other_ask.tokens.i,
+            other_ask.tokens.j,
+            &other_bid,
+            &other_ask,
+            ETHER,
+        )
+        .optimized()
+        .unwrap();
+        assert!(!packer.conflicts(&crossed_market));
+        // Taking half the arbitrage leaves a smaller one
+        let half = crossed_market.volume / 2;
+        assert!(packer.take(&crossed_market, &half, &U256::from(ARBITRAGE_GAS)));
+        assert!(packer.conflicts(&crossed_market));
+        assert!(!packer.conflicts(&other));
+        let resized = packer.resize(&crossed_market, &state).unwrap();
+        assert!(resized.volume < crossed_market.volume);
+        assert!(resized.profit < crossed_market.profit);
+        // And taking the rest leaves nothing worth taking
+        let rest = resized.volume;
+        assert!(packer.take(&crossed_market, &rest, &U256::from(ARBITRAGE_GAS)));
+        assert!(packer.resize(&crossed_market, &state).is_none());
+    }
+
+    #[test]
+    fn packing_respects_the_gas_budget() {
+        let (ask, bid) = (pair(2, 1, 1000, 1100), pair(3, 1, 1100, 1000));
+        let markets = graph(vec![ask.clone(), bid.clone()]);
+        let mut packer = Packer::new(&markets);
+        assert!(packer.fits(&U256::from(BUNDLE_GAS_BUDGET)));
+        let crossed_market =
+            CrossedMarketDetails::new(ask.tokens.i, ask.tokens.j, &bid, &ask, ETHER)
+                .optimized()
+                .unwrap();
+        let volume = crossed_market.volume;
+        assert!(packer.take(&crossed_market, &volume, &U256::from(2_000_000)));
+        assert!(!packer.fits(&U256::from(1_500_000)));
+        assert!(packer.fits(&U256::from(1_000_000)));
+        // Gas is counted even when the overlay can't model the swap
+        let (unknown_ask, unknown_bid) = (pair(4, 2, 1000, 1100), pair(5, 2, 1100, 1000));
+        let unknown = CrossedMarketDetails::new(
+            unknown_ask.tokens.i,
+            unknown_ask.tokens.j,
+            &unknown_bid,
+            &unknown_ask,
+            ETHER,
+        );
+        assert!(!packer.take(&unknown, &volume, &U256::from(500_000)));
+        assert!(!packer.fits(&U256::from(1_000_000)));
+        // Candidates are packed in order of their bid per gas, however the miner is paid
+        let gas_price = GasPrice {
+            ludicrous: constants::ZERO_U256,
+            high: constants::ZERO_U256,
+            medium: constants::ZERO_U256,
+            low: constants::ZERO_U256,
+            base_fee: U256::from(100),
+            payment_mode: PaymentMode::PriorityFee,
+        };
+        let candidate = |payment: u64, gas: u64| {
+            let tx = TransactionParameters {
+                gas: U256::from(gas),
+                ..Default::default()
+            };
+            let bid = Bid {
+                payment: U256::from(payment),
+                rationale: String::new(),
+            };
+            crossed_market_transaction(&crossed_market, tx, bid, &gas_price)
+        };
+        let mut candidates = vec![
+            (0, candidate(1_000_000, 1_000_000)),
+            (1, candidate(3_000_000, 1_000_000)),
+            (2, candidate(3_000_000, 500_000)),
+        ];
+        assert!(candidates.iter().all(|(_, tx)| tx.delta_coinbase.is_zero()));
+        sort_by_bid_per_gas(&mut candidates);
+        let order: Vec<usize> = candidates.iter().map(|(index, _)| *index).collect();
+        assert_eq!(order, vec![2, 1, 0]);
+    }
+}
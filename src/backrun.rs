@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{debug, info};
+use web3::ethabi;
+use web3::transports::WebSocket;
+use web3::types::{Address, Bytes, SignedTransaction, TransactionParameters, H256, U256, U64};
+use web3::{Transport, Web3};
+
+use crate::arbitrage::{
+    crossed_market_transaction, CrossedMarketArbitrageEngine, CrossedMarketDetails,
+};
+use crate::bidding::BiddingStrategy;
+use crate::flashbots::Bundle;
+use crate::gas::GasPrice;
+use crate::markets::{Market, MarketGraph};
+use crate::overlay::MarketOverlay;
+use crate::uniswap_router::{RouterDecoder, RouterIntent, SwapAmount};
+use crate::utilities::Transaction;
+use crate::{constants, utilities};
+
+fn u256_h256(value: U256) -> H256 {
+    let mut bytes = [0_u8; 32];
+    value.to_big_endian(&mut bytes);
+    H256::from(bytes)
+}
+
+/// This engine backruns pending Uniswap V2 swaps
+///
+/// Pending router and pair swaps are applied to copies of the affected pairs, and the
+/// crossed market search is run against the post swap state of their edges. A bundle is the
+/// pending transaction followed by the arbitrage.
+pub struct BackrunEngine {
+    arbitrage: CrossedMarketArbitrageEngine,
+    decoder: RouterDecoder,
+    pair_abi: ethabi::Contract,
+    // Pair -> tokens, for swaps made directly on a pair
+    pairs: HashMap<Address, (Address, Address)>,
+}
+
+impl BackrunEngine {
+    pub async fn new(
+        transport: &Web3<WebSocket>,
+        markets: &MarketGraph,
+        bidding: Arc<dyn BiddingStrategy>,
+    ) -> BackrunEngine {
+        let pair_abi =
+            ethabi::Contract::load(&include_bytes!("protocols/uniswap/v2/abis/pair.json")[..])
+                .unwrap();
+        let mut pairs = HashMap::new();
+        for edge in markets.graph.all_edges() {
+            for market in edge.2.markets.iter() {
+                if market.reserves().is_some() {
+                    let tokens = market.tokens();
+                    pairs.insert(market.market_address(), (tokens.i, tokens.j));
+                }
+            }
+        }
+        BackrunEngine {
+            arbitrage: CrossedMarketArbitrageEngine::new(transport, bidding).await,
+            decoder: RouterDecoder::new(),
+            pair_abi,
+            pairs,
+        }
+    }
+
+    /// Is the transaction sent to a router or pair this engine can decode?
+    pub fn watches(&self, to: &Address) -> bool {
+        self.decoder.is_router(to) || self.pairs.contains_key(to)
+    }
+
+    /// Decode a router swap and apply it to copies of the pairs it routes through
+    fn apply_router_swap(
+        &self,
+        overlay: &mut MarketOverlay,
+        router: &Address,
+        transaction: &web3::types::Transaction,
+    ) -> Option<()> {
+        let intent = match self
+            .decoder
+            .decode(router, &transaction.input.0, transaction.value)?
+        {
+            RouterIntent::Swap(intent) => intent,
+            RouterIntent::Liquidity(intent) => {
+                // Liquidity changes leave the price unchanged, there is nothing to backrun
+                if let Some(market) = intent.market(overlay.base()) {
+                    debug!(
+                        "Pending liquidity change on {} in {}",
+                        market.market_address(),
+                        transaction.hash
+                    );
+                }
+                return None;
+            }
+        };
+        // Only swaps routed entirely through known pairs can be applied
+        intent.markets(overlay.base())?;
+        match intent.amount {
+            SwapAmount::ExactIn {
+                amount_in,
+                amount_out_min,
+            } => {
+                let amount_out = overlay.swap_path(&intent.pairs, &intent.path, &amount_in)?;
+                if amount_out < amount_out_min {
+                    return None;
+                }
+            }
+            SwapAmount::ExactOut {
+                amount_out,
+                amount_in_max,
+            } => {
+                let amount_in = overlay.path_amount_in(&intent.pairs, &intent.path, &amount_out)?;
+                // A swap exceeding its slippage limit reverts, and leaves nothing to backrun
+                if amount_in > amount_in_max {
+                    return None;
+                }
+                overlay.swap_path(&intent.pairs, &intent.path, &amount_in)?;
+            }
+        }
+        Some(())
+    }
+
+    /// Decode a swap made directly on a pair and apply it to a copy of the pair
+    fn apply_pair_swap(
+        &self,
+        overlay: &mut MarketOverlay,
+        pair: &Address,
+        transaction: &web3::types::Transaction,
+    ) -> Option<()> {
+        let input = &transaction.input.0;
+        let swap = self.pair_abi.function("swap").ok()?;
+        if input.len() < 4 || utilities::selector(swap) != input[0..4] {
+            return None;
+        }
+        let params = swap.decode_input(&input[4..]).ok()?;
+        let amount_0_out = params[0].clone().into_uint()?;
+        let amount_1_out = params[1].clone().into_uint()?;
+        let (token_0, token_1) = self.pairs[pair];
+        // The input was sent ahead of the call, so infer it from the output
+        let (token_in, token_out, amount_out) = if amount_0_out.is_zero() {
+            (token_0, token_1, amount_1_out)
+        } else {
+            (token_1, token_0, amount_0_out)
+        };
+        let amount_in = overlay.get_tokens_in(pair, &token_in, &token_out, &amount_out)?;
+        overlay.swap(pair, &token_in, &token_out, &amount_in)?;
+        Some(())
+    }
+
+    /// Search the edges of the swapped pairs for crossed markets
+    fn evaluate_pending<'a>(
+        &self,
+        overlay: &'a MarketOverlay,
+    ) -> Vec<CrossedMarketDetails<'a, dyn Market + 'a>> {
+        let origin_units = self.arbitrage.origin_units(overlay.base());
+        let mut crossed_markets = vec![];
+        for swapped in overlay.pending_markets() {
+            let tokens = swapped.tokens();
+            for (origin, unit) in origin_units.iter().copied() {
+                let probe = unit / U256::from(100);
+                let intermediary = if tokens.i.0 == origin.0 {
+                    tokens.j
+                } else if tokens.j.0 == origin.0 {
+                    tokens.i
+                } else {
+                    continue;
+                };
+                let base = overlay.base();
+                if !base.tokens.quotable(&intermediary) || base.failures.is_banned(&intermediary) {
+                    continue;
+                }
+                // The markets on the edge, with any pending swaps applied
+                let edge_markets = overlay.edge_markets(&origin, &intermediary);
+                if edge_markets.len() < 2 {
+                    continue;
+                }
+                // Buy tokens from origin
+                let best_ask = edge_markets
+                    .iter()
+                    .map(|market| {
+                        (
+                            *market,
+                            market.get_tokens_out(&origin, &intermediary, &probe),
+                        )
+                    })
+                    .max_by_key(|(_, offer)| *offer)
+                    .unwrap();
+                // Sell tokens to get back to origin
+                let best_bid = edge_markets
+                    .iter()
+                    .map(|market| {
+                        (
+                            *market,
+                            market.get_tokens_in(&intermediary, &origin, &probe),
+                        )
+                    })
+                    .min_by_key(|(_, bid)| *bid)
+                    .unwrap();
+                if best_ask.1 > best_bid.1 {
+                    if let Some(crossed_market) = CrossedMarketDetails::new(
+                        origin,
+                        intermediary,
+                        best_bid.0,
+                        best_ask.0,
+                        unit,
+                    )
+                    .optimized()
+                    {
+                        crossed_markets.push(crossed_market);
+                    }
+                }
+            }
+        }
+        crossed_markets.sort_by_key(|crossed_market| std::cmp::Reverse(crossed_market.profit()));
+        crossed_markets
+    }
+
+    /// The raw signed pending transaction, to include in the bundle ahead of the backrun
+    async fn raw_transaction(
+        &self,
+        transport: &Web3<WebSocket>,
+        transaction_hash: &H256,
+    ) -> Option<Bytes> {
+        let raw = transport
+            .transport()
+            .execute(
+                "eth_getRawTransactionByHash",
+                vec![serde_json::to_value(transaction_hash).ok()?],
+            )
+            .await
+            .ok()?;
+        serde_json::from_value(raw).ok()
+    }
+
+    /// Generate a bundle backrunning a pending transaction
+    pub async fn backrun(
+        &self,
+        markets: &MarketGraph,
+        transport: &Web3<WebSocket>,
+        account: &Address,
+        gas_price: &GasPrice,
+        block_number: &U64,
+        transaction: &web3::types::Transaction,
+    ) -> Option<Bundle> {
+        let to = transaction.to?;
+        let mut overlay = MarketOverlay::new(markets);
+        if self.decoder.is_router(&to) {
+            self.apply_router_swap(&mut overlay, &to, transaction)?;
+        } else if self.pairs.contains_key(&to) {
+            self.apply_pair_swap(&mut overlay, &to, transaction)?;
+        } else {
+            return None;
+        }
+        let crossed_markets = self.evaluate_pending(&overlay);
+        if crossed_markets.is_empty() {
+            return None;
+        }
+        let executor_state = self.arbitrage.executor_state().await;
+        let crossed_market = &crossed_markets
+            .into_iter()
+            .find_map(|crossed_market| crossed_market.funded(&executor_state))?;
+        debug!(
+            "Backrunning {} with crossed market {}",
+            transaction.hash,
+            crossed_market.describe(&markets.tokens)
+        );
+        let raw_transaction = self.raw_transaction(transport, &transaction.hash).await?;
+        // The arbitrage only exists after the pending transaction, so gas can't be estimated
+        let (tx, bid) = self
+            .arbitrage
+            .take_crossed_market(
+                crossed_market,
+                account,
+                &executor_state,
+                gas_price,
+                &markets.bids,
+                block_number.as_u64() + 1,
+                false,
+            )
+            .await;
+        let (tx, bid) = (tx?, bid?);
+        let victim = Transaction {
+            raw_profit: constants::ZERO_U256,
+            taken_profit: constants::ZERO_U256,
+            delta_coinbase: constants::ZERO_U256,
+            estimated_gas: transaction.gas * U256::from(90) / U256::from(100),
+            parameters: TransactionParameters {
+                nonce: Some(transaction.nonce),
+                to: transaction.to,
+                gas: transaction.gas,
+                gas_price: transaction.gas_price,
+                value: transaction.value,
+                data: transaction.input.clone(),
+                transaction_type: transaction.transaction_type,
+                access_list: transaction.access_list.clone(),
+                max_fee_per_gas: transaction.max_fee_per_gas,
+                max_priority_fee_per_gas: transaction.max_priority_fee_per_gas,
+                ..Default::default()
+            },
+            signed: Some(SignedTransaction {
+                message_hash: H256::zero(),
+                v: transaction.v.unwrap_or_default().as_u64(),
+                r: u256_h256(transaction.r.unwrap_or_default()),
+                s: u256_h256(transaction.s.unwrap_or_default()),
+                raw_transaction,
+                transaction_hash: transaction.hash,
+            }),
+            signed_parameters: None,
+            external: true,
+            route: vec![],
+            bid: None,
+        };
+        let bundle = Bundle {
+            bundle_hash: None,
+            transactions: vec![
+                victim,
+                crossed_market_transaction(crossed_market, tx, bid, gas_price),
+            ],
+            block: *block_number,
+            base_fee: gas_price.base_fee,
+            executor: *account,
+        };
+        if bundle.effective_gas() > gas_price.low {
+            info!(
+                "Found backrun of {}: {}",
+                transaction.hash,
+                crossed_market.describe(&markets.tokens)
+            );
+            return Some(bundle);
+        }
+        None
+    }
+}
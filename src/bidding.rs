@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use web3::types::{Address, U256};
+
+use crate::gas::GasPrice;
+use crate::utilities;
+
+/// Blocks a winning bid is remembered for
+const BID_HISTORY_BLOCKS: u64 = 50;
+
+/// How far over the recent winning priority fee the competitive strategy bids, in percent
+const OUTBID_PERCENTAGE: u64 = 10;
+
+/// The most of the profit the competitive strategy will pay to outbid, in percent
+const MAX_COMPETITIVE_PERCENTAGE: u64 = 99;
+
+/// The strategy for each engine when none is configured
+pub const DEFAULT_STRATEGIES: &[(&str, &str)] = &[
+    ("arbitrage", "fixed:99"),
+    ("backrun", "fixed:99"),
+    ("skim", "fixed:99"),
+    ("compound", "fixed:90"),
+    ("alpha_homora", "fixed:90"),
+];
+
+/// A miner payment, and why it was chosen
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bid {
+    pub payment: U256,
+    pub rationale: String,
+}
+
+/// What a strategy knows about an opportunity when bidding on it
+pub struct BidContext<'a> {
+    // Profit in wei before paying the miner and burning the base fee
+    pub profit: U256,
+    // Expected gas used by the transaction
+    pub gas: U256,
+    pub gas_price: &'a GasPrice,
+    // Markets traded through
+    pub route: &'a [Address],
+    pub history: &'a BidHistory,
+}
+
+impl BidContext<'_> {
+    /// The profit left after the base fee is burned, if any
+    pub fn net_profit(&self) -> Option<U256> {
+        self.profit
+            .checked_sub(self.gas * self.gas_price.base_fee)
+            .filter(|profit| !profit.is_zero())
+    }
+}
+
+/// Decides how much of an opportunity's profit to pay the miner
+pub trait BiddingStrategy: fmt::Debug + Send + Sync {
+    /// The bid for the opportunity, or None if it isn't worth bidding on
+    fn bid(&self, context: &BidContext) -> Option<Bid>;
+}
+
+/// Pay a fixed percentage of the profit
+#[derive(Debug)]
+pub struct FixedPercentage {
+    pub percentage: u64,
+}
+
+impl BiddingStrategy for FixedPercentage {
+    fn bid(&self, context: &BidContext) -> Option<Bid> {
+        let profit = context.net_profit()?;
+        Some(Bid {
+            payment: profit * self.percentage / 100,
+            rationale: format!(
+                "{}% of Ξ{} profit",
+                self.percentage,
+                utilities::to_ether(&profit)
+            ),
+        })
+    }
+}
+
+/// Pay everything but a minimum margin, skipping opportunities which can't cover it
+#[derive(Debug)]
+pub struct MinimumMargin {
+    pub margin: U256,
+}
+
+impl BiddingStrategy for MinimumMargin {
+    fn bid(&self, context: &BidContext) -> Option<Bid> {
+        let profit = context.net_profit()?;
+        if profit <= self.margin {
+            return None;
+        }
+        Some(Bid {
+            payment: profit - self.margin,
+            rationale: format!(
+                "all but a Ξ{} margin of Ξ{} profit",
+                utilities::to_ether(&self.margin),
+                utilities::to_ether(&profit)
+            ),
+        })
+    }
+}
+
+/// Outbid the recent winning priority fees on the same markets
+///
+/// Without recent winners on the route, a fixed percentage is paid.
+#[derive(Debug)]
+pub struct Competitive {
+    pub fallback_percentage: u64,
+}
+
+impl BiddingStrategy for Competitive {
+    fn bid(&self, context: &BidContext) -> Option<Bid> {
+        let profit = context.net_profit()?;
+        let winning = match context.history.winning_gas_price(context.route) {
+            Some(winning) => winning,
+            None => {
+                return Some(Bid {
+                    payment: profit * self.fallback_percentage / 100,
+                    rationale: format!(
+                        "no recent winners, {}% of Ξ{} profit",
+                        self.fallback_percentage,
+                        utilities::to_ether(&profit)
+                    ),
+                })
+            }
+        };
+        let gas_price = winning * (100 + OUTBID_PERCENTAGE) / 100;
+        let payment = gas_price * context.gas;
+        if payment > profit * MAX_COMPETITIVE_PERCENTAGE / 100 {
+            return None;
+        }
+        Some(Bid {
+            payment,
+            rationale: format!(
+                "outbidding a recent winner at {} gwei by {}%",
+                utilities::to_gwei(&winning),
+                OUTBID_PERCENTAGE
+            ),
+        })
+    }
+}
+
+/// Raise another strategy's bid to the lowest priority fee worth including
+#[derive(Debug)]
+pub struct GasFloor {
+    pub inner: Arc<dyn BiddingStrategy>,
+}
+
+impl BiddingStrategy for GasFloor {
+    fn bid(&self, context: &BidContext) -> Option<Bid> {
+        let bid = self.inner.bid(context)?;
+        // Bundles must pay strictly more than the low priority fee
+        let floor = (context.gas_price.low + 1) * context.gas;
+        if bid.payment >= floor {
+            return Some(bid);
+        }
+        if floor > context.net_profit()? {
+            return None;
+        }
+        Some(Bid {
+            payment: floor,
+            rationale: format!(
+                "{}, raised to the {} gwei floor",
+                bid.rationale,
+                utilities::to_gwei(&context.gas_price.low)
+            ),
+        })
+    }
+}
+
+/// Parse a strategy from its configuration
+///
+/// The strategies are `fixed:<percent>`, `margin:<wei>`, `competitive:<fallback percent>` and
+/// `floor:<strategy>`, which wraps another.
+pub fn parse(spec: &str) -> Result<Arc<dyn BiddingStrategy>> {
+    let (name, argument) = spec
+        .split_once(':')
+        .with_context(|| format!("Bidding strategy {} has no argument", spec))?;
+    let percentage = |argument: &str| -> Result<u64> {
+        let percentage: u64 = argument
+            .parse()
+            .with_context(|| format!("Invalid percentage {}", argument))?;
+        if percentage > 100 {
+            return Err(anyhow!("Percentage {} is over 100", percentage));
+        }
+        Ok(percentage)
+    };
+    Ok(match name {
+        "fixed" => Arc::new(FixedPercentage {
+            percentage: percentage(argument)?,
+        }),
+        "margin" => Arc::new(MinimumMargin {
+            margin: U256::from_dec_str(argument)
+                .map_err(|_| anyhow!("Invalid margin {}", argument))?,
+        }),
+        "competitive" => Arc::new(Competitive {
+            fallback_percentage: percentage(argument)?,
+        }),
+        "floor" => Arc::new(GasFloor {
+            inner: parse(argument)?,
+        }),
+        _ => return Err(anyhow!("Unknown bidding strategy {}", name)),
+    })
+}
+
+#[derive(Debug, Default)]
+struct BidHistoryState {
+    // The highest winning priority fee on each market, and the block it was seen in
+    winning: HashMap<Address, (U256, u64)>,
+    block: u64,
+}
+
+/// Recent winning priority fees of the transactions through each market
+///
+/// Only the priority fee is seen, so winners paying the miner directly look cheaper than they are.
+#[derive(Debug, Default)]
+pub struct BidHistory {
+    state: Mutex<BidHistoryState>,
+}
+
+impl BidHistory {
+    pub fn new() -> BidHistory {
+        BidHistory::default()
+    }
+
+    /// Record the priority fees paid through markets in a block
+    pub fn record(&self, block: u64, winners: &[(Address, U256)]) {
+        let mut state = self.state.lock().unwrap();
+        state.block = block;
+        for (market, gas_price) in winners {
+            let entry = state.winning.entry(*market).or_insert((*gas_price, block));
+            // Older winners are replaced rather than compared against
+            if entry.1 + BID_HISTORY_BLOCKS < block || *gas_price > entry.0 {
+                *entry = (*gas_price, block);
+            }
+        }
+        state
+            .winning
+            .retain(|_, (_, seen)| *seen + BID_HISTORY_BLOCKS >= block);
+    }
+
+    /// The highest recent winning priority fee through any of the markets
+    pub fn winning_gas_price(&self, route: &[Address]) -> Option<U256> {
+        let state = self.state.lock().unwrap();
+        route
+            .iter()
+            .filter_map(|market| state.winning.get(market))
+            .filter(|(_, seen)| *seen + BID_HISTORY_BLOCKS >= state.block)
+            .map(|(gas_price, _)| *gas_price)
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gas::PaymentMode;
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn gas_price(low: u64) -> GasPrice {
+        let low = U256::from(low);
+        GasPrice {
+            ludicrous: low,
+            high: low,
+            medium: low,
+            low,
+            base_fee: U256::zero(),
+            payment_mode: PaymentMode::CoinbaseTransfer,
+        }
+    }
+
+    #[test]
+    fn fixed_and_margin() {
+        let history = BidHistory::new();
+        let gas_price = gas_price(0);
+        let context = BidContext {
+            profit: U256::from(1000),
+            gas: U256::from(1),
+            gas_price: &gas_price,
+            route: &[],
+            history: &history,
+        };
+        let fixed = parse("fixed:90").unwrap().bid(&context).unwrap();
+        assert_eq!(fixed.payment, U256::from(900));
+        let margin = parse("margin:300").unwrap().bid(&context).unwrap();
+        assert_eq!(margin.payment, U256::from(700));
+        assert_eq!(parse("margin:1000").unwrap().bid(&context), None);
+    }
+
+    #[test]
+    fn competitive_outbids_recent_winners() {
+        let market = Address::repeat_byte(1);
+        let history = BidHistory::new();
+        let gas_price = gas_price(0);
+        let strategy = parse("competitive:50").unwrap();
+        let context = BidContext {
+            profit: U256::from(10_000_000 * GWEI),
+            gas: U256::from(100_000),
+            gas_price: &gas_price,
+            route: &[market],
+            history: &history,
+        };
+        // Nothing seen on the market yet
+        assert_eq!(
+            strategy.bid(&context).unwrap().payment,
+            U256::from(5_000_000 * GWEI)
+        );
+        history.record(1, &[(market, U256::from(40 * GWEI))]);
+        assert_eq!(
+            strategy.bid(&context).unwrap().payment,
+            U256::from(44 * GWEI * 100_000)
+        );
+        // Too expensive to outbid
+        history.record(2, &[(market, U256::from(100 * GWEI))]);
+        assert_eq!(strategy.bid(&context), None);
+        // And then forgotten
+        history.record(2 + BID_HISTORY_BLOCKS + 1, &[]);
+        assert_eq!(history.winning_gas_price(&[market]), None);
+    }
+
+    #[test]
+    fn floor_raises_bids() {
+        let history = BidHistory::new();
+        let gas_price = gas_price(10);
+        let strategy = parse("floor:fixed:1").unwrap();
+        let mut context = BidContext {
+            profit: U256::from(10_000),
+            gas: U256::from(100),
+            gas_price: &gas_price,
+            route: &[],
+            history: &history,
+        };
+        assert_eq!(strategy.bid(&context).unwrap().payment, U256::from(1100));
+        context.profit = U256::from(1000);
+        assert_eq!(strategy.bid(&context), None);
+        assert!(parse("floor:bogus:1").is_err());
+        assert!(parse("fixed:101").is_err());
+    }
+}
@@ -1,15 +1,25 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
 use anyhow::Error;
 use async_trait::async_trait;
+use log::{debug, info, warn};
+use web3::ethabi;
 use web3::ethabi::ethereum_types::U256;
-use web3::types::{Address, BlockId, BlockNumber};
+use web3::types::{Address, BlockId, BlockNumber, FilterBuilder, TransactionParameters, H256, U64};
 
+use crate::ape_bank::ApeBank;
+use crate::bidding::{Bid, BidContext, BiddingStrategy};
 use crate::constants;
 use crate::evm;
-use crate::evm::Call;
-use crate::markets::{Market, Protocol, TokenPair};
-use crate::{address_book, markets};
+use crate::evm::{Call, Multicall, MulticallHeader};
+use crate::flashbots::{Bundle, BundleGenerator};
+use crate::gas::GasPrice;
+use crate::markets::{Market, MarketGraph, Protocol, TokenPair};
+use crate::utilities::Transaction;
+use crate::{address_book, markets, utilities};
 use web3::contract::tokens::Tokenize;
-use web3::contract::Contract;
+use web3::contract::{Contract, Options};
 use web3::transports::WebSocket;
 use web3::Web3;
 
@@ -68,6 +78,10 @@ impl Market for CethEthMarket {
         self.bundle_executor
     }
 
+    fn failure_address(&self) -> Address {
+        self.ceth.address()
+    }
+
     fn delta_contracts(&self) -> Vec<Address> {
         // No updates to be made here.
         return vec![self.bundle_executor];
@@ -77,7 +91,11 @@ impl Market for CethEthMarket {
         Protocol::Compound
     }
 
-    fn miner_reward_percentage(&self) -> Option<U256> {
+    fn reserves(&self) -> Option<(U256, U256)> {
+        None
+    }
+
+    fn with_swap(&self, _token_in: &Address, _amount_in: &U256) -> Option<Box<dyn Market>> {
         None
     }
 
@@ -158,6 +176,15 @@ impl Market for CethEthMarket {
         }
     }
 
+    // Minting is paid for up front
+    fn flash_sell_tokens(
+        &self,
+        _token_in: &Address,
+        _amount_in: &U256,
+    ) -> anyhow::Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
     async fn update(&mut self) {
         self.update_exchange_rate().await
     }
@@ -180,3 +207,906 @@ impl Market for CethEthMarket {
         Ok(None)
     }
 }
+
+/// Gas a liquidation is expected to use, for bidding before it is estimated
+const LIQUIDATION_GAS: u64 = 700_000;
+
+/// Blocks of events to scan for borrowers on the first run
+const POSITION_LOOKBACK_BLOCKS: u64 = 200_000;
+
+/// Blocks per log query when scanning for borrowers
+const LOG_CHUNK_BLOCKS: u64 = 5_000;
+
+/// Number of accounts to refresh concurrently
+const SNAPSHOT_BATCH_SIZE: usize = 100;
+
+/// Number of underwater accounts to attempt liquidating per block
+const MAX_LIQUIDATION_CANDIDATES: usize = 5;
+
+/// Share of seized ctokens kept by the protocol, scaled by 10^18
+const PROTOCOL_SEIZE_SHARE_MANTISSA: U256 = U256([28_000_000_000_000_000, 0, 0, 0]);
+
+/// Seized ctokens left unredeemed, in basis points. Seizes are priced at the stored exchange rate,
+/// but liquidateBorrow accrues interest first, and the higher rate seizes slightly fewer ctokens.
+const REDEEM_MARGIN_BPS: u64 = 10;
+
+/// A Compound market as seen by the liquidation engine
+struct CToken {
+    contract: Contract<WebSocket>,
+    // The underlying token, weth for ceth, which is wrapped and unwrapped around calls
+    underlying: Contract<WebSocket>,
+    is_ceth: bool,
+}
+
+/// The comptroller's parameters for sizing liquidations, all scaled by 10^18
+#[derive(Debug, Clone)]
+struct RiskParameters {
+    // Share of each market's collateral which can be borrowed against
+    collateral_factors: HashMap<Address, U256>,
+    // Share of a borrow which can be repaid in one liquidation
+    close_factor: U256,
+    // Collateral seized per unit of borrow repaid
+    liquidation_incentive: U256,
+}
+
+/// The state of a Compound market at a block
+#[derive(Debug, Clone, Copy)]
+struct CTokenState {
+    // Price of the underlying in usd, scaled by 10^(36 - underlying decimals)
+    price: U256,
+    exchange_rate: U256,
+}
+
+/// A borrower's balances in one Compound market
+#[derive(Debug, Default, Clone, Copy)]
+struct Balance {
+    ctokens: U256,
+    borrowed: U256,
+}
+
+/// A liquidation of a borrower, repaying a borrow and seizing collateral
+#[derive(Debug)]
+struct Liquidation {
+    borrower: Address,
+    borrow_market: Address,
+    collateral_market: Address,
+    repay_amount: U256,
+    // Ctokens redeemed after the protocol takes its share of the seized ctokens
+    redeem_tokens: U256,
+    // Underlying returned by the redemption
+    redeem_amount: U256,
+}
+
+impl RiskParameters {
+    /// Size a liquidation of the account, or None if it is not underwater
+    ///
+    /// The largest borrow is repaid up to the close factor, seizing the largest collateral.
+    fn evaluate_account(
+        &self,
+        account: Address,
+        balances: &HashMap<Address, Balance>,
+        states: &HashMap<Address, CTokenState>,
+    ) -> Option<(U256, Liquidation)> {
+        let mut liquidity = constants::ZERO_U256;
+        let mut borrows = constants::ZERO_U256;
+        // (market, usd value)
+        let mut largest_borrow = (Address::zero(), constants::ZERO_U256);
+        let mut largest_collateral = (Address::zero(), constants::ZERO_U256);
+        for (market, balance) in balances {
+            let state = states.get(market)?;
+            // Values are in usd scaled by 10^18
+            let collateral = balance.ctokens * state.exchange_rate / constants::ETHER * state.price
+                / constants::ETHER;
+            let borrowed = balance.borrowed * state.price / constants::ETHER;
+            liquidity += collateral * self.collateral_factors.get(market)? / constants::ETHER;
+            borrows += borrowed;
+            if borrowed > largest_borrow.1 {
+                largest_borrow = (*market, borrowed);
+            }
+            if collateral > largest_collateral.1 {
+                largest_collateral = (*market, collateral);
+            }
+        }
+        if borrows <= liquidity || largest_collateral.1.is_zero() {
+            return None;
+        }
+        let shortfall = borrows - liquidity;
+        let borrow_state = states[&largest_borrow.0];
+        let collateral_state = states[&largest_collateral.0];
+        // The repayment is limited by the close factor and by the collateral available to seize
+        let max_repay = balances[&largest_borrow.0].borrowed * self.close_factor / constants::ETHER;
+        let seizable_repay = largest_collateral.1 * constants::ETHER / self.liquidation_incentive
+            * constants::ETHER
+            / borrow_state.price;
+        let repay_amount = std::cmp::min(max_repay, seizable_repay);
+        let seize_amount = repay_amount * borrow_state.price / collateral_state.price
+            * self.liquidation_incentive
+            / constants::ETHER;
+        let seize_tokens = seize_amount * constants::ETHER / collateral_state.exchange_rate;
+        let redeem_tokens = seize_tokens * (constants::ETHER - PROTOCOL_SEIZE_SHARE_MANTISSA)
+            / constants::ETHER
+            * (10_000 - REDEEM_MARGIN_BPS)
+            / 10_000;
+        Some((
+            shortfall,
+            Liquidation {
+                borrower: account,
+                borrow_market: largest_borrow.0,
+                collateral_market: largest_collateral.0,
+                repay_amount,
+                redeem_tokens,
+                redeem_amount: redeem_tokens * collateral_state.exchange_rate / constants::ETHER,
+            },
+        ))
+    }
+}
+
+/// Swaps of the redeemed collateral which repay the loan, with the weth left as profit
+///
+/// `swap` sells an amount of one token for another, returning the calls and the amount out, and
+/// `amount_in` prices an amount out of one token in another.
+fn settle_liquidation(
+    collateral_token: &Address,
+    repay_token: &Address,
+    weth: &Address,
+    redeem_amount: &U256,
+    repayment: &U256,
+    swap: impl Fn(&Address, &Address, &U256) -> Option<(Vec<Call>, U256)>,
+    amount_in: impl Fn(&Address, &Address, &U256) -> Option<U256>,
+) -> Option<(Vec<Call>, U256)> {
+    if repay_token.0 == weth.0 && collateral_token.0 != weth.0 {
+        // Everything goes to weth, so what's left after the repayment is the profit
+        let (calls, amount_out) = swap(collateral_token, weth, redeem_amount)?;
+        return Some((calls, amount_out.checked_sub(*repayment)?));
+    }
+    let mut calls = vec![];
+    let remaining = if collateral_token.0 == repay_token.0 {
+        redeem_amount.checked_sub(*repayment)?
+    } else {
+        let amount_in = amount_in(collateral_token, repay_token, repayment)?;
+        calls.extend(swap(collateral_token, repay_token, &amount_in)?.0);
+        redeem_amount.checked_sub(amount_in)?
+    };
+    // The leftover collateral is sold for weth
+    if collateral_token.0 == weth.0 || remaining.is_zero() {
+        return Some((calls, remaining));
+    }
+    let (swap_calls, profit) = swap(collateral_token, weth, &remaining)?;
+    calls.extend(swap_calls);
+    Some((calls, profit))
+}
+
+/// This engine liquidates underwater Compound borrowers, funded by an ApeBank flash loan
+///
+/// Positions are tracked from the market events and refreshed when an account is touched,
+/// while account liquidity is computed locally against oracle prices each block.
+pub struct CompoundLiquidationEngine {
+    bundle_executor_contract: Contract<WebSocket>,
+    comptroller: Contract<WebSocket>,
+    oracle: Contract<WebSocket>,
+    ape_bank: ApeBank,
+    ctokens: HashMap<Address, CToken>,
+    // Event signatures of position changes
+    position_topics: Vec<H256>,
+    risk: RiskParameters,
+    positions: Mutex<HashMap<Address, HashMap<Address, Balance>>>,
+    last_block: Mutex<Option<U64>>,
+    bidding: Arc<dyn BiddingStrategy>,
+}
+
+impl CompoundLiquidationEngine {
+    pub async fn new(
+        transport: &Web3<WebSocket>,
+        bidding: Arc<dyn BiddingStrategy>,
+    ) -> anyhow::Result<CompoundLiquidationEngine> {
+        let bundle_executor_contract = Contract::from_json(
+            transport.eth(),
+            address_book::MulticallEXECUTOR.parse().unwrap(),
+            include_bytes!("abis/Multicall.json"),
+        )?;
+        let comptroller = Contract::from_json(
+            transport.eth(),
+            address_book::COMPOUND_COMPTROLLER.parse().unwrap(),
+            include_bytes!("protocols/compound/comptroller.json"),
+        )?;
+        let oracle_address: Address = comptroller
+            .query("oracle", (), None, Options::default(), None)
+            .await?;
+        let oracle = Contract::from_json(
+            transport.eth(),
+            oracle_address,
+            include_bytes!("protocols/compound/oracle.json"),
+        )?;
+        let close_factor: U256 = comptroller
+            .query("closeFactorMantissa", (), None, Options::default(), None)
+            .await?;
+        let liquidation_incentive: U256 = comptroller
+            .query(
+                "liquidationIncentiveMantissa",
+                (),
+                None,
+                Options::default(),
+                None,
+            )
+            .await?;
+        let all_markets: Vec<Address> = comptroller
+            .query("getAllMarkets", (), None, Options::default(), None)
+            .await?;
+        let ceth: Address = address_book::CETH_ADDRESS.parse().unwrap();
+        let mut ctokens = HashMap::new();
+        let mut collateral_factors = HashMap::new();
+        for address in all_markets {
+            let (is_listed, collateral_factor, _): (bool, U256, bool) = comptroller
+                .query("markets", address, None, Options::default(), None)
+                .await?;
+            if !is_listed {
+                continue;
+            }
+            let is_ceth = address.0 == ceth.0;
+            let (contract, underlying) = if is_ceth {
+                (
+                    Contract::from_json(
+                        transport.eth(),
+                        address,
+                        include_bytes!("protocols/compound/ceth.json"),
+                    )?,
+                    Contract::from_json(
+                        transport.eth(),
+                        address_book::WETH_ADDRESS.parse().unwrap(),
+                        include_bytes!("abis/WETH9.json"),
+                    )?,
+                )
+            } else {
+                let contract = Contract::from_json(
+                    transport.eth(),
+                    address,
+                    include_bytes!("protocols/compound/cerc20.json"),
+                )?;
+                let underlying: Address = match contract
+                    .query("underlying", (), None, Options::default(), None)
+                    .await
+                {
+                    Ok(underlying) => underlying,
+                    Err(error) => {
+                        warn!("Failed to load Compound market {}: {:?}", address, error);
+                        continue;
+                    }
+                };
+                (
+                    contract,
+                    Contract::from_json(
+                        transport.eth(),
+                        underlying,
+                        include_bytes!("abis/IERC20.json"),
+                    )?,
+                )
+            };
+            ctokens.insert(
+                address,
+                CToken {
+                    contract,
+                    underlying,
+                    is_ceth,
+                },
+            );
+            collateral_factors.insert(address, collateral_factor);
+        }
+        info!("Tracking {} Compound markets.", ctokens.len());
+        let events =
+            ethabi::Contract::load(&include_bytes!("protocols/compound/ceth.json")[..]).unwrap();
+        let position_topics = ["Mint", "Redeem", "Borrow", "RepayBorrow", "LiquidateBorrow"]
+            .iter()
+            .map(|event| events.event(event).unwrap().signature())
+            .collect();
+        let engine = CompoundLiquidationEngine {
+            bundle_executor_contract,
+            comptroller,
+            oracle,
+            ape_bank: ApeBank::new(transport),
+            ctokens,
+            position_topics,
+            risk: RiskParameters {
+                collateral_factors,
+                close_factor,
+                liquidation_incentive,
+            },
+            positions: Mutex::new(HashMap::new()),
+            last_block: Mutex::new(None),
+            bidding,
+        };
+        // Backfill the borrowers now, so searches only catch up on the blocks since
+        let block_number = transport.eth().block_number().await?;
+        engine.update_positions(transport, &block_number).await;
+        info!(
+            "Tracking {} Compound borrowers.",
+            engine.positions.lock().unwrap().len()
+        );
+        Ok(engine)
+    }
+
+    /// Accounts whose positions changed between the blocks
+    async fn touched_accounts(
+        &self,
+        transport: &Web3<WebSocket>,
+        from_block: u64,
+        to_block: u64,
+    ) -> HashSet<Address> {
+        let mut accounts = HashSet::new();
+        let mut start = from_block;
+        while start <= to_block {
+            let end = std::cmp::min(start + LOG_CHUNK_BLOCKS - 1, to_block);
+            let logs = transport
+                .eth()
+                .logs(
+                    FilterBuilder::default()
+                        .from_block(BlockNumber::Number(U64::from(start)))
+                        .to_block(BlockNumber::Number(U64::from(end)))
+                        .address(self.ctokens.keys().copied().collect())
+                        .topics(Some(self.position_topics.clone()), None, None, None)
+                        .build(),
+                )
+                .await;
+            match logs {
+                Ok(logs) => {
+                    for log in logs {
+                        // The account is the first word of the data, after the payer for
+                        // repayments and the liquidator for liquidations
+                        let word = if log.topics[0] == self.position_topics[3]
+                            || log.topics[0] == self.position_topics[4]
+                        {
+                            1
+                        } else {
+                            0
+                        };
+                        let offset = word * 32;
+                        if log.data.0.len() >= offset + 32 {
+                            accounts
+                                .insert(Address::from_slice(&log.data.0[offset + 12..offset + 32]));
+                        }
+                    }
+                }
+                Err(error) => warn!("Failed to get Compound logs: {:?}", error),
+            }
+            start = end + 1;
+        }
+        accounts
+    }
+
+    /// Query the balances of an account in each market it has entered
+    async fn account_snapshot(&self, account: Address) -> Option<HashMap<Address, Balance>> {
+        let assets_in: Vec<Address> = self
+            .comptroller
+            .query("getAssetsIn", account, None, Options::default(), None)
+            .await
+            .ok()?;
+        let mut balances = HashMap::new();
+        for asset in assets_in {
+            let ctoken = match self.ctokens.get(&asset) {
+                Some(ctoken) => ctoken,
+                None => continue,
+            };
+            let (error, ctokens, borrowed, _): (U256, U256, U256, U256) = ctoken
+                .contract
+                .query(
+                    "getAccountSnapshot",
+                    account,
+                    None,
+                    Options::default(),
+                    None,
+                )
+                .await
+                .ok()?;
+            if error.is_zero() {
+                balances.insert(asset, Balance { ctokens, borrowed });
+            }
+        }
+        Some(balances)
+    }
+
+    /// Refresh the positions of accounts touched since the last block
+    async fn update_positions(&self, transport: &Web3<WebSocket>, block_number: &U64) {
+        let last_block = *self.last_block.lock().unwrap();
+        let from_block = match last_block {
+            Some(last_block) => last_block.as_u64() + 1,
+            None => block_number
+                .as_u64()
+                .saturating_sub(POSITION_LOOKBACK_BLOCKS),
+        };
+        let accounts: Vec<Address> = self
+            .touched_accounts(transport, from_block, block_number.as_u64())
+            .await
+            .into_iter()
+            .collect();
+        debug!("Refreshing {} Compound positions.", accounts.len());
+        for batch in accounts.chunks(SNAPSHOT_BATCH_SIZE) {
+            let snapshots = futures::future::join_all(
+                batch.iter().map(|account| self.account_snapshot(*account)),
+            )
+            .await;
+            let mut positions = self.positions.lock().unwrap();
+            for (account, snapshot) in batch.iter().zip(snapshots) {
+                match snapshot {
+                    Some(balances)
+                        if balances.values().any(|balance| !balance.borrowed.is_zero()) =>
+                    {
+                        positions.insert(*account, balances);
+                    }
+                    Some(_) => {
+                        positions.remove(account);
+                    }
+                    None => warn!("Failed to refresh Compound position of {}", account),
+                }
+            }
+        }
+        *self.last_block.lock().unwrap() = Some(*block_number);
+    }
+
+    /// Prices and exchange rates of every market at the block
+    async fn market_states(&self, block_number: &U64) -> HashMap<Address, CTokenState> {
+        let states = futures::future::join_all(self.ctokens.iter().map(|(address, ctoken)| {
+            futures::future::join(
+                self.oracle.query::<U256, _, _, _>(
+                    "getUnderlyingPrice",
+                    *address,
+                    None,
+                    Options::default(),
+                    BlockId::from(*block_number),
+                ),
+                ctoken.contract.query::<U256, _, _, _>(
+                    "exchangeRateStored",
+                    (),
+                    None,
+                    Options::default(),
+                    BlockId::from(*block_number),
+                ),
+            )
+        }))
+        .await;
+        self.ctokens
+            .keys()
+            .zip(states)
+            .filter_map(|(address, state)| match state {
+                (Ok(price), Ok(exchange_rate)) if !price.is_zero() => Some((
+                    *address,
+                    CTokenState {
+                        price,
+                        exchange_rate,
+                    },
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Calls to sell tokens through the best market on the edge, with the amount out
+    fn swap(
+        &self,
+        markets: &MarketGraph,
+        token_in: &Address,
+        token_out: &Address,
+        amount_in: &U256,
+    ) -> Option<(Vec<Call>, U256)> {
+        let token_markets = markets.graph.edge_weight(*token_in, *token_out)?;
+        let (market, amount_out) = token_markets.best_ask_market(token_in, token_out, amount_in);
+        let mut calls = vec![];
+        if let Some(to_first_market) = market.to_first_market(token_in, amount_in).ok()? {
+            calls.extend(to_first_market);
+        }
+        calls.extend(
+            market
+                .sell_tokens(
+                    token_in,
+                    amount_in,
+                    &self.bundle_executor_contract.address(),
+                )
+                .ok()?,
+        );
+        Some((calls, amount_out))
+    }
+
+    /// Build the flash loan funded liquidation, returning the transaction and weth profit
+    async fn take_liquidation(
+        &self,
+        markets: &MarketGraph,
+        liquidation: &mut Liquidation,
+        account: &Address,
+        gas_price: &GasPrice,
+    ) -> Option<(TransactionParameters, U256, Bid)> {
+        let weth: Address = address_book::WETH_ADDRESS.parse().unwrap();
+        let borrow_ctoken = &self.ctokens[&liquidation.borrow_market];
+        let collateral_ctoken = &self.ctokens[&liquidation.collateral_market];
+        let repay_token = borrow_ctoken.underlying.address();
+        let collateral_token = collateral_ctoken.underlying.address();
+        if !self.ape_bank.lends(&repay_token) {
+            debug!(
+                "Can't flash loan {} to liquidate {}",
+                repay_token, liquidation.borrower
+            );
+            return None;
+        }
+        let available = self.ape_bank.available(&repay_token).await;
+        if available < liquidation.repay_amount {
+            // Scale the liquidation down to the loan
+            liquidation.redeem_tokens =
+                liquidation.redeem_tokens * available / liquidation.repay_amount;
+            liquidation.redeem_amount =
+                liquidation.redeem_amount * available / liquidation.repay_amount;
+            liquidation.repay_amount = available;
+        }
+        if liquidation.repay_amount.is_zero() {
+            return None;
+        }
+
+        let mut calls = vec![];
+        // Repay the borrow, seizing collateral
+        if borrow_ctoken.is_ceth {
+            calls.push(
+                Call::from_contract(
+                    &borrow_ctoken.underlying,
+                    "withdraw",
+                    liquidation.repay_amount,
+                    evm::Type::Call,
+                    None,
+                )
+                .ok()?,
+            );
+            calls.push(
+                Call::from_contract(
+                    &borrow_ctoken.contract,
+                    "liquidateBorrow",
+                    (liquidation.borrower, liquidation.collateral_market),
+                    evm::Type::ValueCall,
+                    Some(liquidation.repay_amount),
+                )
+                .ok()?,
+            );
+        } else {
+            calls.push(
+                Call::from_contract(
+                    &borrow_ctoken.underlying,
+                    "approve",
+                    (liquidation.borrow_market, liquidation.repay_amount),
+                    evm::Type::Call,
+                    None,
+                )
+                .ok()?,
+            );
+            calls.push(
+                Call::from_contract(
+                    &borrow_ctoken.contract,
+                    "liquidateBorrow",
+                    (
+                        liquidation.borrower,
+                        liquidation.repay_amount,
+                        liquidation.collateral_market,
+                    ),
+                    evm::Type::Call,
+                    None,
+                )
+                .ok()?,
+            );
+        }
+        // Redeem the seized collateral
+        calls.push(
+            Call::from_contract(
+                &collateral_ctoken.contract,
+                "redeem",
+                liquidation.redeem_tokens,
+                evm::Type::Call,
+                None,
+            )
+            .ok()?,
+        );
+        if collateral_ctoken.is_ceth {
+            calls.push(
+                Call::from_contract(
+                    &collateral_ctoken.underlying,
+                    "deposit",
+                    (),
+                    evm::Type::ValueCall,
+                    Some(liquidation.redeem_amount),
+                )
+                .ok()?,
+            );
+        }
+
+        // Swap enough collateral back to repay the loan, and the rest to weth
+        let (swap_calls, profit) = settle_liquidation(
+            &collateral_token,
+            &repay_token,
+            &weth,
+            &liquidation.redeem_amount,
+            &(liquidation.repay_amount + constants::ONE_U256),
+            |token_in, token_out, amount_in| self.swap(markets, token_in, token_out, amount_in),
+            |token_in, token_out, amount_out| {
+                let token_markets = markets.graph.edge_weight(*token_in, *token_out)?;
+                Some(
+                    token_markets
+                        .best_bid_market(token_in, token_out, amount_out)
+                        .1,
+                )
+            },
+        )?;
+        calls.extend(swap_calls);
+        if profit.is_zero() {
+            return None;
+        }
+        calls.push(self.ape_bank.repay_premium(&repay_token).ok()?);
+
+        let bid = self.bidding.bid(&BidContext {
+            profit,
+            gas: U256::from(LIQUIDATION_GAS),
+            gas_price,
+            route: &[liquidation.borrow_market, liquidation.collateral_market],
+            history: &markets.bids,
+        })?;
+        let miner_payment = gas_price.payment(bid.payment);
+        let mch = MulticallHeader::new(true, false, miner_payment.coinbase_transfer().as_u128(), 0);
+        let tx = self
+            .ape_bank
+            .flash_loan(
+                &[repay_token],
+                &Multicall::new(mch, calls),
+                account,
+                true,
+                miner_payment,
+            )
+            .await?;
+        Some((tx, profit, bid))
+    }
+}
+
+#[async_trait]
+impl BundleGenerator for CompoundLiquidationEngine {
+    async fn generate(
+        &self,
+        markets: &MarketGraph,
+        transport: &Web3<WebSocket>,
+        account: &Address,
+        gas_price: &GasPrice,
+        block_number: &U64,
+    ) -> Option<Bundle> {
+        self.update_positions(transport, block_number).await;
+        let states = self.market_states(block_number).await;
+        let mut liquidations: Vec<(U256, Liquidation)> = {
+            let positions = self.positions.lock().unwrap();
+            positions
+                .iter()
+                .filter_map(|(account, balances)| {
+                    self.risk.evaluate_account(*account, balances, &states)
+                })
+                .collect()
+        };
+        if liquidations.is_empty() {
+            return None;
+        }
+        info!("Found {} underwater Compound accounts.", liquidations.len());
+        // Largest shortfalls first
+        liquidations.sort_by_key(|(shortfall, _)| std::cmp::Reverse(*shortfall));
+        let mut transactions = vec![];
+        for (_, liquidation) in liquidations.iter_mut().take(MAX_LIQUIDATION_CANDIDATES) {
+            debug!("Attempting Compound liquidation {:?}", liquidation);
+            if let Some((tx, profit, bid)) = self
+                .take_liquidation(markets, liquidation, account, gas_price)
+                .await
+            {
+                transactions.push(Transaction {
+                    raw_profit: profit,
+                    taken_profit: profit - bid.payment,
+                    delta_coinbase: gas_price.payment(bid.payment).coinbase_transfer(),
+                    estimated_gas: tx.gas * U256::from(90) / U256::from(100),
+                    parameters: tx,
+                    signed: None,
+                    signed_parameters: None,
+                    external: false,
+                    route: vec![],
+                    bid: Some(bid),
+                });
+            }
+        }
+        // Take the most profitable liquidation
+        let transaction = transactions
+            .into_iter()
+            .max_by_key(|transaction| transaction.raw_profit)?;
+        let bundle = Bundle {
+            bundle_hash: None,
+            transactions: vec![transaction],
+            block: *block_number,
+            base_fee: gas_price.base_fee,
+            executor: *account,
+        };
+        if bundle.effective_gas() > gas_price.low {
+            info!(
+                "Found Compound liquidation worth Ξ{}",
+                utilities::to_ether(&bundle.transactions[0].raw_profit)
+            );
+            return Some(bundle);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ether(amount: u64) -> U256 {
+        U256::from(amount) * constants::ETHER
+    }
+
+    // Collateral worth $2000 at a 75% collateral factor, against a borrow of $1 tokens
+    fn account(borrowed: U256, close_factor: U256) -> (RiskParameters, HashMap<Address, Balance>) {
+        let (collateral, borrow) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let risk = RiskParameters {
+            collateral_factors: vec![(collateral, ether(75) / 100), (borrow, ether(80) / 100)]
+                .into_iter()
+                .collect(),
+            close_factor,
+            liquidation_incentive: ether(108) / 100,
+        };
+        let balances = vec![
+            (
+                collateral,
+                Balance {
+                    ctokens: ether(50),
+                    borrowed: constants::ZERO_U256,
+                },
+            ),
+            (
+                borrow,
+                Balance {
+                    ctokens: constants::ZERO_U256,
+                    borrowed,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        (risk, balances)
+    }
+
+    fn states() -> HashMap<Address, CTokenState> {
+        vec![
+            (
+                Address::repeat_byte(1),
+                CTokenState {
+                    price: ether(2000),
+                    exchange_rate: ether(2) / 100,
+                },
+            ),
+            (
+                Address::repeat_byte(2),
+                CTokenState {
+                    price: constants::ETHER,
+                    exchange_rate: ether(2) / 100,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    // Settle 100 of collateral against a repayment of 60, with every swap paying out double
+    fn settle(collateral: u8, repay: u8) -> Option<(Vec<(Address, U256)>, U256)> {
+        let weth = Address::repeat_byte(0xee);
+        let swap = |token_in: &Address, token_out: &Address, amount_in: &U256| {
+            let call = Call::new(
+                *token_out,
+                vec![],
+                evm::Type::Call,
+                Some(*amount_in),
+                vec![],
+            );
+            let _ = token_in;
+            Some((vec![call], amount_in * 2))
+        };
+        let amount_in = |_: &Address, _: &Address, amount_out: &U256| Some(amount_out / 2);
+        let (calls, profit) = settle_liquidation(
+            &Address::repeat_byte(collateral),
+            &Address::repeat_byte(repay),
+            &weth,
+            &ether(100),
+            &ether(60),
+            swap,
+            amount_in,
+        )?;
+        let swaps = calls
+            .iter()
+            .map(|call| (call.header.target, call.value.unwrap()))
+            .collect();
+        Some((swaps, profit))
+    }
+
+    #[test]
+    fn settlements_leave_the_profit_in_weth() {
+        let weth = Address::repeat_byte(0xee);
+        // Collateral sold for the weth loan is only sold once
+        let (swaps, profit) = settle(1, 0xee).unwrap();
+        assert_eq!(swaps, vec![(weth, ether(100))]);
+        assert_eq!(profit, ether(140));
+        // Collateral in the borrowed token repays it directly, and the rest is sold
+        let (swaps, profit) = settle(1, 1).unwrap();
+        assert_eq!(swaps, vec![(weth, ether(40))]);
+        assert_eq!(profit, ether(80));
+        // Other collateral buys the repayment, and the rest is sold
+        let (swaps, profit) = settle(1, 2).unwrap();
+        assert_eq!(
+            swaps,
+            vec![(Address::repeat_byte(2), ether(30)), (weth, ether(70))]
+        );
+        assert_eq!(profit, ether(140));
+        // Weth collateral needs nothing more than the repayment
+        let (swaps, profit) = settle(0xee, 2).unwrap();
+        assert_eq!(swaps, vec![(Address::repeat_byte(2), ether(30))]);
+        assert_eq!(profit, ether(70));
+        // And a repayment worth more than the collateral isn't taken
+        assert!(settle_liquidation(
+            &weth,
+            &weth,
+            &weth,
+            &ether(50),
+            &ether(60),
+            |_, _, _| None,
+            |_, _, _| None
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn healthy_accounts_are_left_alone() {
+        // $1500 of liquidity covers $1500 of borrows
+        let (risk, balances) = account(ether(1500), ether(1) / 2);
+        assert!(risk
+            .evaluate_account(Address::zero(), &balances, &states())
+            .is_none());
+    }
+
+    #[test]
+    fn liquidations_repay_up_to_the_close_factor() {
+        let (risk, balances) = account(ether(1600), ether(1) / 2);
+        let (shortfall, liquidation) = risk
+            .evaluate_account(Address::zero(), &balances, &states())
+            .unwrap();
+        assert_eq!(shortfall, ether(100));
+        assert_eq!(liquidation.borrow_market, Address::repeat_byte(2));
+        assert_eq!(liquidation.collateral_market, Address::repeat_byte(1));
+        // Half the borrow, seizing $864 of collateral with the 8% incentive
+        assert_eq!(liquidation.repay_amount, ether(800));
+        let seize_tokens = ether(432) / 1000 * constants::ETHER / (ether(2) / 100);
+        assert_eq!(seize_tokens, ether(216) / 10);
+        // Less the protocol's share of the seized ctokens, and the margin for accrued interest
+        let received =
+            seize_tokens * (constants::ETHER - PROTOCOL_SEIZE_SHARE_MANTISSA) / constants::ETHER;
+        assert_eq!(
+            received * ether(2) / 100 / constants::ETHER,
+            ether(419_904) / 1_000_000
+        );
+        assert_eq!(
+            liquidation.redeem_tokens,
+            received * (10_000 - REDEEM_MARGIN_BPS) / 10_000
+        );
+        assert_eq!(
+            liquidation.redeem_amount,
+            ether(419_904) / 1_000_000 * (10_000 - REDEEM_MARGIN_BPS) / 10_000
+        );
+    }
+
+    #[test]
+    fn liquidations_are_limited_by_the_collateral() {
+        // Repaying the whole borrow would seize more than the $2000 of collateral
+        let (risk, balances) = account(ether(2500), constants::ETHER);
+        let (shortfall, liquidation) = risk
+            .evaluate_account(Address::zero(), &balances, &states())
+            .unwrap();
+        assert_eq!(shortfall, ether(1000));
+        let repay_amount = ether(2000) * constants::ETHER / (ether(108) / 100);
+        assert_eq!(liquidation.repay_amount, repay_amount);
+        // The incentive on the repayment seizes all 50 ctokens of collateral, to rounding
+        let all_collateral = ether(50) * (constants::ETHER - PROTOCOL_SEIZE_SHARE_MANTISSA)
+            / constants::ETHER
+            * (10_000 - REDEEM_MARGIN_BPS)
+            / 10_000;
+        assert!(liquidation.redeem_tokens <= all_collateral);
+        assert!(all_collateral - liquidation.redeem_tokens < U256::from(1_000_000_000));
+    }
+}
@@ -21,9 +21,9 @@ object "Multicall" {
             
             switch selector()
             
-            case 0xb3a59b29 /* ostium(uint256[] calldata) external onlyOwner payable */
+            case 0xb3a59b29 /* ostium(uint256[] calldata) external onlyExecutor payable */
             {
-                onlyOwner()
+                onlyExecutor()
                 // Ensure data is in the shape of a uint256[>1]
                 if iszero(lt(0x64, calldatasize())) {
                     if iszero(eq(0x64, calldatasize())) {
@@ -45,8 +45,8 @@ object "Multicall" {
             
             case 0xb3ab0995 /* function largeApeCallback(address sender, uint wethToReturn, uint wbtcToReturn, uint daiToReturn, uint usdcToReturn, uint usdtToReturn, bytes calldata data) */
             {
-                // Require sender is owner
-                require(eq(decodeAddress(0x0), owner()), "Unauthorized sender")
+                // Require sender is an executor
+                require(isExecutor(decodeAddress(0x0)), "Unauthorized sender")
                 // Require caller is Ape Bank
                 require(eq(caller(), ape_bank()), "Unauthorized caller")
                 
@@ -87,6 +87,67 @@ object "Multicall" {
                 }
             }
             
+            case 0x920f5c84 /* function executeOperation(address[] calldata assets, uint[] calldata amounts, uint[] calldata premiums, address initiator, bytes calldata params) returns (bool) */
+            {
+                // Require caller is the Aave lending pool
+                require(eq(caller(), aave_lending_pool()), "Unauthorized caller")
+                // Require the loan was taken by an executor
+                require(isExecutor(decodeAddress(0x60)), "Unauthorized sender")
+                
+                // Start the multicall, which approves the pool to take back the loan and premium
+                bytesMulticall(0x80)
+                
+                let ptr, tail := obj_allocate(word())
+                mstore(ptr, 1)
+                return(ptr, tail)
+            }
+            
+            case 0xf04f2707 /* function receiveFlashLoan(address[] calldata tokens, uint[] calldata amounts, uint[] calldata feeAmounts, bytes calldata userData) */
+            {
+                // Require caller is the Balancer vault
+                require(eq(caller(), balancer_vault()), "Unauthorized caller")
+                // The vault doesn't say who took the loan, so require an executor sent the transaction
+                require(isExecutor(origin()), "Unauthorized sender")
+                
+                // Start the multicall, which repays the loan and fee to the vault
+                bytesMulticall(0x60)
+            }
+            
+            case 0x8b418713 /* function callFunction(address sender, Types.AccountInfo memory accountInfo, bytes memory data) */
+            {
+                // Require caller is dYdX
+                require(eq(caller(), solo_margin()), "Unauthorized caller")
+                // Require the operation was sent by this contract
+                require(eq(decodeAddress(zero()), address()), "Unauthorized sender")
+                
+                // Start the multicall, which approves dYdX to take back the loan and fee
+                bytesMulticall(0x60)
+            }
+            
+            case 0x10d1e85c /* function uniswapV2Call(address sender, uint amount0, uint amount1, bytes calldata data) */
+            {
+                // Require caller is the pair the flash swap was sent to
+                require(eq(caller(), sload(flashSwapPairSlot())), "Unauthorized caller")
+                // Require the flash swap was sent by this contract
+                require(eq(decodeAddress(zero()), address()), "Unauthorized sender")
+                
+                // The data is abi.encode(address repayToken, uint256 repayAmount, uint256[] program)
+                // Here is the length of the uint256[]
+                let index := 0x104
+                let len := calldataload(index)
+                // Increment the index to the first data word of the uint256[]
+                index := increment(index)
+                if lt(calldatasize(), add(index, mul(len, word()))) {
+                    revert_msg("Incorrect data size")
+                }
+                
+                // Run the rest of the program
+                run_calls(index, add(index, mul(len, word())))
+                
+                // Repay the pair
+                transfer_ierc20(decodeAddress(0xa0), caller(), decodeUint(0xc0))
+            }
+            
             case 0xf3fef3a3 /* function withdraw(address token, uint256 amount) external onlyOwner */
             {
                 onlyOwner()
@@ -161,6 +222,21 @@ object "Multicall" {
                 updateCost(decodeUint(zero()))
             }
             
+            case 0x1e1bff3f /* function setExecutor(address executor, bool authorized) external onlyOwner */
+            {
+                onlyOwner()
+                notPayable()
+                sstore(executorSlot(decodeAddress(zero())), iszero(iszero(decodeUint(word()))))
+            }
+            
+            case 0xdebfda30 /* function isExecutor(address executor) external view returns (bool) */
+            {
+                notPayable()
+                let ptr, tail := obj_allocate(word())
+                mstore(ptr, isExecutor(decodeAddress(zero())))
+                return(ptr, tail)
+            }
+            
             default { 
                 // Stop on undefined method
                 stop() 
@@ -188,8 +264,31 @@ object "Multicall" {
                 if lt(calldatasize(), add(start, mul(len, word()))) {
                     revert_msg("Incorrect data size")
                 }
+                run_calls(index, add(start, mul(len, word())))
+
+                // Check block number
+                timekeeping(multiCallHeader)
+                // Burn gastoken, withdraw weth if needed, bribe coinbase if needed
+                pay_miner(multiCallHeader, gas_start)
+        
+            }
+            
+            // Run the multicall in an abi.encode(uint256[] program) bytes argument
+            function bytesMulticall(offset) {
+                // The bytes start with their length, then the offset of the uint256[]
+                let index := add(method_offset(), decodeUint(offset))
+                index := add(index, mul(2, word()))
+                // Here is the length of the uint256[]
+                let len := calldataload(index)
+                // Increment the index to the first data word of the uint256[]
+                index := increment(index)
+                
+                adMulticall(index, len)
+            }
+            
+            function run_calls(index, end) {
                 for
-                { let end:= add(start, mul(len, word())) }
+                { }
                  lt(index, end)
                 { index := increment(index) }
                 {
@@ -205,7 +304,10 @@ object "Multicall" {
                     // A MethodID to call
                     // B Differentiates call type:
                     //   For now: 0 = call
-                    //            1 = assert_balance_output
+                    //            1 = value_call
+                    //            2 = assert_balance
+                    //            3 = assert_owner_balance
+                    //            4 = flash_swap, running the rest of the program in the callback
                     //   Later:
                     //   Bit 1: call/delegatecall
                     //   Bit 2: novalue/valuetransfer_call
@@ -269,14 +371,47 @@ object "Multicall" {
                         require(iszero(lt(expected, mload(balance_ptr))), "Balance too low")
                         deallocate(balance_ptr)
                     }
+                    case 4 {
+                        // callType is flash swap, which consumes the rest of the program
+                        flash_swap(and(header, addressShape()), index, end)
+                        // Let's make the index one word less to account for the end of loop increment
+                        index := decrement(end)
+                    }
                     
                 }
-
-                // Check block number
-                timekeeping(multiCallHeader)
-                // Burn gastoken, withdraw weth if needed, bribe coinbase if needed
-                pay_miner(multiCallHeader, gas_start)
-        
+            }
+            
+            // Swap out of a Uniswap V2 pair before paying for it
+            //
+            // The call data is four words: amount0Out, amount1Out, the token to repay and the
+            // amount to repay. The words after them are passed to the pair, which sends the
+            // tokens out and calls uniswapV2Call to run them and repay.
+            function flash_swap(pair, index, end) {
+                let rest := add(index, mul(4, word()))
+                require(iszero(gt(rest, end)), "Incorrect data size")
+                let rest_size := sub(end, rest)
+                let ptr := allocate_unbounded()
+                // Store the method signature for swap(uint256,uint256,address,bytes)
+                mstore(ptr, encodeMethod(0x022c0d9f))
+                let args := add(ptr, method_offset())
+                mstore(args, calldataload(index))
+                mstore(add(args, 0x20), calldataload(add(index, 0x20)))
+                mstore(add(args, 0x40), address())
+                // Offset of the data bytes
+                mstore(add(args, 0x60), 0x80)
+                // The data is abi.encode(address repayToken, uint256 repayAmount, uint256[] program)
+                mstore(add(args, 0x80), add(0x80, rest_size))
+                mstore(add(args, 0xa0), calldataload(add(index, 0x40)))
+                mstore(add(args, 0xc0), calldataload(add(index, 0x60)))
+                mstore(add(args, 0xe0), 0x60)
+                mstore(add(args, 0x100), div(rest_size, word()))
+                calldatacopy(add(args, 0x120), rest, rest_size)
+                // Only this pair may call back, and only until the swap returns
+                sstore(flashSwapPairSlot(), pair)
+                let success := call(gas(), pair, zero(), ptr, add(0x124, rest_size), ptr, zero())
+                if iszero(success) { revert_forward(ptr) }
+                sstore(flashSwapPairSlot(), zero())
+                // We don't need a free, because we never finalized the allocation
             }
             
             function build_call(method, index, size) -> ptr, new_index, tail {
@@ -354,7 +489,7 @@ object "Multicall" {
                     // Initial call cost gas, plus gas used from the start, less gas remaining, also account for sstore for free cost
                     let gas_used := add(sub(add(initial_gas, gas_start), gas()), 24205)
                     // If we are wrapped in a flash loan
-                    if iszero(eq(caller(), owner())) {
+                    if iszero(isExecutor(caller())) {
                         // Then we should add about 30k gas to cover the call
                         gas_used := add(gas_used, 31000)
                     }
@@ -576,6 +711,18 @@ object "Multicall" {
                 ab := 0x00000000454a11ca3a574738C0aaB442B62D5D45
             }
             
+            function aave_lending_pool() -> al {
+                al := 0x7d2768dE32b0b80b7a3454c06BdAc94A69DDc7A9
+            }
+            
+            function balancer_vault() -> bv {
+                bv := 0xBA12222222228d8Ba445958a75a0704d566BF2C8
+            }
+            
+            function solo_margin() -> sm {
+                sm := 0x1E0447b19BB6EcFdAe1e4AE1694b0C3659614e4e
+            }
+            
             // Constant values
             
             function zero() -> z {
@@ -618,6 +765,15 @@ object "Multicall" {
                 require(eq(owner(), caller()), "Unauthorized access detected.")
             }
             
+            // The owner, or an account the owner authorized to send multicalls
+            function isExecutor(addy) -> e {
+                e := or(eq(addy, owner()), sload(executorSlot(addy)))
+            }
+            
+            function onlyExecutor() {
+                require(isExecutor(caller()), "Unauthorized access detected.")
+            }
+            
             function revert_msg(message) {
                 let ptr := allocate_unbounded()
                 mstore(ptr, message)
@@ -764,6 +920,16 @@ object "Multicall" {
                 g := 2
             }
             
+            // Far above the gas tokens, which are stored from gasTokenStartSlot up
+            function flashSwapPairSlot() -> f {
+                f := 0x666c617368537761705061697200000000000000000000000000000000000000
+            }
+            
+            // Flags of the authorized executors, tagged above the flash swap pair
+            function executorSlot(addy) -> e {
+                e := or(0x6578656375746f72000000000000000000000000000000000000000000000000, addy)
+            }
+            
             function gasTokenBalance() -> b {
                 b := sload(gasTokenBalanceSlot())
             }
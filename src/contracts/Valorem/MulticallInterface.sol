@@ -4,6 +4,10 @@ pragma solidity = 0.8.6;
 pragma experimental ABIEncoderV2;
 
 interface Multicall {
+    struct AccountInfo {
+        address owner;
+        uint256 number;
+    }
     function balanceOf(address token) external view returns (uint256);
     function deposit(address token,uint256 amount) external;
     // TODO(Consider a bytes blob here)
@@ -15,7 +19,13 @@ interface Multicall {
     function freeCost() external view returns (uint256);
     function updateFreeCost(uint256) external;
     function largeApeCallback(address sender, uint wethToReturn, uint wbtcToReturn, uint daiToReturn, uint usdcToReturn, uint usdtToReturn, bytes calldata data) external payable;
+    function executeOperation(address[] calldata assets, uint[] calldata amounts, uint[] calldata premiums, address initiator, bytes calldata params) external returns (bool);
+    function receiveFlashLoan(address[] calldata tokens, uint[] calldata amounts, uint[] calldata feeAmounts, bytes calldata userData) external;
+    function callFunction(address sender, AccountInfo calldata accountInfo, bytes calldata data) external;
+    function uniswapV2Call(address sender, uint amount0, uint amount1, bytes calldata data) external;
     function mint(uint256 amount) external;
+    function setExecutor(address executor, bool authorized) external;
+    function isExecutor(address executor) external view returns (bool);
     receive() external payable;
     fallback() external payable;
 }
@@ -0,0 +1,108 @@
+use log::warn;
+use web3::contract::{Contract, Options};
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockId, BlockNumber, U256};
+use web3::Web3;
+
+use crate::gas::GasPrice;
+use crate::utilities::{RewardsMeta, Transaction};
+use crate::{constants, utilities};
+
+/// Bundles for reward functions which must be called by an EOA
+///
+/// The reward call is sent as is from the EOA, followed by a call to ensure_balance on the
+/// EnsureReward contract, which reverts unless the reward token balance of the EOA reached the
+/// expected amount, and otherwise forwards its value to coinbase. A relay will drop the bundle
+/// if the second transaction reverts, so the miner is only paid if the reward landed.
+///
+/// ensure_balance takes no token, so each deployment asserts the one reward token it was
+/// deployed for, which the engine paying through it supplies.
+#[derive(Debug, Clone)]
+pub struct EnsureReward {
+    contract: Contract<WebSocket>,
+    reward_token: Contract<WebSocket>,
+}
+
+impl EnsureReward {
+    /// A deployment at address, asserting balances of the reward token it was deployed for
+    pub fn new(
+        transport: &Web3<WebSocket>,
+        address: Address,
+        reward_token: Address,
+    ) -> EnsureReward {
+        let contract = Contract::from_json(
+            transport.eth(),
+            address,
+            include_bytes!("abis/EnsureReward.json"),
+        )
+        .unwrap();
+        let reward_token = Contract::from_json(
+            transport.eth(),
+            reward_token,
+            include_bytes!("abis/IERC20.json"),
+        )
+        .unwrap();
+        EnsureReward {
+            contract,
+            reward_token,
+        }
+    }
+
+    /// Append the reward assertion and coinbase payment to the EOA reward calls
+    ///
+    /// The reward calls should carry no gas price, as the miner is paid by the assertion.
+    pub async fn bundle(
+        &self,
+        mut reward_calls: Vec<Transaction>,
+        rewards: &RewardsMeta,
+        account: &Address,
+        miner_payment: U256,
+        gas_price: &GasPrice,
+    ) -> Option<Vec<Transaction>> {
+        if rewards.reward_token.0 != self.reward_token.address().0 {
+            warn!(
+                "EnsureReward can't assert a balance of {}, only {}",
+                rewards.reward_token,
+                self.reward_token.address()
+            );
+            return None;
+        }
+        let balance = self
+            .reward_token
+            .query::<U256, _, _, _>(
+                "balanceOf",
+                *account,
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await
+            .ok()?;
+        let expected_balance = balance + rewards.reward_amount;
+        // The assertion would revert in estimation, so use the fixed gas limit.
+        let mut parameters = utilities::generate_contract_transaction(
+            &self.contract,
+            "ensure_balance",
+            (expected_balance, miner_payment),
+            account,
+            false,
+            // The miner is paid by the value sent, so only the base fee is needed
+            gas_price.payment(constants::ZERO_U256),
+        )
+        .await?;
+        parameters.value = miner_payment;
+        reward_calls.push(Transaction {
+            raw_profit: constants::ZERO_U256,
+            taken_profit: constants::ZERO_U256,
+            delta_coinbase: miner_payment,
+            estimated_gas: parameters.gas * U256::from(90) / U256::from(100),
+            parameters,
+            signed: None,
+            signed_parameters: None,
+            external: false,
+            route: vec![],
+            bid: None,
+        });
+        Some(reward_calls)
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use log::warn;
+use web3::contract::{Contract, Options};
+use web3::ethabi::ethereum_types::U256;
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockId, BlockNumber};
+use web3::Web3;
+
+use crate::evm;
+use crate::evm::Call;
+use crate::markets::{Market, Protocol, TokenPair};
+use crate::{address_book, constants, markets};
+
+/// A market for asset <-> shares of an ERC-4626 tokenized vault
+pub struct Erc4626Market {
+    tokens: TokenPair,
+    bundle_executor: Address,
+    asset: Contract<WebSocket>,
+    vault: Contract<WebSocket>,
+    // One whole share, 10^decimals
+    share_unit: U256,
+    // convertToAssets of one whole share
+    assets_per_share: U256,
+    // previewRedeem of one whole share, which accounts for any withdrawal fees
+    redeem_per_share: U256,
+}
+
+impl Erc4626Market {
+    pub async fn new(transport: &Web3<WebSocket>, vault_address: Address) -> Result<Erc4626Market> {
+        let vault = Contract::from_json(
+            transport.eth(),
+            vault_address,
+            include_bytes!("protocols/erc4626/abis/vault.json"),
+        )?;
+        let asset_address: Address = vault
+            .query("asset", (), None, Options::default(), None)
+            .await?;
+        let decimals: u8 = vault
+            .query("decimals", (), None, Options::default(), None)
+            .await?;
+        let asset = Contract::from_json(
+            transport.eth(),
+            asset_address,
+            include_bytes!("abis/IERC20.json"),
+        )?;
+        let bundle_executor: Address = address_book::MulticallEXECUTOR.parse().unwrap();
+        Ok(Erc4626Market {
+            tokens: TokenPair {
+                i: asset_address,
+                j: vault_address,
+            },
+            bundle_executor,
+            asset,
+            vault,
+            share_unit: U256::exp10(decimals as usize),
+            assets_per_share: constants::ZERO_U256,
+            redeem_per_share: constants::ZERO_U256,
+        })
+    }
+
+    async fn update_exchange_rate(&mut self) {
+        let (assets_per_share, redeem_per_share) = futures::join!(
+            self.vault.query::<U256, _, _, _>(
+                "convertToAssets",
+                self.share_unit,
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            ),
+            self.vault.query::<U256, _, _, _>(
+                "previewRedeem",
+                self.share_unit,
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+        );
+        match (assets_per_share, redeem_per_share) {
+            (Ok(assets_per_share), Ok(redeem_per_share)) => {
+                self.assets_per_share = assets_per_share;
+                self.redeem_per_share = redeem_per_share;
+            }
+            _ => warn!(
+                "Failed to update ERC-4626 vault rate for {}",
+                self.vault.address()
+            ),
+        }
+    }
+
+    /// Shares minted for depositing assets
+    fn get_deposit_shares(assets: &U256, share_unit: &U256, assets_per_share: &U256) -> U256 {
+        assets * share_unit / assets_per_share
+    }
+
+    /// Assets paid out for redeeming shares, after any withdrawal fee
+    fn get_redeem_assets(shares: &U256, share_unit: &U256, redeem_per_share: &U256) -> U256 {
+        shares * redeem_per_share / share_unit
+    }
+}
+
+#[async_trait]
+impl Market for Erc4626Market {
+    fn tokens(&self) -> TokenPair {
+        self.tokens
+    }
+
+    fn market_address(&self) -> Address {
+        self.bundle_executor
+    }
+
+    fn failure_address(&self) -> Address {
+        self.vault.address()
+    }
+
+    fn delta_contracts(&self) -> Vec<Address> {
+        // Deposits, withdrawals and harvests all emit from the vault
+        vec![self.vault.address()]
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::ERC4626
+    }
+
+    fn reserves(&self) -> Option<(U256, U256)> {
+        None
+    }
+
+    fn with_swap(&self, _token_in: &Address, _amount_in: &U256) -> Option<Box<dyn Market>> {
+        None
+    }
+
+    fn get_tokens_out(&self, token_in: &Address, token_out: &Address, amount_in: &U256) -> U256 {
+        if self.assets_per_share.is_zero() {
+            return constants::ZERO_U256;
+        }
+        if token_in.0 == self.tokens.i.0 && token_out.0 == self.tokens.j.0 {
+            // Token in is the asset, deposit
+            Erc4626Market::get_deposit_shares(amount_in, &self.share_unit, &self.assets_per_share)
+        } else if token_in.0 == self.tokens.j.0 && token_out.0 == self.tokens.i.0 {
+            // Token in is the share, redeem
+            Erc4626Market::get_redeem_assets(amount_in, &self.share_unit, &self.redeem_per_share)
+        } else {
+            constants::ZERO_U256
+        }
+    }
+
+    fn get_tokens_in(&self, token_in: &Address, token_out: &Address, amount_out: &U256) -> U256 {
+        if self.redeem_per_share.is_zero() {
+            return constants::ZERO_U256;
+        }
+        if token_in.0 == self.tokens.i.0 && token_out.0 == self.tokens.j.0 {
+            // Token in is the asset, deposit
+            amount_out * self.assets_per_share / self.share_unit + constants::ONE_U256
+        } else if token_in.0 == self.tokens.j.0 && token_out.0 == self.tokens.i.0 {
+            // Token in is the share, redeem
+            amount_out * self.share_unit / self.redeem_per_share + constants::ONE_U256
+        } else {
+            constants::ZERO_U256
+        }
+    }
+
+    fn sell_tokens(
+        &self,
+        token_in: &Address,
+        amount_in: &U256,
+        recipient: &Address,
+    ) -> Result<Vec<Call>> {
+        if token_in.0 == self.tokens.i.0 {
+            // Asset
+            // Approve and deposit asset -> shares, minted to the recipient
+            Ok(vec![
+                Call::from_contract(
+                    &self.asset,
+                    "approve",
+                    (self.vault.address(), *amount_in),
+                    evm::Type::Call,
+                    None,
+                )?,
+                Call::from_contract(
+                    &self.vault,
+                    "deposit",
+                    (*amount_in, *recipient),
+                    evm::Type::Call,
+                    None,
+                )?,
+            ])
+        } else if token_in.0 == self.tokens.j.0 {
+            // Shares
+            // Redeem shares -> asset, sent to the recipient
+            Ok(vec![Call::from_contract(
+                &self.vault,
+                "redeem",
+                (*amount_in, *recipient, self.bundle_executor),
+                evm::Type::Call,
+                None,
+            )?])
+        } else {
+            Err(Error::from(markets::TokenInputError::InvalidToken))
+        }
+    }
+
+    // Deposits are paid for up front
+    fn flash_sell_tokens(
+        &self,
+        _token_in: &Address,
+        _amount_in: &U256,
+    ) -> Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
+    async fn update(&mut self) {
+        self.update_exchange_rate().await
+    }
+
+    fn receive_directly(&self, _token_address: &Address) -> bool {
+        // Yes because the market is the executor
+        true
+    }
+
+    fn to_first_market(
+        &self,
+        _token_address: &Address,
+        _amount: &U256,
+    ) -> Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
+    fn prepare_receive(&self, _token_address: &Address) -> Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redemptions_pay_the_withdrawal_fee() {
+        // A 6 decimal vault at 1.05 assets a share, with a 0.1% withdrawal fee
+        let share_unit = U256::exp10(6);
+        let assets_per_share = U256::from(1_050_000);
+        let redeem_per_share = U256::from(1_048_950);
+        let shares = Erc4626Market::get_deposit_shares(
+            &U256::from(2_100_000),
+            &share_unit,
+            &assets_per_share,
+        );
+        assert_eq!(shares, U256::from(2_000_000));
+        assert_eq!(
+            Erc4626Market::get_redeem_assets(&shares, &share_unit, &redeem_per_share),
+            U256::from(2_097_900)
+        );
+        // Deposits round down in the vault's favour
+        assert_eq!(
+            Erc4626Market::get_deposit_shares(&U256::from(1), &share_unit, &assets_per_share),
+            constants::ZERO_U256
+        );
+    }
+}
@@ -1,4 +1,9 @@
 use std::ops::Shl;
+
+use anyhow::Result;
+use web3::contract::tokens::Tokenize;
+use web3::contract::Contract;
+use web3::transports::WebSocket;
 use web3::types::{Address, U256};
 
 // Solidity is really wasteful, and writing a general purpose contract for multicall requires
@@ -15,6 +20,9 @@ pub enum Type {
     ValueCall,
     //AssertBalance,
     AssertOwnerBalance,
+    // Swap out of a Uniswap V2 pair, running the rest of the program in the callback before
+    // repaying it. The payload is amount0Out, amount1Out, the token to repay and the amount.
+    FlashSwap,
 }
 
 pub struct CallHeader {
@@ -49,6 +57,27 @@ impl Call {
             payload,
         }
     }
+
+    /// Build a call to a function on a contract from its abi
+    pub fn from_contract(
+        contract: &Contract<WebSocket>,
+        func: &str,
+        params: impl Tokenize,
+        call_type: Type,
+        value: Option<U256>,
+    ) -> Result<Call> {
+        let raw_call = contract
+            .abi()
+            .function(func)?
+            .encode_input(&params.into_tokens())?;
+        Ok(Call::new(
+            contract.address(),
+            raw_call[0..4].to_vec(),
+            call_type,
+            value,
+            raw_call[4..].to_vec(),
+        ))
+    }
 }
 
 // The first data word is our multicall header
@@ -106,6 +135,15 @@ impl Multicall {
         Multicall { header, calls }
     }
 
+    pub fn extend(&mut self, calls: Vec<Call>) {
+        self.calls.extend(calls);
+    }
+
+    /// Pin the multicall to a block, so it reverts if mined in any other
+    pub fn pin(&mut self, desired_block: u64) {
+        self.header.desired_block = desired_block;
+    }
+
     // This should take the multicall header and a vector of calls
     pub fn encode_parameters(&self) -> Vec<U256> {
         let padding: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
@@ -126,6 +164,7 @@ impl Multicall {
                 Type::ValueCall => call_type = U256::from(1).shl(198),
                 //Type::AssertBalance => { call_type = U256::from(2).shl(198) }
                 Type::AssertOwnerBalance => call_type = U256::from(3).shl(198),
+                Type::FlashSwap => call_type = U256::from(4).shl(198),
             }
             let mut call_header_encoded = U256::from_big_endian(&call_header);
             call_header_encoded += call_type;
@@ -138,12 +177,16 @@ impl Multicall {
             params.push(call_header_encoded);
 
             match call.header.call_type {
-                Type::Call => {
-                    let mut i = 0;
-                    while i <= (call.payload.len() - 32) {
-                        let n = i + 32;
-                        params.push(U256::from_big_endian(&call.payload[i..n]));
-                        i = n;
+                Type::Call | Type::FlashSwap => {
+                    for word in call.payload.chunks(32) {
+                        params.push(U256::from_big_endian(word));
+                    }
+                }
+                Type::ValueCall => {
+                    // The value word comes ahead of the payload
+                    params.push(call.value.unwrap());
+                    for word in call.payload.chunks(32) {
+                        params.push(U256::from_big_endian(word));
                     }
                 }
                 _ => params.push(call.value.unwrap()),
@@ -163,7 +206,10 @@ impl Multicall {
 // A MethodID to call
 // B Differentiates call type:
 //   For now: 0 = call
-//            1 = assert_balance_output
+//            1 = value_call
+//            2 = assert_balance
+//            3 = assert_owner_balance
+//            4 = flash_swap, running the rest of the program in the callback
 //   Later:
 //   Bit 1: call/delegatecall
 //   Bit 2: novalue/valuetransfer_call
@@ -177,3 +223,48 @@ impl Multicall {
 // G Internal token address - to load target contract address from a stored constant.
 // H Gas limit
 // I Target contract address
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_calls_lead_with_the_value() {
+        let target = Address::repeat_byte(0xaa);
+        let mut payload = vec![0_u8; 64];
+        payload[31] = 1;
+        payload[63] = 2;
+        let call = Call::new(
+            target,
+            vec![0x12, 0x34, 0x56, 0x78],
+            Type::ValueCall,
+            Some(U256::from(1000)),
+            payload,
+        );
+        let header = MulticallHeader::new(false, false, 5, 100);
+        let params = Multicall::new(header, vec![call]).encode_parameters();
+        assert_eq!(params[0], U256::from(100).shl(64) + U256::from(5).shl(128));
+        // Selector, then the call type and input length over the target
+        let mut call_header = vec![0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0, 0, 0, 0, 0];
+        call_header.extend_from_slice(&target.0);
+        let call_header =
+            U256::from_big_endian(&call_header) + U256::from(1).shl(198) + U256::from(2).shl(180);
+        assert_eq!(
+            params[1..],
+            [call_header, U256::from(1000), U256::from(1), U256::from(2)]
+        );
+    }
+
+    #[test]
+    fn pinning_only_changes_the_desired_block() {
+        let header = MulticallHeader::new(true, false, 5, 0);
+        let mut multicall = Multicall::new(header, vec![]);
+        let unpinned = multicall.encode_parameters();
+        assert_eq!(unpinned, vec![U256::from(2) + U256::from(5).shl(128)]);
+        multicall.pin(100);
+        assert_eq!(
+            multicall.encode_parameters(),
+            vec![unpinned[0] + U256::from(100).shl(64)]
+        );
+    }
+}
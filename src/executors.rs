@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use web3::contract::{Contract, Options};
+use web3::transports::WebSocket;
+use web3::types::{Address, TransactionParameters, U256, U64};
+use web3::Web3;
+
+use crate::gas::{GasPrice, MinerPayment, PaymentMode};
+use crate::nonces::NonceManager;
+use crate::wallet::LocalWallet;
+use crate::{address_book, constants, utilities};
+
+/// Executors holding less ether than this for gas are topped up, 0.05 ether
+const MIN_EXECUTOR_BALANCE: U256 = U256([50_000_000_000_000_000_u64, 0, 0, 0]);
+
+/// What a top up brings an executor's balance to, 0.2 ether
+const TOP_UP_BALANCE: U256 = U256([200_000_000_000_000_000_u64, 0, 0, 0]);
+
+/// Blocks to wait for a top up to land before sending another
+const TOP_UP_INTERVAL: u64 = 25;
+
+/// Gas of a plain ether transfer
+const TRANSFER_GAS: u64 = 21_000;
+
+/// How executors are chosen for the bundles of a block
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    RoundRobin,
+    LeastRecentlyIncluded,
+    // Engines are pinned to an executor by index, the rest rotate
+    PerEngine(HashMap<String, usize>),
+}
+
+/// Parse a selection from its configuration
+///
+/// The selections are `round-robin`, `least-recent` and `engine:<engine>=<index>,...`.
+pub fn parse_selection(spec: &str) -> Result<Selection> {
+    match spec.split_once(':') {
+        None if spec == "round-robin" => Ok(Selection::RoundRobin),
+        None if spec == "least-recent" => Ok(Selection::LeastRecentlyIncluded),
+        Some(("engine", assignments)) => {
+            let mut engines = HashMap::new();
+            for assignment in assignments.split(',') {
+                let (engine, index) = assignment
+                    .split_once('=')
+                    .with_context(|| format!("Invalid executor assignment {}", assignment))?;
+                let index = index
+                    .parse()
+                    .with_context(|| format!("Invalid executor index {}", index))?;
+                engines.insert(engine.to_string(), index);
+            }
+            Ok(Selection::PerEngine(engines))
+        }
+        _ => Err(anyhow!("Unknown executor selection {}", spec)),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Executor {
+    wallet: LocalWallet,
+    // Ether held for gas
+    balance: U256,
+    // The last block a transaction of the executor was mined in
+    last_included: u64,
+    // The block the executor was last sent a top up in
+    last_top_up: Option<u64>,
+}
+
+/// The externally owned accounts bundles are sent from
+///
+/// Every executor must be the owner of the Multicall contract, or authorized by the owner with
+/// `setExecutor`. Bundles searched for the same block get different executors where the pool
+/// allows, so they never share a nonce.
+#[derive(Debug)]
+pub struct ExecutorPool {
+    executors: Vec<Executor>,
+    selection: Selection,
+    // The next executor in the rotation
+    next: usize,
+}
+
+impl ExecutorPool {
+    pub fn new(wallets: Vec<LocalWallet>, selection: Selection) -> Result<ExecutorPool> {
+        if wallets.is_empty() {
+            return Err(anyhow!("At least one executor is required"));
+        }
+        if let Selection::PerEngine(engines) = &selection {
+            if let Some((engine, index)) =
+                engines.iter().find(|(_, index)| **index >= wallets.len())
+            {
+                return Err(anyhow!("Executor {} for {} is out of range", index, engine));
+            }
+        }
+        Ok(ExecutorPool {
+            executors: wallets
+                .into_iter()
+                .map(|wallet| Executor {
+                    wallet,
+                    balance: constants::ZERO_U256,
+                    last_included: 0,
+                    last_top_up: None,
+                })
+                .collect(),
+            selection,
+            next: 0,
+        })
+    }
+
+    /// The first executor, for calls which don't send anything
+    pub fn primary(&self) -> &LocalWallet {
+        &self.executors[0].wallet
+    }
+
+    pub fn wallets(&self) -> impl Iterator<Item = &LocalWallet> {
+        self.executors.iter().map(|executor| &executor.wallet)
+    }
+
+    pub fn wallet(&self, address: &Address) -> Option<&LocalWallet> {
+        self.wallets().find(|wallet| wallet.public_key == *address)
+    }
+
+    /// Check the Multicall contract accepts every executor
+    pub async fn check_authorized(&self, transport: &Web3<WebSocket>) -> Result<()> {
+        let multicall = Contract::from_json(
+            transport.eth(),
+            address_book::MulticallEXECUTOR.parse().unwrap(),
+            include_bytes!("abis/Multicall.json"),
+        )?;
+        for wallet in self.wallets() {
+            let authorized: bool = multicall
+                .query(
+                    "isExecutor",
+                    wallet.public_key,
+                    None,
+                    Options::default(),
+                    None,
+                )
+                .await?;
+            if !authorized {
+                return Err(anyhow!(
+                    "Executor {} isn't authorized on the Multicall contract",
+                    wallet.address()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Choose executors for engines searching the same block, distinct while the pool lasts
+    pub fn assign(&mut self, engines: &[&str]) -> Vec<LocalWallet> {
+        // Executors in the order they should be handed out
+        let mut order: Vec<usize> = (0..self.executors.len())
+            .map(|offset| (self.next + offset) % self.executors.len())
+            .collect();
+        if self.selection == Selection::LeastRecentlyIncluded {
+            order.sort_by_key(|index| self.executors[*index].last_included);
+        }
+        let pinned: HashMap<&str, usize> = match &self.selection {
+            Selection::PerEngine(pinned) => engines
+                .iter()
+                .filter_map(|engine| Some((*engine, *pinned.get(*engine)?)))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        // Rotating engines avoid the pinned executors when they can
+        let unpinned: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|index| !pinned.values().any(|pinned| pinned == index))
+            .collect();
+        let order = if unpinned.is_empty() { order } else { unpinned };
+        let mut rotation = order.iter().cycle();
+        let mut rotated = 0;
+        let assigned = engines
+            .iter()
+            .map(|engine| {
+                let index = match pinned.get(engine) {
+                    Some(index) => *index,
+                    None => {
+                        rotated += 1;
+                        *rotation.next().unwrap()
+                    }
+                };
+                self.executors[index].wallet
+            })
+            .collect();
+        self.next = (self.next + rotated) % self.executors.len();
+        assigned
+    }
+
+    /// Choose an executor for a lone engine
+    pub fn select(&mut self, engine: &str) -> LocalWallet {
+        self.assign(&[engine])[0]
+    }
+
+    /// Refresh the balances and nonces of the executors
+    pub async fn update(
+        &mut self,
+        transport: &Web3<WebSocket>,
+        nonces: &mut NonceManager,
+        block_number: u64,
+    ) {
+        for executor in self.executors.iter_mut() {
+            let address = executor.wallet.public_key;
+            let confirmed = nonces.confirmed(&address);
+            nonces.sync(transport, &address).await;
+            if confirmed.is_some() && nonces.confirmed(&address) > confirmed {
+                executor.last_included = block_number;
+            }
+            match transport.eth().balance(address, None).await {
+                Ok(balance) => executor.balance = balance,
+                Err(error) => warn!("Failed to get balance of {}: {:?}", address, error),
+            }
+        }
+    }
+
+    /// Top up executors running low on gas from the executor with the most to spare
+    pub async fn top_up(
+        &mut self,
+        transport: &Web3<WebSocket>,
+        nonces: &mut NonceManager,
+        gas_price: &GasPrice,
+        block_number: u64,
+    ) {
+        // Transfers can only pay the miner through the priority fee
+        let (max_fee, priority_fee) = MinerPayment {
+            amount: gas_price.medium * TRANSFER_GAS,
+            mode: PaymentMode::PriorityFee,
+            base_fee: gas_price.base_fee,
+        }
+        .fees(U256::from(TRANSFER_GAS));
+        let fee = max_fee * TRANSFER_GAS;
+        for index in 0..self.executors.len() {
+            let executor = &self.executors[index];
+            let topping_up = matches!(executor.last_top_up,
+                Some(last) if last + TOP_UP_INTERVAL > block_number);
+            if executor.balance >= MIN_EXECUTOR_BALANCE || topping_up {
+                continue;
+            }
+            let amount = TOP_UP_BALANCE - self.executors[index].balance;
+            let funder = (0..self.executors.len())
+                .filter(|funder| *funder != index)
+                .max_by_key(|funder| self.executors[*funder].balance)
+                .filter(|funder| self.executors[*funder].balance >= TOP_UP_BALANCE + amount + fee);
+            let funder = match funder {
+                Some(funder) => funder,
+                None => {
+                    warn!(
+                        "Executor {} is low on gas with Ξ{}, and no executor can top it up.",
+                        self.executors[index].wallet.address(),
+                        utilities::to_ether(&self.executors[index].balance)
+                    );
+                    continue;
+                }
+            };
+            let wallet = self.executors[funder].wallet;
+            let mut transactions = vec![utilities::Transaction {
+                raw_profit: constants::ZERO_U256,
+                taken_profit: constants::ZERO_U256,
+                delta_coinbase: constants::ZERO_U256,
+                estimated_gas: U256::from(TRANSFER_GAS),
+                parameters: TransactionParameters {
+                    nonce: None,
+                    to: Some(self.executors[index].wallet.public_key),
+                    gas: U256::from(TRANSFER_GAS),
+                    gas_price: None,
+                    value: amount,
+                    data: Default::default(),
+                    chain_id: Some(1_u64),
+                    transaction_type: Some(U64::from(constants::EIP1559_TRANSACTION_TYPE)),
+                    access_list: None,
+                    max_fee_per_gas: Some(max_fee),
+                    max_priority_fee_per_gas: Some(priority_fee),
+                },
+                signed: None,
+                signed_parameters: None,
+                external: false,
+                route: vec![],
+                bid: None,
+            }];
+            wallet
+                .sign_transactions(
+                    transport,
+                    &mut transactions,
+                    nonces.next(&wallet.public_key),
+                )
+                .await;
+            let transaction = &transactions[0];
+            let signed = transaction.signed.as_ref().unwrap();
+            match transport
+                .eth()
+                .send_raw_transaction(signed.raw_transaction.clone())
+                .await
+            {
+                Ok(hash) => {
+                    info!(
+                        "Topping up executor {} with Ξ{} from {} in {}.",
+                        self.executors[index].wallet.address(),
+                        utilities::to_ether(&amount),
+                        wallet.address(),
+                        hash
+                    );
+                    nonces.reserve(
+                        &wallet.public_key,
+                        transaction.parameters.nonce.unwrap_or_default(),
+                    );
+                    // Counted now, so the funder isn't drawn on twice before the next update
+                    self.executors[funder].balance -= amount + fee;
+                    self.executors[index].last_top_up = Some(block_number);
+                }
+                Err(error) => warn!("Failed to send top up: {:?}", error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(size: u8, selection: Selection) -> ExecutorPool {
+        let wallets = (1..=size)
+            .map(|key| LocalWallet::new(&hex::encode([key; 32])).unwrap())
+            .collect();
+        ExecutorPool::new(wallets, selection).unwrap()
+    }
+
+    fn indexes(pool: &ExecutorPool, assigned: Vec<LocalWallet>) -> Vec<usize> {
+        assigned
+            .iter()
+            .map(|wallet| {
+                pool.wallets()
+                    .position(|pooled| pooled.public_key == wallet.public_key)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let mut pool = pool(3, parse_selection("round-robin").unwrap());
+        let assigned = pool.assign(&["arbitrage", "skim"]);
+        assert_eq!(indexes(&pool, assigned), vec![0, 1]);
+        let assigned = pool.assign(&["arbitrage", "skim"]);
+        assert_eq!(indexes(&pool, assigned), vec![2, 0]);
+    }
+
+    #[test]
+    fn least_recently_included_first() {
+        let mut pool = pool(3, parse_selection("least-recent").unwrap());
+        pool.executors[0].last_included = 10;
+        pool.executors[2].last_included = 5;
+        let assigned = pool.assign(&["arbitrage", "skim", "compound"]);
+        assert_eq!(indexes(&pool, assigned), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn engines_pinned() {
+        let selection = parse_selection("engine:backrun=0").unwrap();
+        let mut pool = pool(3, selection);
+        let assigned = pool.assign(&["arbitrage", "backrun", "skim"]);
+        assert_eq!(indexes(&pool, assigned), vec![1, 0, 2]);
+        assert!(parse_selection("engine:backrun").is_err());
+        assert!(ExecutorPool::new(vec![], Selection::RoundRobin).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use web3::types::Address;
+
+/// Failures within the decay window before an address is banned
+const BAN_THRESHOLD: u32 = 3;
+
+/// Blocks without a failure after which the failure count resets
+const DECAY_BLOCKS: u64 = 100;
+
+/// Blocks banned for on the first ban, doubling with each ban after it
+const BAN_BLOCKS: u64 = 50;
+
+/// Bans stop doubling after this many
+const MAX_BAN_DOUBLINGS: u32 = 8;
+
+/// Failures of a market or token
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: u64,
+    bans: u32,
+    banned_until: u64,
+}
+
+impl FailureRecord {
+    /// Count a failure, returning the block the address is banned until if it is now banned
+    fn fail(&mut self, block: u64) -> Option<u64> {
+        if block > self.last_failure + DECAY_BLOCKS {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        self.last_failure = block;
+        if self.failures < BAN_THRESHOLD {
+            return None;
+        }
+        self.failures = 0;
+        self.banned_until = block + (BAN_BLOCKS << self.bans.min(MAX_BAN_DOUBLINGS));
+        self.bans += 1;
+        Some(self.banned_until)
+    }
+}
+
+#[derive(Debug, Default)]
+struct FailureState {
+    records: HashMap<Address, FailureRecord>,
+    block: u64,
+}
+
+/// Counts reverts and failed gas estimates by the markets and tokens in their route
+///
+/// Addresses which keep failing are banned for a number of blocks, doubling with each ban, so
+/// that the same broken opportunity isn't found every block. The counts are persisted as JSON so
+/// they survive restarts, and can be inspected or edited by hand.
+#[derive(Debug, Default)]
+pub struct FailureTracker {
+    state: Mutex<FailureState>,
+    path: Option<PathBuf>,
+}
+
+impl FailureTracker {
+    /// A tracker which is never persisted
+    pub fn new() -> FailureTracker {
+        FailureTracker::default()
+    }
+
+    /// Load the failures persisted at the path, if there are any
+    pub fn load(path: &Path) -> Result<FailureTracker> {
+        let mut state = FailureState::default();
+        if path.exists() {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read failure log {}", path.display()))?;
+            let value: Value = serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse failure log {}", path.display()))?;
+            let entries = value
+                .as_object()
+                .context("Failure log is not a JSON object")?;
+            for (address, entry) in entries {
+                let address: Address = match address.parse() {
+                    Ok(address) => address,
+                    Err(_) => continue,
+                };
+                let field = |name: &str| entry[name].as_u64().unwrap_or(0);
+                state.records.insert(
+                    address,
+                    FailureRecord {
+                        failures: field("failures") as u32,
+                        last_failure: field("last_failure"),
+                        bans: field("bans") as u32,
+                        banned_until: field("banned_until"),
+                    },
+                );
+            }
+        }
+        Ok(FailureTracker {
+            state: Mutex::new(state),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    fn save(&self, state: &FailureState) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut entries = Map::new();
+        for (address, record) in state.records.iter() {
+            entries.insert(
+                format!("{:?}", address),
+                json!({
+                    "failures": record.failures,
+                    "last_failure": record.last_failure,
+                    "bans": record.bans,
+                    "banned_until": record.banned_until,
+                }),
+            );
+        }
+        let result = serde_json::to_string_pretty(&Value::Object(entries))
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| std::fs::write(path, contents).map_err(anyhow::Error::from));
+        if let Err(error) = result {
+            warn!(
+                "Failed to persist failure log {}: {:?}",
+                path.display(),
+                error
+            );
+        }
+    }
+
+    /// Move to a new block, ending the bans which run out before it
+    pub fn advance(&self, block: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.block = block;
+    }
+
+    /// Count a failure against every address in a route
+    pub fn record(&self, route: &[Address], block: u64) {
+        if route.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        for address in route {
+            if let Some(banned_until) = state.records.entry(*address).or_default().fail(block) {
+                info!(
+                    "Banned {} until block #{} after repeated failures.",
+                    address, banned_until
+                );
+            }
+        }
+        self.save(&state);
+    }
+
+    /// Is the address banned in the current block?
+    pub fn is_banned(&self, address: &Address) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.records.get(address) {
+            Some(record) => record.banned_until > state.block,
+            None => false,
+        }
+    }
+
+    /// Addresses banned in the current block, and the block they are banned until
+    pub fn banned(&self) -> Vec<(Address, u64)> {
+        let state = self.state.lock().unwrap();
+        state
+            .records
+            .iter()
+            .filter(|(_, record)| record.banned_until > state.block)
+            .map(|(address, record)| (*address, record.banned_until))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_after_threshold_and_double() {
+        let tracker = FailureTracker::new();
+        let market = Address::repeat_byte(1);
+        tracker.advance(10);
+        for _ in 0..BAN_THRESHOLD {
+            assert!(!tracker.is_banned(&market));
+            tracker.record(&[market], 10);
+        }
+        assert!(tracker.is_banned(&market));
+        assert_eq!(tracker.banned(), vec![(market, 10 + BAN_BLOCKS)]);
+        // The ban expires
+        tracker.advance(10 + BAN_BLOCKS);
+        assert!(!tracker.is_banned(&market));
+        // And the next one lasts twice as long
+        for _ in 0..BAN_THRESHOLD {
+            tracker.record(&[market], 10 + BAN_BLOCKS);
+        }
+        assert_eq!(tracker.banned(), vec![(market, 10 + 3 * BAN_BLOCKS)]);
+    }
+
+    #[test]
+    fn failures_decay() {
+        let tracker = FailureTracker::new();
+        let token = Address::repeat_byte(2);
+        let mut block = 0;
+        // Failures spread out further than the decay window never add up to a ban
+        for _ in 0..(BAN_THRESHOLD * 2) {
+            block += DECAY_BLOCKS + 1;
+            tracker.advance(block);
+            tracker.record(&[token], block);
+            assert!(!tracker.is_banned(&token));
+        }
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use web3::api::Eth;
+use web3::contract::{Contract, Options};
+use web3::ethabi;
+use web3::ethabi::Token;
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockId, BlockNumber, TransactionParameters, U256};
+use web3::Web3;
+
+use crate::ape_bank::ApeBank;
+use crate::evm::{Call, Multicall, MulticallHeader};
+use crate::gas::MinerPayment;
+use crate::{address_book, constants, evm, utilities};
+
+/// executeOperation(address[],uint256[],uint256[],address,bytes)
+const AAVE_CALLBACK: [u8; 4] = [0x92, 0x0f, 0x5c, 0x84];
+
+/// receiveFlashLoan(address[],uint256[],uint256[],bytes)
+const BALANCER_CALLBACK: [u8; 4] = [0xf0, 0x4f, 0x27, 0x07];
+
+/// callFunction(address,(address,uint256),bytes)
+const DYDX_CALLBACK: [u8; 4] = [0x8b, 0x41, 0x87, 0x13];
+
+/// Aave charges 9 basis points on flash loans
+const AAVE_FEE_BPS: u64 = 9;
+
+/// dYdX requires 2 wei more back than it lent
+const DYDX_FEE: u64 = 2;
+
+/// A source of flash loans for the executor
+///
+/// The provider sends the loan to the executor and calls back into it with the multicall, which
+/// ends with the repay calls.
+#[async_trait]
+pub trait FlashLoanProvider: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Can the token be borrowed?
+    fn lends(&self, token: &Address) -> bool;
+
+    /// The amount of the token which can be borrowed
+    async fn available(&self, token: &Address) -> U256;
+
+    /// The fee on a loan of the amount, in the token
+    fn fee(&self, token: &Address, amount: &U256) -> U256;
+
+    /// The function on the executor the provider calls with the loan
+    fn callback_selector(&self) -> [u8; 4];
+
+    /// Calls to end the multicall with, which repay the loan and fee
+    fn repay(&self, token: &Address, amount: &U256) -> Result<Vec<Call>>;
+
+    /// Build a transaction borrowing the amount of the token and running the multicall with it
+    async fn flash_loan(
+        &self,
+        token: &Address,
+        amount: &U256,
+        multicall: &Multicall,
+        account: &Address,
+        estimate_gas: bool,
+        miner_payment: MinerPayment,
+    ) -> Option<TransactionParameters>;
+}
+
+/// Encode the multicall as the abi encoded uint256[] the executor callbacks read
+pub(crate) fn encode_program(multicall: &Multicall) -> Vec<u8> {
+    ethabi::encode(&[Token::Array(
+        multicall
+            .encode_parameters()
+            .into_iter()
+            .map(Token::Uint)
+            .collect(),
+    )])
+}
+
+async fn balance_of(eth: &Eth<WebSocket>, token: &Address, holder: &Address) -> U256 {
+    let contract =
+        match Contract::from_json(eth.clone(), *token, include_bytes!("abis/IERC20.json")) {
+            Ok(contract) => contract,
+            Err(_) => return constants::ZERO_U256,
+        };
+    contract
+        .query::<U256, _, _, _>(
+            "balanceOf",
+            *holder,
+            None,
+            Options::default(),
+            BlockId::from(BlockNumber::Latest),
+        )
+        .await
+        .unwrap_or_default()
+}
+
+/// Call transfer or approve on a token
+fn erc20_call(
+    eth: &Eth<WebSocket>,
+    token: &Address,
+    func: &str,
+    recipient: &Address,
+    amount: U256,
+) -> Result<Call> {
+    let contract = Contract::from_json(eth.clone(), *token, include_bytes!("abis/IERC20.json"))?;
+    Call::from_contract(&contract, func, (*recipient, amount), evm::Type::Call, None)
+}
+
+/// Flash loans from the Aave V2 lending pool
+///
+/// The pool calls executeOperation, and takes back the loan and premium with transferFrom.
+#[derive(Clone, Debug)]
+pub struct Aave {
+    lending_pool: Contract<WebSocket>,
+    eth: Eth<WebSocket>,
+    bundle_executor: Address,
+    // The aToken holding the liquidity of each underlying
+    a_tokens: HashMap<Address, Address>,
+}
+
+impl Aave {
+    pub fn new(transport: &Web3<WebSocket>) -> Aave {
+        let lending_pool = Contract::from_json(
+            transport.eth(),
+            address_book::AAVE_LENDING_POOL.parse().unwrap(),
+            include_bytes!("abis/AaveLendingPool.json"),
+        )
+        .unwrap();
+        let a_tokens = address_book::AAVE_A_TOKENS
+            .iter()
+            .map(|(token, a_token)| (token.parse().unwrap(), a_token.parse().unwrap()))
+            .collect();
+        Aave {
+            lending_pool,
+            eth: transport.eth(),
+            bundle_executor: address_book::MulticallEXECUTOR.parse().unwrap(),
+            a_tokens,
+        }
+    }
+}
+
+#[async_trait]
+impl FlashLoanProvider for Aave {
+    fn name(&self) -> &'static str {
+        "Aave"
+    }
+
+    fn lends(&self, token: &Address) -> bool {
+        self.a_tokens.contains_key(token)
+    }
+
+    async fn available(&self, token: &Address) -> U256 {
+        match self.a_tokens.get(token) {
+            Some(a_token) => balance_of(&self.eth, token, a_token).await,
+            None => constants::ZERO_U256,
+        }
+    }
+
+    fn fee(&self, _token: &Address, amount: &U256) -> U256 {
+        // The premium is rounded half up
+        (amount * U256::from(AAVE_FEE_BPS) + U256::from(5000)) / U256::from(10000)
+    }
+
+    fn callback_selector(&self) -> [u8; 4] {
+        AAVE_CALLBACK
+    }
+
+    fn repay(&self, token: &Address, amount: &U256) -> Result<Vec<Call>> {
+        let owed = amount + self.fee(token, amount);
+        Ok(vec![erc20_call(
+            &self.eth,
+            token,
+            "approve",
+            &self.lending_pool.address(),
+            owed,
+        )?])
+    }
+
+    async fn flash_loan(
+        &self,
+        token: &Address,
+        amount: &U256,
+        multicall: &Multicall,
+        account: &Address,
+        estimate_gas: bool,
+        miner_payment: MinerPayment,
+    ) -> Option<TransactionParameters> {
+        // Mode 0 repays the loan in the same transaction rather than opening debt
+        let params = (
+            self.bundle_executor,
+            vec![*token],
+            vec![*amount],
+            vec![constants::ZERO_U256],
+            self.bundle_executor,
+            encode_program(multicall),
+            0_u16,
+        );
+        utilities::generate_contract_transaction(
+            &self.lending_pool,
+            "flashLoan",
+            params,
+            account,
+            estimate_gas,
+            miner_payment,
+        )
+        .await
+    }
+}
+
+/// Flash loans from the Balancer vault
+///
+/// The vault calls receiveFlashLoan, which must transfer back the loan and fee. The fee is set by
+/// governance and has been zero since launch.
+#[derive(Clone, Debug)]
+pub struct Balancer {
+    vault: Contract<WebSocket>,
+    eth: Eth<WebSocket>,
+    bundle_executor: Address,
+}
+
+impl Balancer {
+    pub fn new(transport: &Web3<WebSocket>) -> Balancer {
+        let vault = Contract::from_json(
+            transport.eth(),
+            address_book::BALANCER_VAULT.parse().unwrap(),
+            include_bytes!("abis/BalancerVault.json"),
+        )
+        .unwrap();
+        Balancer {
+            vault,
+            eth: transport.eth(),
+            bundle_executor: address_book::MulticallEXECUTOR.parse().unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl FlashLoanProvider for Balancer {
+    fn name(&self) -> &'static str {
+        "Balancer"
+    }
+
+    // The vault lends any token it holds
+    fn lends(&self, _token: &Address) -> bool {
+        true
+    }
+
+    async fn available(&self, token: &Address) -> U256 {
+        balance_of(&self.eth, token, &self.vault.address()).await
+    }
+
+    fn fee(&self, _token: &Address, _amount: &U256) -> U256 {
+        constants::ZERO_U256
+    }
+
+    fn callback_selector(&self) -> [u8; 4] {
+        BALANCER_CALLBACK
+    }
+
+    fn repay(&self, token: &Address, amount: &U256) -> Result<Vec<Call>> {
+        let owed = amount + self.fee(token, amount);
+        Ok(vec![erc20_call(
+            &self.eth,
+            token,
+            "transfer",
+            &self.vault.address(),
+            owed,
+        )?])
+    }
+
+    async fn flash_loan(
+        &self,
+        token: &Address,
+        amount: &U256,
+        multicall: &Multicall,
+        account: &Address,
+        estimate_gas: bool,
+        miner_payment: MinerPayment,
+    ) -> Option<TransactionParameters> {
+        let params = (
+            self.bundle_executor,
+            vec![*token],
+            vec![*amount],
+            encode_program(multicall),
+        );
+        utilities::generate_contract_transaction(
+            &self.vault,
+            "flashLoan",
+            params,
+            account,
+            estimate_gas,
+            miner_payment,
+        )
+        .await
+    }
+}
+
+/// Flash loans from dYdX
+///
+/// dYdX has no flash loan function, so the executor runs an operation which withdraws the loan,
+/// calls back into callFunction, then deposits the loan and fee back with transferFrom.
+#[derive(Clone, Debug)]
+pub struct DyDx {
+    solo_margin: Contract<WebSocket>,
+    bundle_executor_contract: Contract<WebSocket>,
+    eth: Eth<WebSocket>,
+    // The market id of each token
+    markets: HashMap<Address, u64>,
+}
+
+impl DyDx {
+    pub fn new(transport: &Web3<WebSocket>) -> DyDx {
+        let solo_margin = Contract::from_json(
+            transport.eth(),
+            address_book::DYDX_SOLO_MARGIN.parse().unwrap(),
+            include_bytes!("abis/SoloMargin.json"),
+        )
+        .unwrap();
+        let bundle_executor_contract = Contract::from_json(
+            transport.eth(),
+            address_book::MulticallEXECUTOR.parse().unwrap(),
+            include_bytes!("abis/Multicall.json"),
+        )
+        .unwrap();
+        let markets = address_book::DYDX_MARKETS
+            .iter()
+            .map(|(token, market)| (token.parse().unwrap(), *market))
+            .collect();
+        DyDx {
+            solo_margin,
+            bundle_executor_contract,
+            eth: transport.eth(),
+            markets,
+        }
+    }
+
+    /// An action on the executor's account, in wei as a delta of its balance
+    fn action(
+        &self,
+        action_type: u64,
+        market: u64,
+        deposit: bool,
+        amount: U256,
+        data: Vec<u8>,
+    ) -> Token {
+        Token::Tuple(vec![
+            Token::Uint(U256::from(action_type)),
+            // The first, and only, account
+            Token::Uint(constants::ZERO_U256),
+            Token::Tuple(vec![
+                Token::Bool(deposit),
+                Token::Uint(constants::ZERO_U256),
+                Token::Uint(constants::ZERO_U256),
+                Token::Uint(amount),
+            ]),
+            Token::Uint(U256::from(market)),
+            Token::Uint(constants::ZERO_U256),
+            Token::Address(self.bundle_executor_contract.address()),
+            Token::Uint(constants::ZERO_U256),
+            Token::Bytes(data),
+        ])
+    }
+}
+
+#[async_trait]
+impl FlashLoanProvider for DyDx {
+    fn name(&self) -> &'static str {
+        "dYdX"
+    }
+
+    fn lends(&self, token: &Address) -> bool {
+        self.markets.contains_key(token)
+    }
+
+    async fn available(&self, token: &Address) -> U256 {
+        if !self.lends(token) {
+            return constants::ZERO_U256;
+        }
+        balance_of(&self.eth, token, &self.solo_margin.address()).await
+    }
+
+    fn fee(&self, _token: &Address, _amount: &U256) -> U256 {
+        U256::from(DYDX_FEE)
+    }
+
+    fn callback_selector(&self) -> [u8; 4] {
+        DYDX_CALLBACK
+    }
+
+    fn repay(&self, token: &Address, amount: &U256) -> Result<Vec<Call>> {
+        let owed = amount + self.fee(token, amount);
+        Ok(vec![erc20_call(
+            &self.eth,
+            token,
+            "approve",
+            &self.solo_margin.address(),
+            owed,
+        )?])
+    }
+
+    async fn flash_loan(
+        &self,
+        token: &Address,
+        amount: &U256,
+        multicall: &Multicall,
+        account: &Address,
+        estimate_gas: bool,
+        miner_payment: MinerPayment,
+    ) -> Option<TransactionParameters> {
+        let market = *self.markets.get(token)?;
+        let executor = self.bundle_executor_contract.address();
+        let accounts = Token::Array(vec![Token::Tuple(vec![
+            Token::Address(executor),
+            Token::Uint(constants::ONE_U256),
+        ])]);
+        let actions = Token::Array(vec![
+            // Withdraw
+            self.action(1, market, false, *amount, vec![]),
+            // Call
+            self.action(
+                8,
+                market,
+                false,
+                constants::ZERO_U256,
+                encode_program(multicall),
+            ),
+            // Deposit
+            self.action(0, market, true, amount + self.fee(token, amount), vec![]),
+        ]);
+        let raw_call = self
+            .solo_margin
+            .abi()
+            .function("operate")
+            .ok()?
+            .encode_input(&[accounts, actions])
+            .ok()?;
+        // The executor reads at most 255 words of input for a call
+        if (raw_call.len() - 4) / 32 > 0xff {
+            return None;
+        }
+        let operate = Call::new(
+            self.solo_margin.address(),
+            raw_call[0..4].to_vec(),
+            evm::Type::Call,
+            None,
+            raw_call[4..].to_vec(),
+        );
+        // The miner is paid by the multicall in the callback
+        let mch = MulticallHeader::new(false, false, 0, 0);
+        let params = Multicall::new(mch, vec![operate]).encode_parameters();
+        utilities::generate_contract_transaction(
+            &self.bundle_executor_contract,
+            "ostium",
+            params,
+            account,
+            estimate_gas,
+            miner_payment,
+        )
+        .await
+    }
+}
+
+/// The offer with enough liquidity for the amount and the lowest fee, preferring earlier offers
+fn cheapest(offers: &[(U256, U256)], amount: &U256) -> Option<usize> {
+    offers
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, liquidity))| liquidity >= amount)
+        .min_by_key(|(_, (fee, _))| *fee)
+        .map(|(idx, _)| idx)
+}
+
+/// The flash loan providers available to the engines
+#[derive(Clone, Debug)]
+pub struct FlashLoanProviders {
+    providers: Vec<Arc<dyn FlashLoanProvider>>,
+}
+
+impl FlashLoanProviders {
+    pub fn new(transport: &Web3<WebSocket>) -> FlashLoanProviders {
+        FlashLoanProviders {
+            providers: vec![
+                Arc::new(ApeBank::new(transport)),
+                Arc::new(Aave::new(transport)),
+                Arc::new(Balancer::new(transport)),
+                Arc::new(DyDx::new(transport)),
+            ],
+        }
+    }
+
+    /// The amount of the token each provider can lend, in provider order
+    pub async fn liquidity(&self, token: &Address) -> Vec<U256> {
+        futures::future::join_all(self.providers.iter().map(|provider| async move {
+            if provider.lends(token) {
+                provider.available(token).await
+            } else {
+                constants::ZERO_U256
+            }
+        }))
+        .await
+    }
+
+    /// Choose the cheapest provider with the liquidity to lend the amount of the token
+    pub fn choose(
+        &self,
+        token: &Address,
+        amount: &U256,
+        liquidity: &[U256],
+    ) -> Option<&dyn FlashLoanProvider> {
+        let offers: Vec<(U256, U256)> = self
+            .providers
+            .iter()
+            .zip(liquidity)
+            .map(|(provider, liquidity)| (provider.fee(token, amount), *liquidity))
+            .collect();
+        cheapest(&offers, amount).map(|idx| self.providers[idx].as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choose_cheapest_with_liquidity() {
+        let amount = U256::from(1000);
+        let offers = [
+            (U256::from(1), U256::from(999)),
+            (U256::from(9), U256::from(5000)),
+            (U256::from(0), U256::from(5000)),
+            (U256::from(0), U256::from(1000)),
+        ];
+        // The first of the free offers with enough liquidity
+        assert_eq!(cheapest(&offers, &amount), Some(2));
+        assert_eq!(cheapest(&offers[..2], &amount), Some(1));
+        assert_eq!(cheapest(&offers[..1], &amount), None);
+    }
+
+    #[test]
+    fn callbacks_match_executor_abi() {
+        let executor = ethabi::Contract::load(&include_bytes!("abis/Multicall.json")[..]).unwrap();
+        for (name, selector) in [
+            ("largeApeCallback", crate::ape_bank::LARGE_APE_CALLBACK),
+            ("executeOperation", AAVE_CALLBACK),
+            ("receiveFlashLoan", BALANCER_CALLBACK),
+            ("callFunction", DYDX_CALLBACK),
+        ] {
+            let function = executor.function(name).unwrap();
+            assert_eq!(utilities::selector(function), selector, "{}", name);
+        }
+    }
+}
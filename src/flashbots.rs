@@ -8,12 +8,13 @@ use surf::http::mime::JSON;
 use tiny_keccak::Hasher;
 use web3::signing::{Key, SecretKeyRef};
 use web3::transports::WebSocket;
-use web3::types::{Address, BlockNumber, H160, U256, U64};
+use web3::types::{Address, BlockNumber, Log, H160, H256, U256, U64};
 use web3::Web3;
 
+use crate::bidding::Bid;
 use crate::gas::GasPrice;
 use crate::markets::MarketGraph;
-use crate::{constants, utilities, wallet};
+use crate::{constants, gas, utilities, wallet};
 
 // TODO(Consider a signing thread to reduce overhead on each signature)
 
@@ -72,15 +73,39 @@ pub struct Bundle {
     pub bundle_hash: Option<H160>,
     pub transactions: Vec<utilities::Transaction>,
     pub block: U64,
+    // Predicted base fee of the target block
+    pub base_fee: U256,
+    // The executor the bundle's transactions are sent from
+    pub executor: Address,
 }
 
 impl Bundle {
+    /// The miner payment bids behind the transactions of the bundle
+    pub fn bids(&self) -> Vec<&Bid> {
+        self.transactions
+            .iter()
+            .filter_map(|transaction| transaction.bid.as_ref())
+            .collect()
+    }
+
+    /// The profit kept, less the base fee burned by our own transactions
     pub fn taken_profit(&self) -> U256 {
         let mut taken_profit = constants::ZERO_U256;
         for transaction in &self.transactions {
             taken_profit += transaction.taken_profit;
         }
-        taken_profit
+        taken_profit.saturating_sub(self.burned_fee())
+    }
+
+    /// The base fee burned by our own transactions
+    pub fn burned_fee(&self) -> U256 {
+        let mut burned_fee = constants::ZERO_U256;
+        for transaction in &self.transactions {
+            if !transaction.external {
+                burned_fee += transaction.estimated_gas * self.base_fee;
+            }
+        }
+        burned_fee
     }
 
     /// Return the bundle score
@@ -89,21 +114,19 @@ impl Bundle {
         self.effective_gas()
     }
 
+    /// The coinbase transfers and priority fees paid to the miner, the base fee is burned
     pub fn miner_payment(&self) -> U256 {
         let mut delta_coinbase = constants::ZERO_U256;
         let mut gas_payment = constants::ZERO_U256;
         for transaction in &self.transactions {
             delta_coinbase += transaction.delta_coinbase;
-            let gas_paid = match transaction.parameters.gas_price {
-                Some(gas) => gas,
-                None => constants::ZERO_U256,
-            };
-            gas_payment += transaction.estimated_gas * gas_paid;
+            let priority_fee = gas::priority_fee(&transaction.parameters, self.base_fee);
+            gas_payment += transaction.estimated_gas * priority_fee;
         }
         delta_coinbase + gas_payment
     }
 
-    /// Return the effective gas price for the bundle
+    /// Return the effective gas price paid to the miner for the bundle
     pub fn effective_gas(&self) -> U256 {
         let mut gas_used_estimate = constants::ZERO_U256;
         for transaction in &self.transactions {
@@ -112,7 +135,27 @@ impl Bundle {
         self.miner_payment() / gas_used_estimate
     }
 
+    /// Replace the gas estimates and coinbase transfers of the transactions with simulated ones
+    ///
+    /// So the score and effective gas price of the bundle reflect what it would actually pay.
+    pub fn apply_simulation(&mut self, simulation: &Simulation) {
+        for transaction in self.transactions.iter_mut() {
+            let hash = match &transaction.signed {
+                Some(signed) => signed.transaction_hash,
+                None => continue,
+            };
+            if let Some(result) = simulation.results.iter().find(|result| result.hash == hash) {
+                transaction.estimated_gas = result.gas_used;
+                transaction.delta_coinbase = result.eth_sent_to_coinbase;
+            }
+        }
+    }
+
     /// Call a simulation or send to the flashbots relay
+    ///
+    /// Transactions are numbered from start_nonce, or the executor's nonce on chain without one,
+    /// and only signed again when their parameters have changed.
+    #[allow(clippy::too_many_arguments)]
     pub async fn submit(
         &mut self,
         web3_transport: &Web3<WebSocket>,
@@ -121,11 +164,11 @@ impl Bundle {
         flashbots_signer: &wallet::LocalWallet,
         client: &surf::Client,
         relay: &str,
+        start_nonce: Option<U256>,
     ) -> anyhow::Result<String> {
         // TODO(Consider ethers library with this as a provider)
-        // TODO(Sign transaction only if it isn't already signed)
         executor
-            .sign_transactions(web3_transport, &mut self.transactions, None)
+            .sign_transactions(web3_transport, &mut self.transactions, start_nonce)
             .await;
         let mut raw_transactions = vec![];
         for tx in &self.transactions {
@@ -170,9 +213,14 @@ impl Bundle {
         req.set_header("X-Flashbots-Signature", &fb_req_sig_header);
         req.set_content_type(JSON);
         req.set_body(fb_req);
-        let res = client.send(req);
-        let mut res = res.await.unwrap();
-        let body = res.body_string().await.unwrap();
+        let mut res = client
+            .send(req)
+            .await
+            .map_err(|error| anyhow!("Request to {} failed: {}", relay, error))?;
+        let body = res
+            .body_string()
+            .await
+            .map_err(|error| anyhow!("Failed to read response from {}: {}", relay, error))?;
         if res.status() != 200 {
             match operation_mode {
                 OperationMode::Simulate => warn!("{}", body),
@@ -185,6 +233,95 @@ impl Bundle {
     }
 }
 
+/// The outcome of one transaction of an eth_callBundle simulation
+#[derive(Debug, Clone)]
+pub struct SimulatedTransaction {
+    pub hash: H256,
+    pub gas_used: U256,
+    // Priority fees and transfers to the coinbase
+    pub coinbase_diff: U256,
+    // Transfers to the coinbase alone
+    pub eth_sent_to_coinbase: U256,
+    // The error or revert reason, if it failed
+    pub error: Option<String>,
+    // Only returned by some relays
+    pub logs: Vec<Log>,
+}
+
+/// A parsed eth_callBundle response
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub results: Vec<SimulatedTransaction>,
+    pub total_gas_used: U256,
+    pub coinbase_diff: U256,
+}
+
+impl Simulation {
+    /// Parse an eth_callBundle response body
+    pub fn parse(body: &str) -> anyhow::Result<Simulation> {
+        let response: serde_json::Value = serde_json::from_str(body)?;
+        if !response["error"].is_null() {
+            return Err(anyhow!("Simulation failed: {}", response["error"]));
+        }
+        let result = &response["result"];
+        // Quantities come back as decimal strings, and gas as numbers
+        let quantity = |value: &serde_json::Value| -> anyhow::Result<U256> {
+            match value {
+                serde_json::Value::String(value) => {
+                    U256::from_dec_str(value).map_err(|_| anyhow!("Invalid quantity {}", value))
+                }
+                serde_json::Value::Number(value) => value
+                    .as_u64()
+                    .map(U256::from)
+                    .ok_or_else(|| anyhow!("Invalid quantity {}", value)),
+                serde_json::Value::Null => Ok(constants::ZERO_U256),
+                _ => Err(anyhow!("Invalid quantity {}", value)),
+            }
+        };
+        let mut results = vec![];
+        for transaction in result["results"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Simulation has no results"))?
+        {
+            let error = [&transaction["error"], &transaction["revert"]]
+                .iter()
+                .find(|error| !error.is_null())
+                .map(|error| match error.as_str() {
+                    Some(error) => error.to_string(),
+                    None => error.to_string(),
+                });
+            results.push(SimulatedTransaction {
+                hash: serde_json::from_value(transaction["txHash"].clone())?,
+                gas_used: quantity(&transaction["gasUsed"])?,
+                coinbase_diff: quantity(&transaction["coinbaseDiff"])?,
+                eth_sent_to_coinbase: quantity(&transaction["ethSentToCoinbase"])?,
+                error,
+                logs: serde_json::from_value(transaction["logs"].clone()).unwrap_or_default(),
+            });
+        }
+        Ok(Simulation {
+            results,
+            total_gas_used: quantity(&result["totalGasUsed"])?,
+            coinbase_diff: quantity(&result["coinbaseDiff"])?,
+        })
+    }
+
+    /// The first failed transaction, if any
+    pub fn reverted(&self) -> Option<&SimulatedTransaction> {
+        self.results
+            .iter()
+            .find(|transaction| transaction.error.is_some())
+    }
+
+    /// The effective gas price paid to the miner, per simulated gas
+    pub fn effective_gas(&self) -> U256 {
+        if self.total_gas_used.is_zero() {
+            return constants::ZERO_U256;
+        }
+        self.coinbase_diff / self.total_gas_used
+    }
+}
+
 // TODO(Bundle generator trait)
 #[async_trait]
 pub trait BundleGenerator {
@@ -197,3 +334,34 @@ pub trait BundleGenerator {
         block_number: &U64,
     ) -> Option<Bundle>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_simulation() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":{
+            "bundleHash":"0x2ca9c4d2ba00d8144d8e396a4989374443cb20fb490d800f4f883ad4e1b32158",
+            "coinbaseDiff":"2100000000000000","totalGasUsed":42000,"results":[
+            {"txHash":"0x0000000000000000000000000000000000000000000000000000000000000001",
+             "gasUsed":21000,"coinbaseDiff":"2000000000000000",
+             "ethSentToCoinbase":"1000000000000000"},
+            {"txHash":"0x0000000000000000000000000000000000000000000000000000000000000002",
+             "gasUsed":21000,"coinbaseDiff":"100000000000000","ethSentToCoinbase":"0",
+             "revert":"Mined in wrong block."}]}}"#;
+        let simulation = Simulation::parse(body).unwrap();
+        assert_eq!(simulation.results.len(), 2);
+        assert_eq!(simulation.results[0].gas_used, U256::from(21000));
+        assert_eq!(
+            simulation.results[0].eth_sent_to_coinbase,
+            U256::from(1_000_000_000_000_000_u64)
+        );
+        assert_eq!(simulation.effective_gas(), U256::from(50_000_000_000_u64));
+        let reverted = simulation.reverted().unwrap();
+        assert_eq!(reverted.hash, H256::from_low_u64_be(2));
+        assert_eq!(reverted.error.as_deref(), Some("Mined in wrong block."));
+        let error = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"nonce too low"}}"#;
+        assert!(Simulation::parse(error).is_err());
+    }
+}
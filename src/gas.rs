@@ -1,64 +1,395 @@
+use std::collections::VecDeque;
+
 use log::debug;
 
 use web3::transports::WebSocket;
-use web3::types::U256;
+use web3::types::{Block, Transaction, TransactionParameters, U256, U64};
 use web3::Web3;
 
+use crate::constants;
+use crate::mempool::Mempool;
+
+/// Pending transactions which fit in the next block, which gas pricing is drawn from
+// TODO(Make this update to the largest in the last 1000 blocks dynamically)
+const NEXT_BLOCK_TRANSACTIONS: usize = 250;
+
+/// EIP-1559 bound on the base fee change between blocks, as a denominator
+const BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+
+/// EIP-1559 ratio of the gas limit to the gas target
+const ELASTICITY_MULTIPLIER: u64 = 2;
+
+/// Predict the base fee of the next block from the base fee and gas usage of a block
+pub fn next_base_fee(base_fee: U256, gas_used: U256, gas_limit: U256) -> U256 {
+    let gas_target = gas_limit / ELASTICITY_MULTIPLIER;
+    if gas_target.is_zero() || gas_used == gas_target {
+        return base_fee;
+    }
+    if gas_used > gas_target {
+        let delta =
+            base_fee * (gas_used - gas_target) / gas_target / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee + std::cmp::max(delta, U256::one())
+    } else {
+        let delta =
+            base_fee * (gas_target - gas_used) / gas_target / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee.saturating_sub(delta)
+    }
+}
+
+/// How transactions pay the block builder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaymentMode {
+    // Through the priority fee of the transaction
+    PriorityFee,
+    // Through a coinbase transfer from the executor, with no priority fee
+    CoinbaseTransfer,
+}
+
+/// An amount paid to the block builder, and how it is paid
+#[derive(Debug, Clone, Copy)]
+pub struct MinerPayment {
+    pub amount: U256,
+    pub mode: PaymentMode,
+    // Predicted base fee of the target block
+    pub base_fee: U256,
+}
+
+impl MinerPayment {
+    /// The amount the executor should transfer to the coinbase
+    pub fn coinbase_transfer(&self) -> U256 {
+        match self.mode {
+            PaymentMode::CoinbaseTransfer => self.amount,
+            PaymentMode::PriorityFee => U256::zero(),
+        }
+    }
+
+    /// Max fee and max priority fee per gas for a transaction expected to use gas_used
+    ///
+    /// The max fee allows for the base fee rising a block more than predicted.
+    pub fn fees(&self, gas_used: U256) -> (U256, U256) {
+        let priority_fee = match self.mode {
+            PaymentMode::PriorityFee if !gas_used.is_zero() => self.amount / gas_used,
+            _ => U256::zero(),
+        };
+        let max_base_fee =
+            self.base_fee + self.base_fee / BASE_FEE_MAX_CHANGE_DENOMINATOR + U256::one();
+        (max_base_fee + priority_fee, priority_fee)
+    }
+}
+
+/// Blocks of gas statistics kept by the oracle
+const ORACLE_BLOCKS: usize = 100;
+
+/// Gas statistics of an included block
+#[derive(Debug, Clone)]
+struct BlockGasStats {
+    number: u64,
+    base_fee: U256,
+    gas_used: U256,
+    gas_limit: U256,
+    // Priority fees paid by the transactions of the block, sorted ascending
+    priority_fees: Vec<U256>,
+    // Priority fees of the transactions which look like bundles, sorted ascending
+    bundle_fees: Vec<U256>,
+}
+
+/// The value at percentile of ascending values
+fn percentile(values: &[U256], percentile: usize) -> Option<U256> {
+    if values.is_empty() {
+        return None;
+    }
+    let index = std::cmp::min(values.len() * percentile / 100, values.len() - 1);
+    Some(values[index])
+}
+
+/// Rolling gas statistics over recent blocks
+///
+/// Miners order the public mempool by priority fee, so transactions paying less than one after
+/// them in the block were placed there by a bundle. Their coinbase transfers aren't visible, so
+/// bundle fees understate what bundles really pay.
+#[derive(Debug, Default)]
+pub struct GasOracle {
+    blocks: VecDeque<BlockGasStats>,
+}
+
+impl GasOracle {
+    pub fn new() -> GasOracle {
+        GasOracle::default()
+    }
+
+    /// Record an included block
+    pub fn observe(&mut self, block: &Block<Transaction>) {
+        let base_fee = block.base_fee_per_gas.unwrap_or_default();
+        let priority_fees = block
+            .transactions
+            .iter()
+            .map(|transaction| {
+                transaction
+                    .gas_price
+                    .unwrap_or_default()
+                    .saturating_sub(base_fee)
+            })
+            .collect();
+        self.record(
+            block.number.unwrap_or_default().as_u64(),
+            base_fee,
+            block.gas_used,
+            block.gas_limit,
+            priority_fees,
+        );
+    }
+
+    /// Record the base fee, gas usage and priority fees in order of a block
+    fn record(
+        &mut self,
+        number: u64,
+        base_fee: U256,
+        gas_used: U256,
+        gas_limit: U256,
+        priority_fees: Vec<U256>,
+    ) {
+        // Reorgs replace the blocks after the common ancestor
+        while matches!(self.blocks.back(), Some(last) if last.number >= number) {
+            self.blocks.pop_back();
+        }
+        // A fee lower than the highest after it is out of mempool order
+        let mut bundle_fees = vec![];
+        let mut highest_after = U256::zero();
+        for fee in priority_fees.iter().rev() {
+            if *fee < highest_after {
+                bundle_fees.push(*fee);
+            }
+            highest_after = std::cmp::max(highest_after, *fee);
+        }
+        let mut priority_fees = priority_fees;
+        priority_fees.sort();
+        bundle_fees.sort();
+        self.blocks.push_back(BlockGasStats {
+            number,
+            base_fee,
+            gas_used,
+            gas_limit,
+            priority_fees,
+            bundle_fees,
+        });
+        while self.blocks.len() > ORACLE_BLOCKS {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Predict the base fee of the block after the latest observed
+    pub fn next_base_fee(&self) -> Option<U256> {
+        self.blocks
+            .back()
+            .map(|last| next_base_fee(last.base_fee, last.gas_used, last.gas_limit))
+    }
+
+    /// The median over recent blocks of the base fee
+    pub fn base_fee(&self) -> Option<U256> {
+        let mut base_fees: Vec<U256> = self.blocks.iter().map(|block| block.base_fee).collect();
+        base_fees.sort();
+        percentile(&base_fees, 50)
+    }
+
+    /// The median over recent blocks of the percentile of priority fees paid in each
+    pub fn priority_fee(&self, percentile: usize) -> Option<U256> {
+        self.median(|block| self::percentile(&block.priority_fees, percentile))
+    }
+
+    /// The median over recent blocks of the percentile of priority fees paid by bundles in each
+    pub fn bundle_fee(&self, percentile: usize) -> Option<U256> {
+        self.median(|block| self::percentile(&block.bundle_fees, percentile))
+    }
+
+    fn median(&self, statistic: impl Fn(&BlockGasStats) -> Option<U256>) -> Option<U256> {
+        let mut values: Vec<U256> = self.blocks.iter().filter_map(statistic).collect();
+        values.sort();
+        percentile(&values, 50)
+    }
+}
+
+/// Gas pricing for the next block
+///
+/// The percentiles are priority fees, paid to the miner over the predicted base fee.
 #[derive(Debug, Clone, Copy)]
 pub struct GasPrice {
     pub ludicrous: U256,
     pub high: U256,
     pub medium: U256,
     pub low: U256,
+    // Predicted base fee of the next block
+    pub base_fee: U256,
+    pub payment_mode: PaymentMode,
 }
 
 impl GasPrice {
-    async fn get_prices(transport: &Web3<WebSocket>) -> (U256, U256, U256, U256) {
-        // TODO(This should just get a reference to the txpool)
-        let block_info = transport.txpool().content().await;
-        debug!("Building gas pricing from txpool");
-        let mut gas_prices = vec![];
-        match block_info {
-            Err(_err) => (),
-            Ok(info) => {
-                let pending_txpool = info.pending;
-                // TODO (This is a naive algo O(n^2), there must be a better way)
-                for address in &pending_txpool {
-                    for tx in address.1 {
-                        gas_prices.push(tx.1.gas_price)
-                    }
-                }
-            }
+    async fn get_prices(
+        transport: &Web3<WebSocket>,
+        oracle: &GasOracle,
+        mempool: &Mempool,
+        base_fee: U256,
+    ) -> (U256, U256, U256, U256) {
+        debug!(
+            "Building gas pricing from {} pending transactions",
+            mempool.len()
+        );
+        if let Some(high) = mempool.nth_highest_fee(0) {
+            // Only the transactions which would fit in the next block matter
+            let ranked = std::cmp::min(mempool.len(), NEXT_BLOCK_TRANSACTIONS);
+            let medium = mempool.nth_highest_fee(ranked / 2).unwrap();
+            let low = mempool.nth_highest_fee(ranked - 1).unwrap();
+            let ludicrous = high * 3_u64;
+            return (ludicrous, high, medium, low);
         }
-        if gas_prices.is_empty() {
-            // Fallback pricing for no txpool.
-            let estimated = transport.eth().gas_price().await.unwrap();
-            return (
-                (estimated + estimated),
-                (&estimated + 4),
-                (&estimated + 2),
-                estimated,
-            );
-        } else {
-            gas_prices.sort();
-            gas_prices.reverse();
-            // TODO(Make this update to the largest in the last 1000 blocks dynamically)
-            gas_prices.truncate(250);
-            let high = gas_prices.first().unwrap().to_owned();
-            let medium = gas_prices.get(gas_prices.len() / 2).unwrap().to_owned();
-            let low = gas_prices.last().unwrap().to_owned();
-            let ludicrous = &high * 3_u64;
-            (ludicrous, high, medium, low)
+        // Fallback to recent blocks for an empty mempool.
+        if let (Some(low), Some(medium), Some(high)) = (
+            oracle.priority_fee(25),
+            oracle.priority_fee(50),
+            oracle.priority_fee(90),
+        ) {
+            return (high * 3_u64, high, medium, low);
         }
+        // Fallback pricing for no mempool or history.
+        let estimated = transport
+            .eth()
+            .gas_price()
+            .await
+            .unwrap()
+            .saturating_sub(base_fee);
+        (
+            (estimated + estimated),
+            (&estimated + 4),
+            (&estimated + 2),
+            estimated,
+        )
     }
 
-    pub async fn new(transport: &Web3<WebSocket>) -> GasPrice {
-        let (ludicrous, high, medium, low) = GasPrice::get_prices(transport).await;
+    pub async fn new(
+        transport: &Web3<WebSocket>,
+        oracle: &GasOracle,
+        mempool: &Mempool,
+        payment_mode: PaymentMode,
+    ) -> GasPrice {
+        let base_fee = oracle.next_base_fee().unwrap_or_default();
+        let (ludicrous, high, medium, low) =
+            GasPrice::get_prices(transport, oracle, mempool, base_fee).await;
         GasPrice {
             ludicrous,
             high,
             medium,
             low,
+            base_fee,
+            payment_mode,
+        }
+    }
+
+    /// Pay amount to the miner of the next block
+    pub fn payment(&self, amount: U256) -> MinerPayment {
+        MinerPayment {
+            amount,
+            mode: self.payment_mode,
+            base_fee: self.base_fee,
+        }
+    }
+}
+
+/// The priority fee per gas a transaction pays the miner at a base fee
+pub fn priority_fee(parameters: &TransactionParameters, base_fee: U256) -> U256 {
+    match parameters.transaction_type {
+        Some(transaction_type)
+            if transaction_type == U64::from(constants::EIP1559_TRANSACTION_TYPE) =>
+        {
+            std::cmp::min(
+                parameters.max_priority_fee_per_gas.unwrap_or_default(),
+                parameters
+                    .max_fee_per_gas
+                    .unwrap_or_default()
+                    .saturating_sub(base_fee),
+            )
         }
+        _ => parameters
+            .gas_price
+            .unwrap_or_default()
+            .saturating_sub(base_fee),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_fee_follows_gas_target() {
+        let base_fee = U256::from(100_000_000_000_u64);
+        let gas_limit = U256::from(30_000_000);
+        // At target
+        assert_eq!(
+            next_base_fee(base_fee, U256::from(15_000_000), gas_limit),
+            base_fee
+        );
+        // Full blocks raise it by an eighth, empty ones lower it by an eighth
+        assert_eq!(
+            next_base_fee(base_fee, gas_limit, gas_limit),
+            U256::from(112_500_000_000_u64)
+        );
+        assert_eq!(
+            next_base_fee(base_fee, U256::zero(), gas_limit),
+            U256::from(87_500_000_000_u64)
+        );
+        // Always rises by at least one wei over target
+        assert_eq!(
+            next_base_fee(U256::from(7), U256::from(15_000_001), gas_limit),
+            U256::from(8)
+        );
+    }
+
+    #[test]
+    fn oracle_statistics() {
+        let mut oracle = GasOracle::new();
+        assert_eq!(oracle.next_base_fee(), None);
+        let gas_limit = U256::from(30_000_000);
+        let fees = |fees: &[u64]| fees.iter().map(|fee| U256::from(*fee)).collect();
+        // The 1 and 2 ahead of higher fees were placed by bundles
+        oracle.record(
+            1,
+            U256::from(100),
+            gas_limit,
+            gas_limit,
+            fees(&[1, 2, 9, 5, 3]),
+        );
+        oracle.record(
+            2,
+            U256::from(100),
+            gas_limit,
+            gas_limit,
+            fees(&[0, 8, 6, 4]),
+        );
+        assert_eq!(oracle.next_base_fee(), Some(U256::from(112)));
+        assert_eq!(oracle.priority_fee(0), Some(U256::from(1)));
+        assert_eq!(oracle.priority_fee(100), Some(U256::from(9)));
+        assert_eq!(oracle.bundle_fee(100), Some(U256::from(2)));
+        // A reorg replaces the block
+        oracle.record(2, U256::from(80), U256::zero(), gas_limit, fees(&[]));
+        assert_eq!(oracle.next_base_fee(), Some(U256::from(70)));
+        assert_eq!(oracle.bundle_fee(0), Some(U256::from(1)));
+    }
+
+    #[test]
+    fn payment_modes() {
+        let base_fee = U256::from(80);
+        let gas_used = U256::from(100);
+        let priority = MinerPayment {
+            amount: U256::from(1000),
+            mode: PaymentMode::PriorityFee,
+            base_fee,
+        };
+        assert_eq!(priority.coinbase_transfer(), U256::zero());
+        assert_eq!(priority.fees(gas_used), (U256::from(101), U256::from(10)));
+        let transfer = MinerPayment {
+            mode: PaymentMode::CoinbaseTransfer,
+            ..priority
+        };
+        assert_eq!(transfer.coinbase_transfer(), U256::from(1000));
+        assert_eq!(transfer.fees(gas_used), (U256::from(91), U256::zero()));
     }
 }
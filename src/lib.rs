@@ -1,48 +1,99 @@
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use log::{debug, error, info, warn};
 use tokio::join;
 use web3::api::SubscriptionStream;
 use web3::futures::StreamExt;
 use web3::transports::WebSocket;
-use web3::types::{Block, BlockHeader, BlockId, BlockNumber, FilterBuilder, Log, SyncState, H256};
+use web3::types::{
+    Address, Block, BlockHeader, BlockId, BlockNumber, FilterBuilder, Log, SyncState, Transaction,
+    H256, U256,
+};
 use web3::Web3;
 
+use crate::bidding::BiddingStrategy;
 use crate::flashbots::{Bundle, BundleGenerator, OperationMode};
+use crate::gas::PaymentMode;
 use crate::markets::MarketGraph;
 use crate::wallet::LocalWallet;
 
 mod address_book;
+mod alpha_homora;
+mod ape_bank;
 mod arbitrage;
+mod backrun;
+mod bidding;
 mod compound;
 mod constants;
+mod ensure_reward;
+mod erc4626;
 mod evm;
+mod executors;
+mod failures;
+mod flash_loans;
 mod flashbots;
 mod gas;
+mod lido;
 mod markets;
+mod mempool;
+mod nonces;
+mod overlay;
+mod pga;
+mod skim;
 mod sushiswap;
+mod token_checker;
+mod tokens;
 mod uniswap;
+mod uniswap_router;
 mod utilities;
 mod wallet;
 mod weth_token;
-
-// TODO(It would be ideal to dynamically update blocklists for addresses causing reverts)
+mod yearn;
 
 pub struct Config {
-    pub executor_pk: String,
+    // The first executor is the primary, used for calls which don't send anything
+    pub executor_pks: Vec<String>,
+    pub executor_selection: String,
     pub flashbots_pk: String,
     pub ws_rpc: String,
     pub operation_mode: OperationMode,
     pub simulation_relay: String,
+    pub ensure_reward: Option<String>,
+    pub token_registry: String,
+    pub failure_log: String,
+    // Miner payment bidding strategy of each engine
+    pub bidding: Vec<(String, String)>,
+    pub payment_mode: PaymentMode,
+    // Send bundles the relays would discard to the public mempool instead
+    pub pga_fallback: bool,
+}
+
+/// The private keys of the executors, the owner's first
+fn executor_keys(private_key: &str, executor_keys: Option<&str>) -> Vec<String> {
+    let mut keys = vec![private_key.trim_start_matches("0x").to_string()];
+    if let Some(executor_keys) = executor_keys {
+        for key in executor_keys.split(',') {
+            keys.push(key.trim().trim_start_matches("0x").to_string());
+        }
+    }
+    keys
 }
 
 impl Config {
     pub fn new() -> Result<Config> {
         let ws_rpc = env::var("WEB_SOCKET").context("Set the WEB_SOCKET environment variable.")?;
-        let executor_pk = env::var("PRIVATE_KEY")
-            .context("Set the PRIVATE_KEY environment variable.")?[2..]
-            .to_string();
+        let private_key =
+            env::var("PRIVATE_KEY").context("Set the PRIVATE_KEY environment variable.")?;
+        // More executors to spread bundles over, such as EXECUTOR_KEYS=0x...,0x...
+        let executor_pks = executor_keys(&private_key, env::var("EXECUTOR_KEYS").ok().as_deref());
+        // How executors are chosen, such as EXECUTOR_SELECTION=engine:backrun=0
+        let executor_selection =
+            env::var("EXECUTOR_SELECTION").unwrap_or_else(|_| "round-robin".to_string());
         let flashbots_pk = env::var("FLASHBOTS_KEY")
             .context("Set the FLASHBOTS_KEY environment variable.")?[2..]
             .to_string();
@@ -56,48 +107,107 @@ impl Config {
                 OperationMode::Simulate
             }
         };
+        // Optional address of the EnsureReward contract for EOA reward bundles
+        let ensure_reward = env::var("ENSURE_REWARD").ok();
+        // Where token metadata is persisted between runs
+        let token_registry =
+            env::var("TOKEN_REGISTRY").unwrap_or_else(|_| "tokens.json".to_string());
+        // Where failure counts for markets and tokens are persisted between runs
+        let failure_log = env::var("FAILURE_LOG").unwrap_or_else(|_| "failures.json".to_string());
+        // Bidding strategies, such as BIDDING_ARBITRAGE=floor:competitive:90
+        let bidding = bidding::DEFAULT_STRATEGIES
+            .iter()
+            .map(|(engine, default)| {
+                let spec = env::var(format!("BIDDING_{}", engine.to_uppercase()))
+                    .unwrap_or_else(|_| default.to_string());
+                (engine.to_string(), spec)
+            })
+            .collect();
+        // Pay the miner through priority fees rather than coinbase transfers
+        let payment_mode = match env::var("MINER_PAYMENT").as_deref() {
+            Ok("priority") => PaymentMode::PriorityFee,
+            Ok("coinbase") | Err(_) => PaymentMode::CoinbaseTransfer,
+            Ok(mode) => return Err(anyhow!("Unknown MINER_PAYMENT mode {}.", mode)),
+        };
+        let pga_fallback = env::var("PGA_FALLBACK").is_ok();
         Ok(Config {
-            executor_pk,
+            executor_pks,
+            executor_selection,
             flashbots_pk,
             ws_rpc,
             operation_mode,
             simulation_relay,
+            ensure_reward,
+            token_registry,
+            failure_log,
+            bidding,
+            payment_mode,
+            pga_fallback,
         })
     }
 }
 
+/// Most bundle simulations in flight at once
+const MAX_CONCURRENT_SIMULATIONS: usize = 8;
+
+/// Seconds from the start of a search by which its bundles must be simulated
+const SIMULATION_DEADLINE_SECONDS: u64 = 9;
+
+/// Seconds between blocks, after which a bundle for the next block is too late
+const SLOT_SECONDS: u64 = 12;
+
+/// Percentile of recent priority fees a bundle must pay to be worth submitting
+const INCLUSION_PERCENTILE: usize = 10;
+
 // Info we care about for each block
 #[derive(Clone, Debug)]
 struct BlockInfo {
-    pub block: Option<Block<H256>>,
+    pub block: Option<Block<Transaction>>,
     pub logs: Option<Vec<Log>>,
     pub sync: SyncState,
     pub gas_price: gas::GasPrice,
 }
 
 impl BlockInfo {
-    pub async fn new(rpc: &Web3<WebSocket>) -> Result<BlockInfo> {
+    pub async fn new(
+        rpc: &Web3<WebSocket>,
+        gas_oracle: &mut gas::GasOracle,
+        mempool: &mut mempool::Mempool,
+        payment_mode: PaymentMode,
+    ) -> Result<BlockInfo> {
         // Really this should just return early and empty when syncing
 
-        let mut block: Option<Block<H256>> = None;
+        let mut block: Option<Block<Transaction>> = None;
         let mut logs: Option<Vec<Log>> = None;
-        let (block_w, logs_w, sync, gas_price) = join![
-            rpc.eth().block(BlockId::from(BlockNumber::Latest)),
+        let (block_w, logs_w, sync) = join![
+            rpc.eth().block_with_txs(BlockId::from(BlockNumber::Latest)),
             rpc.eth().logs(
                 FilterBuilder::default()
                     .from_block(BlockNumber::Latest)
                     .to_block(BlockNumber::Latest)
                     .build()
             ),
-            rpc.eth().syncing(),
-            // TODO(Should this even be loaded in the sync state?)
-            gas::GasPrice::new(&rpc)
+            rpc.eth().syncing()
         ];
         let sync = sync?;
         if let SyncState::NotSyncing = sync {
             logs = Some(logs_w?);
             block = block_w?;
         }
+        if let Some(block) = &block {
+            gas_oracle.observe(block);
+            mempool.mined(
+                block.number.unwrap_or_default().as_u64(),
+                gas_oracle.next_base_fee().unwrap_or_default(),
+                &block.transactions,
+            );
+            // Seed from the node when there's nothing tracked yet
+            if mempool.is_empty() {
+                mempool.load(rpc).await;
+            }
+        }
+        // TODO(Should this even be loaded in the sync state?)
+        let gas_price = gas::GasPrice::new(&rpc, gas_oracle, mempool, payment_mode).await;
         Ok(BlockInfo {
             block,
             logs,
@@ -108,18 +218,35 @@ impl BlockInfo {
 }
 
 struct RunData {
-    pub executor: LocalWallet,
+    pub executors: executors::ExecutorPool,
     pub flashbots_signer: LocalWallet,
     pub rpc: Web3<WebSocket>,
     pub http_client: surf::Client,
     pub operation_mode: OperationMode,
     pub simulation_relay: String,
+    pub ensure_reward: Option<Address>,
+    pub token_registry: PathBuf,
+    pub failure_log: PathBuf,
+    pub bidding: HashMap<String, Arc<dyn BiddingStrategy>>,
+    pub payment_mode: PaymentMode,
+    pub gas_oracle: gas::GasOracle,
+    pub mempool: mempool::Mempool,
+    pub nonces: nonces::NonceManager,
+    pub pga: Option<pga::PgaSubmitter>,
 }
 
 impl RunData {
     pub async fn new(config: &Config) -> Result<RunData> {
-        let executor = LocalWallet::new(&config.executor_pk)
-            .context("Failed to parse ethereum private key.")?;
+        let mut wallets = vec![];
+        for executor_pk in config.executor_pks.iter() {
+            wallets.push(
+                LocalWallet::new(executor_pk).context("Failed to parse ethereum private key.")?,
+            );
+        }
+        let selection = executors::parse_selection(&config.executor_selection)
+            .context("Failed to parse executor selection.")?;
+        let executors = executors::ExecutorPool::new(wallets, selection)
+            .context("Failed to set up executors.")?;
         let flashbots_signer = LocalWallet::new(&config.flashbots_pk)
             .context("Failed to parse flashbots bundle signing key.")?;
         let rpc: Web3<WebSocket> = web3::Web3::new(
@@ -127,15 +254,46 @@ impl RunData {
                 .await
                 .context("Failed to connect to ethereum RPC websocket.")?,
         );
+        executors
+            .check_authorized(&rpc)
+            .await
+            .context("Failed to check executors.")?;
         let http_client: surf::Client = surf::Client::new();
+        let ensure_reward = match &config.ensure_reward {
+            Some(address) => Some(
+                address
+                    .parse()
+                    .context("Failed to parse EnsureReward contract address.")?,
+            ),
+            None => None,
+        };
+        let mut bidding = HashMap::new();
+        for (engine, spec) in config.bidding.iter() {
+            let strategy = bidding::parse(spec)
+                .with_context(|| format!("Failed to parse bidding strategy for {}.", engine))?;
+            bidding.insert(engine.to_string(), strategy);
+        }
         // TODO(Enable and configure these based on a config file)
         Ok(RunData {
-            executor,
+            executors,
             flashbots_signer,
             rpc,
             http_client,
             operation_mode: config.operation_mode,
             simulation_relay: config.simulation_relay.to_string(),
+            ensure_reward,
+            token_registry: PathBuf::from(&config.token_registry),
+            failure_log: PathBuf::from(&config.failure_log),
+            bidding,
+            payment_mode: config.payment_mode,
+            gas_oracle: gas::GasOracle::new(),
+            mempool: mempool::Mempool::new(),
+            nonces: nonces::NonceManager::new(),
+            pga: if config.pga_fallback {
+                Some(pga::PgaSubmitter::new())
+            } else {
+                None
+            },
         })
     }
 }
@@ -143,30 +301,50 @@ impl RunData {
 // TODO(Break down this function further)
 async fn search(
     markets: &MarketGraph,
-    bundle_generators: &mut Vec<Box<dyn BundleGenerator>>,
+    bundle_generators: &mut Vec<(&str, Box<dyn BundleGenerator>)>,
     run_data: &mut RunData,
     block_info: &BlockInfo,
 ) -> Result<()> {
+    let started = unix_time();
     let block_number = block_info.block.as_ref().unwrap().number.unwrap();
     info!(
         "Searching for opportunities in block #{}.",
         block_number + 1
     );
+    // Each engine searches with its own executor, so their bundles don't share nonces
+    let engines: Vec<&str> = bundle_generators
+        .iter()
+        .map(|(engine, _)| *engine)
+        .collect();
+    let executors = run_data.executors.assign(&engines);
     let mut bundle_futures = vec![];
     let mut bundles: Vec<Bundle> = vec![];
     // TODO(Collect a vector of futures here)
-    for bundle_generator in bundle_generators.iter_mut() {
+    for ((_, bundle_generator), executor) in bundle_generators.iter_mut().zip(executors.iter()) {
         bundle_futures.push(bundle_generator.generate(
             &markets,
             &run_data.rpc,
-            &run_data.executor.public_key,
+            &executor.public_key,
             &block_info.gas_price,
             &block_number,
         ));
     }
     // TODO(Fix this)
     let bundle_options = futures::future::join_all(bundle_futures).await;
+    // Relays throw away bundles paying less than recent blocks usually include
+    let inclusion_floor = run_data
+        .gas_oracle
+        .priority_fee(INCLUSION_PERCENTILE)
+        .unwrap_or_default();
     for bundle in bundle_options.into_iter().flatten() {
+        if bundle.effective_gas() < inclusion_floor {
+            debug!(
+                "Dropping bundle paying {} gwei, under the {} gwei inclusion floor.",
+                utilities::to_gwei(&bundle.effective_gas()),
+                utilities::to_gwei(&inclusion_floor)
+            );
+            continue;
+        }
         bundles.push(bundle)
     }
     if bundles.is_empty() {
@@ -174,57 +352,232 @@ async fn search(
             "No opportunities discovered in block #{}.",
             &block_number + 1
         );
-    } else {
-        // TODO(Consider that bundles with an effective score below gas_pricing.medium are often discarded)
-        // The relay will just throw them away, they should be funneled to PGA
-        // TODO(Handle multiple bundles, mainly around correcting nonce values)
-        let mut best_bundle: Bundle = bundles[0].clone();
-        let mut best_score = constants::ZERO_U256;
-        // TODO(Process simulations async, against local mev-geth)
-        for mut bundle in bundles {
-            // TODO(Drop bundle on error)
-            // TODO(Update gas usage from simulation for effective scoring)
-            let simulation = bundle
-                .submit(
-                    &run_data.rpc,
-                    &flashbots::OperationMode::Simulate,
-                    &run_data.executor,
-                    &run_data.flashbots_signer,
-                    &run_data.http_client,
-                    &run_data.simulation_relay,
-                )
-                .await;
-            match simulation {
-                Ok(_) => (),
-                Err(_) => continue,
+        return Ok(());
+    }
+    submit_bundles(markets, bundles, run_data, block_info, started).await
+}
+
+/// Apply a bundle's simulation, or drop it and blame the routes of a reverted transaction
+///
+/// Relay, transport and parse errors say nothing about the bundle's markets, so they drop the
+/// bundle without recording any failures.
+fn simulated_bundle(
+    markets: &MarketGraph,
+    mut bundle: Bundle,
+    simulation: Result<String>,
+) -> Option<Bundle> {
+    let simulation = match simulation.and_then(|body| flashbots::Simulation::parse(&body)) {
+        Ok(simulation) => simulation,
+        Err(error) => {
+            warn!("Bundle simulation failed: {:?}", error);
+            return None;
+        }
+    };
+    if let Some(reverted) = simulation.reverted() {
+        debug!(
+            "Bundle transaction {} reverted: {}.",
+            reverted.hash,
+            reverted.error.as_deref().unwrap_or_default()
+        );
+        // Only what the reverted transaction routes through is to blame
+        for transaction in bundle.transactions.iter() {
+            let hash = transaction
+                .signed
+                .as_ref()
+                .map(|signed| signed.transaction_hash);
+            if hash == Some(reverted.hash) {
+                markets
+                    .failures
+                    .record(&transaction.route, bundle.block.as_u64());
             }
-            // I.E. if error, continue (logging error)
-            let score = bundle.score();
-            if score > best_score {
-                best_score = score;
-                best_bundle = bundle;
+        }
+        return None;
+    }
+    for result in simulation.results.iter() {
+        debug!(
+            "Simulated {} using {} gas, paying Ξ{} to the miner with {} logs.",
+            result.hash,
+            result.gas_used,
+            utilities::to_ether(&result.coinbase_diff),
+            result.logs.len()
+        );
+    }
+    // Score on what the bundle actually pays, rather than the estimates
+    let estimated = bundle.effective_gas();
+    bundle.apply_simulation(&simulation);
+    debug!(
+        "Simulated effective gas price of {} gwei, against {} gwei estimated.",
+        utilities::to_gwei(&simulation.effective_gas()),
+        utilities::to_gwei(&estimated)
+    );
+    Some(bundle)
+}
+
+/// The time since the unix epoch
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// How long is left to simulate the bundles of a search started at started
+///
+/// Each search gets the same time to simulate, wherever it starts in the slot, but none can
+/// run past the end of the slot of the block it builds on.
+fn simulation_deadline(started: Duration, timestamp: Option<u64>, now: Duration) -> Duration {
+    let deadline = started + Duration::from_secs(SIMULATION_DEADLINE_SECONDS);
+    let deadline = match timestamp {
+        Some(timestamp) => std::cmp::min(deadline, Duration::from_secs(timestamp + SLOT_SECONDS)),
+        None => deadline,
+    };
+    deadline.saturating_sub(now)
+}
+
+/// The best scoring of the simulated bundles finished within the deadline
+///
+/// Bundles which failed simulation come through as None, and bundles not yet simulated by the
+/// deadline are abandoned.
+async fn best_simulated<T>(
+    simulations: impl futures::Stream<Item = Option<T>>,
+    deadline: Duration,
+    candidates: usize,
+    score: impl Fn(&T) -> U256,
+) -> Option<T> {
+    let mut best: Option<T> = None;
+    let mut best_score = constants::ZERO_U256;
+    let deadline = tokio::time::sleep(deadline);
+    tokio::pin!(deadline);
+    tokio::pin!(simulations);
+    let mut simulated = 0;
+    loop {
+        let simulation = tokio::select! {
+            simulation = simulations.next() => match simulation {
+                Some(simulation) => simulation,
+                None => break,
+            },
+            _ = &mut deadline => {
+                warn!(
+                    "Simulation deadline reached with {} of {} bundles simulated.",
+                    simulated, candidates
+                );
+                break;
             }
+        };
+        simulated += 1;
+        let candidate = match simulation {
+            Some(candidate) => candidate,
+            None => continue,
+        };
+        let candidate_score = score(&candidate);
+        if candidate_score > best_score {
+            best_score = candidate_score;
+            best = Some(candidate);
+        }
+    }
+    best
+}
+
+/// Simulate the bundles concurrently, and send the best scoring bundle to the relays
+///
+/// Whatever has been simulated by the search's deadline is chosen from, so a slow simulation
+/// can't hold up submission.
+async fn submit_bundles(
+    markets: &MarketGraph,
+    bundles: Vec<Bundle>,
+    run_data: &mut RunData,
+    block_info: &BlockInfo,
+    started: Duration,
+) -> Result<()> {
+    {
+        let block = block_info.block.as_ref();
+        let deadline = simulation_deadline(
+            started,
+            block.map(|block| block.timestamp.as_u64()),
+            unix_time(),
+        );
+        if deadline.is_zero() {
+            warn!(
+                "Simulation deadline for block #{} passed before simulating {} bundles.",
+                block.and_then(|block| block.number).unwrap_or_default() + 1,
+                bundles.len()
+            );
+            return Ok(());
+        }
+        let candidates = bundles.len();
+        let simulating: &RunData = run_data;
+        let simulations = futures::stream::iter(bundles)
+            .map(|mut bundle| async move {
+                let (executor, start_nonce) = bundle_executor(simulating, &bundle);
+                let simulation = bundle
+                    .submit(
+                        &simulating.rpc,
+                        &flashbots::OperationMode::Simulate,
+                        &executor,
+                        &simulating.flashbots_signer,
+                        &simulating.http_client,
+                        &simulating.simulation_relay,
+                        start_nonce,
+                    )
+                    .await;
+                (bundle, simulation)
+            })
+            .buffer_unordered(MAX_CONCURRENT_SIMULATIONS)
+            .map(|(bundle, simulation)| simulated_bundle(markets, bundle, simulation));
+        let best = best_simulated(simulations, deadline, candidates, Bundle::score).await;
+        let mut best_bundle = match best {
+            Some(best_bundle) => best_bundle,
+            None => return Ok(()),
+        };
+        for bid in best_bundle.bids() {
+            info!(
+                "Bidding Ξ{} to the miner: {}.",
+                utilities::to_ether(&bid.payment),
+                bid.rationale
+            );
         }
         // TODO(Replace with display)
         match run_data.operation_mode {
             OperationMode::Simulate => (),
             OperationMode::Send => {
+                let (executor, start_nonce) = bundle_executor(run_data, &best_bundle);
+                // The relays throw away bundles paying under the medium priority fee
+                if best_bundle.effective_gas() < block_info.gas_price.medium
+                    && pga::PgaSubmitter::eligible(&best_bundle)
+                {
+                    if let Some(pga) = &mut run_data.pga {
+                        if let Err(error) = pga
+                            .submit(
+                                &run_data.rpc,
+                                &executor,
+                                &mut run_data.nonces,
+                                &best_bundle,
+                                &block_info.gas_price,
+                            )
+                            .await
+                        {
+                            error!("Public mempool submission failed: {:?}", error);
+                        }
+                        return Ok(());
+                    }
+                }
                 let mut fb_bun = best_bundle.clone();
                 let fb_fut = fb_bun.submit(
                     &run_data.rpc,
                     &flashbots::OperationMode::Send,
-                    &run_data.executor,
+                    &executor,
                     &run_data.flashbots_signer,
                     &run_data.http_client,
                     "https://relay.flashbots.net/",
+                    start_nonce,
                 );
                 let em_fut = best_bundle.submit(
                     &run_data.rpc,
                     &flashbots::OperationMode::Send,
-                    &run_data.executor,
+                    &executor,
                     &run_data.flashbots_signer,
                     &run_data.http_client,
                     "https://mev-relay.ethermine.org/",
+                    start_nonce,
                 );
                 let (em, fb) = join!(em_fut, fb_fut);
                 match fb {
@@ -265,25 +618,182 @@ async fn search(
     Ok(())
 }
 
+/// The wallet a bundle is sent from, and the nonce its transactions start at
+///
+/// Alternative bundles for a block from the same executor share nonces, after any of its
+/// transactions in the public mempool.
+fn bundle_executor(run_data: &RunData, bundle: &Bundle) -> (LocalWallet, Option<U256>) {
+    let executor = match run_data.executors.wallet(&bundle.executor) {
+        Some(executor) => *executor,
+        None => *run_data.executors.primary(),
+    };
+    (executor, run_data.nonces.next(&executor.public_key))
+}
+
+/// Search for and submit a backrun of a pending transaction
+async fn backrun_pending(
+    markets: &MarketGraph,
+    backrun_engine: &backrun::BackrunEngine,
+    run_data: &mut RunData,
+    block_info: &BlockInfo,
+    transaction: &Transaction,
+) -> Result<()> {
+    match transaction.to {
+        Some(to) if backrun_engine.watches(&to) => (),
+        _ => return Ok(()),
+    }
+    let started = unix_time();
+    let block_number = block_info.block.as_ref().unwrap().number.unwrap();
+    let executor = run_data.executors.select("backrun");
+    let bundle = backrun_engine
+        .backrun(
+            markets,
+            &run_data.rpc,
+            &executor.public_key,
+            &block_info.gas_price,
+            &block_number,
+            transaction,
+        )
+        .await;
+    match bundle {
+        Some(bundle) => submit_bundles(markets, vec![bundle], run_data, block_info, started).await,
+        None => Ok(()),
+    }
+}
+
+/// Outbid any gas auctions lost to a pending transaction and try to backrun it
+async fn handle_pending(
+    markets: &MarketGraph,
+    backrun_engine: &backrun::BackrunEngine,
+    run_data: &mut RunData,
+    block_info: Option<&BlockInfo>,
+    transaction: &Transaction,
+) {
+    if let (Some(pga), Some(pending)) = (&mut run_data.pga, run_data.mempool.get(&transaction.hash))
+    {
+        pga.outbid(
+            &run_data.rpc,
+            transaction,
+            pending.priority_fee,
+            run_data.gas_oracle.next_base_fee().unwrap_or_default(),
+        )
+        .await;
+    }
+    if let Some(block_info) = block_info {
+        // One pending transaction failing is no reason to stop watching the rest
+        if let Err(error) =
+            backrun_pending(markets, backrun_engine, run_data, block_info, transaction).await
+        {
+            warn!("Failed to backrun {}: {:?}", transaction.hash, error);
+        }
+    }
+}
+
 async fn loop_blocks(run_data: &mut RunData) -> Result<()> {
     debug!("Setting up market graph.");
-    let mut market_graph = MarketGraph::new(&run_data.rpc).await;
-    let mut bundle_generators: Vec<Box<dyn BundleGenerator>> = vec![];
-    bundle_generators.push(Box::new(
-        arbitrage::CrossedMarketArbitrageEngine::new(&run_data.rpc).await,
+    let mut market_graph = MarketGraph::new(
+        &run_data.rpc,
+        &run_data.token_registry,
+        &run_data.failure_log,
+    )
+    .await;
+    let mut bundle_generators: Vec<(&str, Box<dyn BundleGenerator>)> = vec![];
+    bundle_generators.push((
+        "arbitrage",
+        Box::new(
+            arbitrage::CrossedMarketArbitrageEngine::new(
+                &run_data.rpc,
+                run_data.bidding["arbitrage"].clone(),
+            )
+            .await,
+        ),
+    ));
+    bundle_generators.push((
+        "alpha_homora",
+        Box::new(
+            alpha_homora::GoblinReinvestEngine::new(
+                &run_data.rpc,
+                run_data.ensure_reward,
+                run_data.bidding["alpha_homora"].clone(),
+            )
+            .await,
+        ),
+    ));
+    bundle_generators.push((
+        "skim",
+        Box::new(skim::SkimEngine::new(
+            &run_data.rpc,
+            run_data.bidding["skim"].clone(),
+        )),
     ));
+    match compound::CompoundLiquidationEngine::new(
+        &run_data.rpc,
+        run_data.bidding["compound"].clone(),
+    )
+    .await
+    {
+        Ok(engine) => bundle_generators.push(("compound", Box::new(engine))),
+        Err(error) => warn!("Failed to set up Compound liquidations: {:?}", error),
+    }
+    let backrun_engine = backrun::BackrunEngine::new(
+        &run_data.rpc,
+        &market_graph,
+        run_data.bidding["backrun"].clone(),
+    )
+    .await;
+    let mut token_checker = token_checker::TokenChecker::new();
     let mut block_subscription: SubscriptionStream<WebSocket, BlockHeader> =
         run_data.rpc.eth_subscribe().subscribe_new_heads().await?;
+    let mut pending_transactions = mempool::watch(&run_data.rpc).await?;
     info!("Waiting for first block header from Ethereum client RPC.");
     let mut full_update = true;
-    'blocks: while block_subscription.next().await.is_some() {
+    // The last block searched, against which pending transactions are backrun
+    let mut last_block_info: Option<BlockInfo> = None;
+    'blocks: loop {
+        let header = tokio::select! {
+            // A new head always goes ahead of the pending transactions queued behind it
+            biased;
+            header = block_subscription.next() => header,
+            Some(transaction) = pending_transactions.next() => {
+                let transaction_hash = transaction.hash;
+                if run_data.mempool.get(&transaction_hash).is_some() {
+                    continue 'blocks;
+                }
+                run_data.mempool.insert(transaction.clone());
+                // Whatever is left of the handling is dropped as soon as a new head arrives
+                let handling = handle_pending(
+                    &market_graph,
+                    &backrun_engine,
+                    run_data,
+                    last_block_info.as_ref(),
+                    &transaction,
+                );
+                tokio::select! {
+                    biased;
+                    header = block_subscription.next() => {
+                        debug!("Head arrived while handling {}, dropping it.", transaction_hash);
+                        header
+                    }
+                    _ = handling => continue 'blocks,
+                }
+            }
+        };
+        if header.is_none() {
+            break 'blocks;
+        }
         // TODO(Track last block and trigger full update if there is a discontinuity)
         // It seems like the syncing state is not dependable
         // Let's make sure we are at the chainhead
-        let block_info = BlockInfo::new(&run_data.rpc).await?;
+        let block_info = BlockInfo::new(
+            &run_data.rpc,
+            &mut run_data.gas_oracle,
+            &mut run_data.mempool,
+            run_data.payment_mode,
+        )
+        .await?;
         match block_info.sync {
             SyncState::NotSyncing => {
-                let block = block_info.clone().block.unwrap();
+                let block = block_info.block.as_ref().unwrap();
                 info!(
                     "Finalized block #{}, Mining Started Timestamp: {}.",
                     block.number.unwrap(),
@@ -300,6 +810,43 @@ async fn loop_blocks(run_data: &mut RunData) -> Result<()> {
                 continue 'blocks;
             }
         }
+        let block_number = block_info.block.as_ref().unwrap().number.unwrap();
+        market_graph.failures.advance(block_number.as_u64());
+        for (address, banned_until) in market_graph.failures.banned() {
+            debug!("{} is banned until block #{}.", address, banned_until);
+        }
+        record_winning_bids(&market_graph, &block_info);
+        run_data
+            .executors
+            .update(&run_data.rpc, &mut run_data.nonces, block_number.as_u64())
+            .await;
+        if let Some(pga) = &mut run_data.pga {
+            pga.update(
+                &run_data.rpc,
+                &run_data.nonces,
+                block_number.as_u64(),
+                block_info.gas_price.base_fee,
+            )
+            .await;
+        }
+        if let OperationMode::Send = run_data.operation_mode {
+            run_data
+                .executors
+                .top_up(
+                    &run_data.rpc,
+                    &mut run_data.nonces,
+                    &block_info.gas_price,
+                    block_number.as_u64(),
+                )
+                .await;
+        }
+        let gas_oracle = &run_data.gas_oracle;
+        debug!(
+            "Recent median base fee {} gwei, priority fee {} gwei and bundle priority fee {} gwei.",
+            utilities::to_gwei(&gas_oracle.base_fee().unwrap_or_default()),
+            utilities::to_gwei(&gas_oracle.priority_fee(50).unwrap_or_default()),
+            utilities::to_gwei(&gas_oracle.bundle_fee(50).unwrap_or_default())
+        );
         // Search for and submit and opportunities found within the block.
         if full_update {
             // Update all state data
@@ -311,15 +858,46 @@ async fn loop_blocks(run_data: &mut RunData) -> Result<()> {
                 .await;
         }
         search(&market_graph, &mut bundle_generators, run_data, &block_info).await?;
+        // Look for broken tokens a batch at a time, once the block's bundles are submitted
+        token_checker
+            .check_tokens(
+                &mut market_graph,
+                &run_data.rpc,
+                &run_data.executors.primary().public_key,
+            )
+            .await;
         // The next block will only need to update state deltas
         full_update = false;
+        last_block_info = Some(block_info);
     }
     Ok(())
 }
 
+/// Record the priority fees paid by transactions through the markets of the last block
+fn record_winning_bids(markets: &MarketGraph, block_info: &BlockInfo) {
+    let block = block_info.block.as_ref().unwrap();
+    // Only the priority fee over the burned base fee goes to the miner
+    let base_fee = block.base_fee_per_gas.unwrap_or_default();
+    let gas_prices: HashMap<H256, U256> = block
+        .transactions
+        .iter()
+        .map(|transaction| {
+            let gas_price = transaction.gas_price.unwrap_or_default();
+            (transaction.hash, gas_price.saturating_sub(base_fee))
+        })
+        .collect();
+    markets.record_bids(
+        block.number.unwrap().as_u64(),
+        block_info.logs.as_ref().unwrap(),
+        &gas_prices,
+    );
+}
+
 /// Display startup message
 fn print_startup(run_data: &RunData) {
-    info!("Searcher wallet address: {}.", run_data.executor.address());
+    for executor in run_data.executors.wallets() {
+        info!("Searcher wallet address: {}.", executor.address());
+    }
     info!(
         "Flashbots relay signing wallet address: {}.",
         run_data.flashbots_signer.address()
@@ -336,3 +914,94 @@ pub async fn run(config: &Config) -> Result<()> {
     // An "infinite" loop over incoming blocks.
     loop_blocks(&mut run_data).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_of_several_executors() {
+        let key = |byte: u8| format!("0x{}", hex::encode([byte; 32]));
+        let keys = executor_keys(
+            &key(1),
+            Some(&format!("{}, {}", key(2), key(3).trim_start_matches("0x"))),
+        );
+        assert_eq!(keys.len(), 3);
+        let wallets = keys
+            .iter()
+            .map(|key| LocalWallet::new(key).unwrap())
+            .collect();
+        let mut pool =
+            executors::ExecutorPool::new(wallets, executors::Selection::RoundRobin).unwrap();
+        // The owner stays first, and every engine of a block gets its own executor
+        assert_eq!(
+            pool.primary().address(),
+            LocalWallet::new(&keys[0]).unwrap().address()
+        );
+        let assigned = pool.assign(&["arbitrage", "skim", "compound"]);
+        assert_eq!(assigned.len(), 3);
+        assert!(assigned[0].public_key != assigned[1].public_key);
+        assert!(assigned[1].public_key != assigned[2].public_key);
+        assert!(assigned[0].public_key != assigned[2].public_key);
+        assert_eq!(executor_keys(&key(1), None).len(), 1);
+    }
+
+    #[test]
+    fn deadline_from_search_start() {
+        let seconds = Duration::from_secs;
+        // A search starting with the block gets the full time to simulate
+        assert_eq!(
+            simulation_deadline(seconds(1000), Some(1000), seconds(1001)),
+            seconds(SIMULATION_DEADLINE_SECONDS - 1)
+        );
+        // A backrun well into the slot is cut off at the end of the slot
+        assert_eq!(
+            simulation_deadline(seconds(1008), Some(1000), seconds(1008)),
+            seconds(SLOT_SECONDS - 8)
+        );
+        // And once the slot is over there is no time left
+        assert!(simulation_deadline(seconds(1013), Some(1000), seconds(1013)).is_zero());
+        assert_eq!(
+            simulation_deadline(seconds(1013), None, seconds(1013)),
+            seconds(SIMULATION_DEADLINE_SECONDS)
+        );
+    }
+
+    #[tokio::test]
+    async fn best_simulated_before_deadline() {
+        // Scores finishing after a delay in milliseconds, None for a failed simulation
+        let simulations = |simulations: Vec<(u64, Option<u64>)>| {
+            futures::stream::iter(simulations).then(|(delay, score)| async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                score
+            })
+        };
+        let score = |score: &u64| U256::from(*score);
+        let best = best_simulated(
+            simulations(vec![(1, Some(3)), (1, None), (1, Some(5)), (1, Some(4))]),
+            Duration::from_secs(5),
+            4,
+            score,
+        )
+        .await;
+        assert_eq!(best, Some(5));
+        // The best bundle is abandoned when its simulation finishes after the deadline
+        let best = best_simulated(
+            simulations(vec![(1, Some(3)), (1_000, Some(5))]),
+            Duration::from_millis(200),
+            2,
+            score,
+        )
+        .await;
+        assert_eq!(best, Some(3));
+        // Nothing scoring is nothing to send
+        let best = best_simulated(
+            simulations(vec![(1, None), (1, Some(0))]),
+            Duration::from_secs(5),
+            2,
+            score,
+        )
+        .await;
+        assert_eq!(best, None);
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use log::warn;
+use web3::contract::{Contract, Options};
+use web3::ethabi::ethereum_types::U256;
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockId, BlockNumber};
+use web3::Web3;
+
+use crate::evm;
+use crate::evm::Call;
+use crate::markets::{Market, Protocol, TokenPair};
+use crate::{address_book, constants, markets};
+
+/// stETH balances are shares of the pooled ether rounded down, so unwrapped or transferred
+/// stETH can arrive up to this many wei short
+const STETH_ROUNDING_WEI: u64 = 2;
+
+/// A market for steth <-> wsteth
+///
+/// The wrapper rate moves with every Lido oracle report, which rebases stETH balances while
+/// wstETH balances stay fixed.
+pub struct WstethStethMarket {
+    tokens: TokenPair,
+    bundle_executor: Address,
+    steth: Contract<WebSocket>,
+    wsteth: Contract<WebSocket>,
+    // stETH per wstETH, scaled by 10^18
+    steth_per_token: U256,
+}
+
+impl WstethStethMarket {
+    pub fn new(transport: &Web3<WebSocket>) -> WstethStethMarket {
+        let steth: Address = address_book::STETH_ADDRESS.parse().unwrap();
+        let wsteth: Address = address_book::WSTETH_ADDRESS.parse().unwrap();
+        let steth_contract =
+            Contract::from_json(transport.eth(), steth, include_bytes!("abis/IERC20.json"))
+                .unwrap();
+        let wsteth_contract = Contract::from_json(
+            transport.eth(),
+            wsteth,
+            include_bytes!("protocols/lido/abis/wsteth.json"),
+        )
+        .unwrap();
+        let bundle_executor: Address = address_book::MulticallEXECUTOR.parse().unwrap();
+        WstethStethMarket {
+            tokens: TokenPair {
+                i: steth,
+                j: wsteth,
+            },
+            bundle_executor,
+            steth: steth_contract,
+            wsteth: wsteth_contract,
+            steth_per_token: constants::ZERO_U256,
+        }
+    }
+
+    async fn update_exchange_rate(&mut self) {
+        let rate = self
+            .wsteth
+            .query::<U256, _, _, _>(
+                "stEthPerToken",
+                (),
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await;
+        match rate {
+            Ok(rate) => self.steth_per_token = rate,
+            Err(error) => warn!("Failed to update wstETH rate: {:?}", error),
+        }
+    }
+
+    /// wstETH for wrapping stETH
+    fn get_wrapped(steth: &U256, steth_per_token: &U256) -> U256 {
+        steth * constants::ETHER / steth_per_token
+    }
+
+    /// stETH for unwrapping wstETH, less what it can lose to rounding
+    fn get_unwrapped(wsteth: &U256, steth_per_token: &U256) -> U256 {
+        (wsteth * steth_per_token / constants::ETHER).saturating_sub(U256::from(STETH_ROUNDING_WEI))
+    }
+}
+
+#[async_trait]
+impl Market for WstethStethMarket {
+    fn tokens(&self) -> TokenPair {
+        self.tokens
+    }
+
+    fn market_address(&self) -> Address {
+        self.bundle_executor
+    }
+
+    fn failure_address(&self) -> Address {
+        self.wsteth.address()
+    }
+
+    fn delta_contracts(&self) -> Vec<Address> {
+        // Oracle reports rebase stETH, which emits from the stETH contract
+        vec![self.steth.address()]
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::Lido
+    }
+
+    fn reserves(&self) -> Option<(U256, U256)> {
+        None
+    }
+
+    fn with_swap(&self, _token_in: &Address, _amount_in: &U256) -> Option<Box<dyn Market>> {
+        None
+    }
+
+    fn get_tokens_out(&self, token_in: &Address, token_out: &Address, amount_in: &U256) -> U256 {
+        if self.steth_per_token.is_zero() {
+            return constants::ZERO_U256;
+        }
+        if token_in.0 == self.tokens.i.0 && token_out.0 == self.tokens.j.0 {
+            // Token in is stETH
+            WstethStethMarket::get_wrapped(amount_in, &self.steth_per_token)
+        } else if token_in.0 == self.tokens.j.0 && token_out.0 == self.tokens.i.0 {
+            // Token in is wstETH
+            WstethStethMarket::get_unwrapped(amount_in, &self.steth_per_token)
+        } else {
+            constants::ZERO_U256
+        }
+    }
+
+    fn get_tokens_in(&self, token_in: &Address, token_out: &Address, amount_out: &U256) -> U256 {
+        if self.steth_per_token.is_zero() {
+            return constants::ZERO_U256;
+        }
+        if token_in.0 == self.tokens.i.0 && token_out.0 == self.tokens.j.0 {
+            // Token in is stETH
+            amount_out * self.steth_per_token / constants::ETHER + constants::ONE_U256
+        } else if token_in.0 == self.tokens.j.0 && token_out.0 == self.tokens.i.0 {
+            // Token in is wstETH
+            (amount_out + STETH_ROUNDING_WEI) * constants::ETHER / self.steth_per_token
+                + constants::ONE_U256
+        } else {
+            constants::ZERO_U256
+        }
+    }
+
+    fn sell_tokens(
+        &self,
+        token_in: &Address,
+        amount_in: &U256,
+        recipient: &Address,
+    ) -> anyhow::Result<Vec<Call>> {
+        let mut calls = vec![];
+        let token_out = if token_in.0 == self.tokens.i.0 {
+            // stETH
+            // Approve and wrap steth -> wsteth
+            calls.push(Call::from_contract(
+                &self.steth,
+                "approve",
+                (self.wsteth.address(), *amount_in),
+                evm::Type::Call,
+                None,
+            )?);
+            calls.push(Call::from_contract(
+                &self.wsteth,
+                "wrap",
+                *amount_in,
+                evm::Type::Call,
+                None,
+            )?);
+            &self.wsteth
+        } else if token_in.0 == self.tokens.j.0 {
+            // wstETH
+            // Unwrap wsteth -> steth
+            calls.push(Call::from_contract(
+                &self.wsteth,
+                "unwrap",
+                *amount_in,
+                evm::Type::Call,
+                None,
+            )?);
+            &self.steth
+        } else {
+            return Err(Error::from(markets::TokenInputError::InvalidToken));
+        };
+        // Wrapping always returns to the executor, so forward if needed, with the stETH quote
+        // already short of what can be lost to rounding
+        if recipient.0 != self.bundle_executor.0 {
+            let amount_out = self.get_tokens_out(token_in, &token_out.address(), amount_in);
+            calls.push(Call::from_contract(
+                token_out,
+                "transfer",
+                (*recipient, amount_out),
+                evm::Type::Call,
+                None,
+            )?);
+        }
+        Ok(calls)
+    }
+
+    // Wrapping is paid for up front
+    fn flash_sell_tokens(
+        &self,
+        _token_in: &Address,
+        _amount_in: &U256,
+    ) -> anyhow::Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
+    async fn update(&mut self) {
+        self.update_exchange_rate().await
+    }
+
+    fn receive_directly(&self, _token_address: &Address) -> bool {
+        // Yes because the market is the executor
+        true
+    }
+
+    fn to_first_market(
+        &self,
+        _token_address: &Address,
+        _amount: &U256,
+    ) -> anyhow::Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
+    fn prepare_receive(&self, _token_address: &Address) -> anyhow::Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwrapping_leaves_room_for_steth_rounding() {
+        // 1.1 stETH per wstETH
+        let steth_per_token = constants::ETHER * 11 / 10;
+        let wsteth = WstethStethMarket::get_wrapped(&(constants::ETHER * 11), &steth_per_token);
+        assert_eq!(wsteth, constants::ETHER * 10);
+        // Round tripped, less the buffer
+        assert_eq!(
+            WstethStethMarket::get_unwrapped(&wsteth, &steth_per_token),
+            constants::ETHER * 11 - STETH_ROUNDING_WEI
+        );
+        // And dust unwraps to nothing rather than underflowing
+        assert_eq!(
+            WstethStethMarket::get_unwrapped(&U256::one(), &steth_per_token),
+            constants::ZERO_U256
+        );
+    }
+}
@@ -2,22 +2,29 @@ use std::collections::{HashMap, HashSet};
 /// Eth market traits and interfaces
 use std::fmt;
 use std::ops::{Deref, Not};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
 use async_trait::async_trait;
-use log::info;
+use log::{debug, info, warn};
 use petgraph::graphmap::UnGraphMap;
 use web3::transports::WebSocket;
-use web3::types::{Address, Log, U256};
+use web3::types::{Address, Log, H256, U256};
 use web3::Web3;
 
 use crate::address_book;
+use crate::bidding::BidHistory;
 use crate::compound;
+use crate::erc4626;
 use crate::evm::Call;
+use crate::failures::FailureTracker;
+use crate::lido;
+use crate::tokens::TokenRegistry;
 use crate::uniswap;
 use crate::uniswap::UniswapV2Pair;
 use crate::weth_token;
+use crate::yearn;
 use rayon::prelude::*;
 
 #[derive(Clone, Copy, Debug)]
@@ -26,6 +33,9 @@ pub enum Protocol {
     UniswapV2,
     ERC20,
     Compound,
+    Lido,
+    ERC4626,
+    Yearn,
 }
 
 #[derive(Debug)]
@@ -59,14 +69,24 @@ pub trait Market: Send + Sync {
     /// Return the address for a market
     fn market_address(&self) -> Address;
 
+    /// The address failures through the market are counted against
+    ///
+    /// Markets run by the executor share its address, so they're told apart by their contract.
+    fn failure_address(&self) -> Address {
+        self.market_address()
+    }
+
     // Contracts to monitor for delta updates
     fn delta_contracts(&self) -> Vec<Address>;
 
     /// Return the protocol for a market
     fn protocol(&self) -> Protocol;
 
-    /// Return the default miner payment for this market
-    fn miner_reward_percentage(&self) -> Option<U256>;
+    /// Return the stored reserves of tokens i and j, for markets which hold reserves
+    fn reserves(&self) -> Option<(U256, U256)>;
+
+    /// Return a copy of the market with a swap applied, for markets which hold reserves
+    fn with_swap(&self, token_in: &Address, amount_in: &U256) -> Option<Box<dyn Market>>;
 
     /// Get the tokens out for a given token amount in.
     fn get_tokens_out(&self, token_in: &Address, token_out: &Address, amount_in: &U256) -> U256;
@@ -82,6 +102,13 @@ pub trait Market: Send + Sync {
         recipient: &Address,
     ) -> Result<Vec<Call>>;
 
+    /// Generate calls to receive the tokens out before paying for them, for markets which
+    /// support flash swaps
+    ///
+    /// The tokens are sent to the executor, and every call after these runs before the market is
+    /// repaid amount_in of token_in.
+    fn flash_sell_tokens(&self, token_in: &Address, amount_in: &U256) -> Result<Option<Vec<Call>>>;
+
     /// Should update any info from the last state block which needs to be updated
     ///
     /// This is async because it operates on state data from the blockchain.
@@ -182,19 +209,39 @@ pub struct MarketGraph {
     pub graph: UnGraphMap<Address, TokenMarkets>,
     // All cycles by origin token
     pub cycles_by_token: HashMap<Address, Vec<Vec<Address>>>,
+    // Metadata for the tokens of the graph
+    pub tokens: TokenRegistry,
+    // Markets and tokens which keep failing
+    pub failures: FailureTracker,
+    // Recent winning priority fees through each market
+    pub bids: BidHistory,
 }
 
 impl MarketGraph {
-    pub async fn new(transport: &Web3<WebSocket>) -> MarketGraph {
+    pub async fn new(
+        transport: &Web3<WebSocket>,
+        token_registry: &Path,
+        failure_log: &Path,
+    ) -> MarketGraph {
         // Gather all markets
         info!("Gathering markets.");
 
+        // Tokens found to be broken in earlier runs are left out of the graph
+        let mut tokens = TokenRegistry::load(token_registry).unwrap_or_else(|error| {
+            warn!("Failed to load token registry: {:?}", error);
+            TokenRegistry::new()
+        });
+
         // Gather uniswap V2 markets
         let mut graph = UnGraphMap::<Address, TokenMarkets>::with_capacity(15000, 15000);
         let par_graph = Arc::new(Mutex::new(&mut graph));
-        let v2_markets: Vec<UniswapV2Pair> = uniswap::UniswapV2Pair::get_all_markets(transport)
+        let mut v2_markets: Vec<UniswapV2Pair> = uniswap::UniswapV2Pair::get_all_markets(transport)
             .await
             .unwrap();
+        v2_markets.retain(|market| {
+            let pair = market.tokens();
+            !tokens.flags(&pair.i).blacklisted && !tokens.flags(&pair.j).blacklisted
+        });
         let v2_market_count = v2_markets.len();
         info!("Gathered {} Uniswap V2 Like Markets", v2_market_count);
 
@@ -254,17 +301,114 @@ impl MarketGraph {
         let edge = graph.edge_weight_mut(eth, ceth).unwrap();
         edge.markets.push(Box::new(market));
 
+        // Wrappers with a moving rate
+        let mut wrapper_markets: Vec<Box<dyn Market>> =
+            vec![Box::new(lido::WstethStethMarket::new(transport))];
+        for vault in address_book::ERC4626_VAULTS {
+            match erc4626::Erc4626Market::new(transport, vault.parse().unwrap()).await {
+                Ok(market) => wrapper_markets.push(Box::new(market)),
+                Err(error) => warn!("Failed to load ERC-4626 vault {}: {:?}", vault, error),
+            }
+        }
+        for vault in address_book::YEARN_VAULTS {
+            match yearn::YearnVaultMarket::new(transport, vault.parse().unwrap()).await {
+                Ok(market) => wrapper_markets.push(Box::new(market)),
+                Err(error) => warn!("Failed to load Yearn vault {}: {:?}", vault, error),
+            }
+        }
+        let wrapper_market_count = wrapper_markets.len();
+        for market in wrapper_markets {
+            MarketGraph::add_market(&mut graph, market);
+        }
+
         // Return market.
         info!(
             "Constructed market graph with {} tokens trading on {} markets with {} token markets.",
             graph.node_count(),
-            v2_market_count + 1,
+            v2_market_count + 2 + wrapper_market_count,
             graph.edge_count()
         );
+        let nodes: Vec<Address> = graph.nodes().collect();
+        tokens.update(transport, &nodes).await;
+        let failures = FailureTracker::load(failure_log).unwrap_or_else(|error| {
+            warn!("Failed to load failure log: {:?}", error);
+            FailureTracker::new()
+        });
         let cycles_by_token: HashMap<Address, Vec<Vec<Address>>> = HashMap::new();
         MarketGraph {
             graph,
             cycles_by_token,
+            tokens,
+            failures,
+            bids: BidHistory::new(),
+        }
+    }
+
+    /// Add a market to the edge between its tokens, creating nodes and the edge as needed
+    fn add_market(graph: &mut UnGraphMap<Address, TokenMarkets>, market: Box<dyn Market>) {
+        let tokens = market.tokens();
+        if graph.contains_node(tokens.i).not() {
+            graph.add_node(tokens.i);
+        }
+        if graph.contains_node(tokens.j).not() {
+            graph.add_node(tokens.j);
+        }
+        if graph.contains_edge(tokens.i, tokens.j).not() {
+            graph.add_edge(tokens.i, tokens.j, TokenMarkets::new());
+        }
+        debug!(
+            "Adding {:?} market {} between {} and {}.",
+            market.protocol(),
+            market.market_address(),
+            tokens.i,
+            tokens.j
+        );
+        let edge = graph.edge_weight_mut(tokens.i, tokens.j).unwrap();
+        edge.markets.push(market);
+    }
+
+    /// Record the priority fees of the transactions which emitted logs from markets in the graph
+    pub fn record_bids(&self, block_number: u64, logs: &[Log], gas_prices: &HashMap<H256, U256>) {
+        let mut paid: HashMap<Address, U256> = HashMap::new();
+        for log in logs.iter() {
+            if let Some(gas_price) = log
+                .transaction_hash
+                .and_then(|transaction_hash| gas_prices.get(&transaction_hash))
+            {
+                let highest = paid.entry(log.address).or_default();
+                *highest = std::cmp::max(*highest, *gas_price);
+            }
+        }
+        let mut winners = vec![];
+        for edge in self.graph.all_edges() {
+            for market in edge.2.markets.iter() {
+                if let Some(gas_price) = paid.get(&market.market_address()) {
+                    winners.push((market.market_address(), *gas_price));
+                }
+            }
+        }
+        self.bids.record(block_number, &winners);
+    }
+
+    /// Find a market on the edge between two tokens by its address
+    pub fn find_market(
+        &self,
+        token_a: &Address,
+        token_b: &Address,
+        market_address: &Address,
+    ) -> Option<&dyn Market> {
+        self.graph
+            .edge_weight(*token_a, *token_b)?
+            .markets
+            .iter()
+            .find(|market| market.market_address().0 == market_address.0)
+            .map(|market| market.as_ref())
+    }
+
+    /// Remove a token and all of its markets from the graph
+    pub fn remove_token(&mut self, token: &Address) {
+        if self.graph.remove_node(*token) {
+            info!("Removed token {} from the market graph.", token);
         }
     }
 
@@ -320,3 +464,138 @@ impl MarketGraph {
         futures::future::join_all(token_market_updates).await;
     }
 }
+
+/// In memory markets for tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    use crate::bidding::BidHistory;
+    use crate::evm::Call;
+    use crate::failures::FailureTracker;
+    use crate::tokens::TokenRegistry;
+    use crate::uniswap::UniswapV2Pair;
+
+    /// A constant product market held entirely in memory
+    #[derive(Clone)]
+    pub(crate) struct ConstantProduct {
+        pub(crate) address: Address,
+        pub(crate) tokens: TokenPair,
+        pub(crate) reserve_i: U256,
+        pub(crate) reserve_j: U256,
+    }
+
+    impl ConstantProduct {
+        fn reserves_for(&self, token_in: &Address) -> (U256, U256) {
+            if *token_in == self.tokens.i {
+                (self.reserve_i, self.reserve_j)
+            } else {
+                (self.reserve_j, self.reserve_i)
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Market for ConstantProduct {
+        fn tokens(&self) -> TokenPair {
+            self.tokens
+        }
+
+        fn market_address(&self) -> Address {
+            self.address
+        }
+
+        fn delta_contracts(&self) -> Vec<Address> {
+            vec![self.address]
+        }
+
+        fn protocol(&self) -> Protocol {
+            Protocol::UniswapV2
+        }
+
+        fn reserves(&self) -> Option<(U256, U256)> {
+            Some((self.reserve_i, self.reserve_j))
+        }
+
+        fn with_swap(&self, token_in: &Address, amount_in: &U256) -> Option<Box<dyn Market>> {
+            let token_out = if *token_in == self.tokens.i {
+                self.tokens.j
+            } else {
+                self.tokens.i
+            };
+            let amount_out = self.get_tokens_out(token_in, &token_out, amount_in);
+            let mut market = self.clone();
+            if *token_in == self.tokens.i {
+                market.reserve_i = self.reserve_i + amount_in;
+                market.reserve_j = self.reserve_j - amount_out;
+            } else {
+                market.reserve_j = self.reserve_j + amount_in;
+                market.reserve_i = self.reserve_i - amount_out;
+            }
+            Some(Box::new(market))
+        }
+
+        fn get_tokens_out(&self, token_in: &Address, _: &Address, amount_in: &U256) -> U256 {
+            let (reserve_in, reserve_out) = self.reserves_for(token_in);
+            UniswapV2Pair::get_amount_out(&reserve_in, &reserve_out, amount_in)
+        }
+
+        fn get_tokens_in(&self, token_in: &Address, _: &Address, amount_out: &U256) -> U256 {
+            let (reserve_in, reserve_out) = self.reserves_for(token_in);
+            UniswapV2Pair::get_amount_in(&reserve_in, &reserve_out, amount_out)
+        }
+
+        fn sell_tokens(&self, _: &Address, _: &U256, _: &Address) -> Result<Vec<Call>> {
+            Ok(vec![])
+        }
+
+        fn flash_sell_tokens(&self, _: &Address, _: &U256) -> Result<Option<Vec<Call>>> {
+            Ok(None)
+        }
+
+        async fn update(&mut self) {}
+
+        fn receive_directly(&self, _: &Address) -> bool {
+            true
+        }
+
+        fn to_first_market(&self, _: &Address, _: &U256) -> Result<Option<Vec<Call>>> {
+            Ok(None)
+        }
+
+        fn prepare_receive(&self, _: &Address) -> Result<Option<Vec<Call>>> {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn graph(markets: Vec<ConstantProduct>) -> MarketGraph {
+        let mut graph = UnGraphMap::new();
+        for market in markets {
+            let tokens = market.tokens;
+            if !graph.contains_edge(tokens.i, tokens.j) {
+                graph.add_edge(tokens.i, tokens.j, TokenMarkets::new());
+            }
+            let edge: &mut TokenMarkets = graph.edge_weight_mut(tokens.i, tokens.j).unwrap();
+            edge.markets.push(Box::new(market));
+        }
+        MarketGraph {
+            graph,
+            cycles_by_token: HashMap::new(),
+            tokens: TokenRegistry::new(),
+            failures: FailureTracker::new(),
+            bids: BidHistory::new(),
+        }
+    }
+
+    pub(crate) fn market(address: u8, i: u8, j: u8, reserve: u64) -> ConstantProduct {
+        ConstantProduct {
+            address: Address::repeat_byte(address),
+            tokens: TokenPair {
+                i: Address::repeat_byte(i),
+                j: Address::repeat_byte(j),
+            },
+            reserve_i: U256::from(reserve),
+            reserve_j: U256::from(reserve),
+        }
+    }
+}
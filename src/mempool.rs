@@ -0,0 +1,367 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::{debug, warn};
+use web3::transports::WebSocket;
+use web3::types::{Address, Transaction, TransactionId, H256, U256};
+use web3::Web3;
+
+/// Width of a fee bucket, 0.1 gwei
+const FEE_BUCKET_WEI: u64 = 100_000_000;
+
+/// Number of fee buckets, fees over the last bucket are counted in it
+const FEE_BUCKETS: usize = 1 << 16;
+
+/// Blocks a pending transaction is kept for before it's assumed dropped
+const PENDING_BLOCKS: u64 = 50;
+
+/// Pending transactions fetched from the node at once
+const MAX_CONCURRENT_FETCHES: usize = 16;
+
+/// Fetched transactions queued for the block loop, more are dropped while it's full
+const PENDING_QUEUE_SIZE: usize = 1024;
+
+/// Counts of pending transactions by priority fee bucket
+///
+/// A Fenwick tree over the buckets, so inserts, removes and rank queries are all O(log n).
+#[derive(Debug, Clone)]
+struct FeeIndex {
+    tree: Vec<u32>,
+    count: usize,
+}
+
+impl FeeIndex {
+    fn new() -> FeeIndex {
+        FeeIndex {
+            tree: vec![0; FEE_BUCKETS + 1],
+            count: 0,
+        }
+    }
+
+    fn bucket(fee: &U256) -> usize {
+        let bucket = *fee / FEE_BUCKET_WEI;
+        if bucket >= U256::from(FEE_BUCKETS) {
+            FEE_BUCKETS - 1
+        } else {
+            bucket.as_usize()
+        }
+    }
+
+    fn insert(&mut self, fee: &U256) {
+        let mut index = FeeIndex::bucket(fee) + 1;
+        while index <= FEE_BUCKETS {
+            self.tree[index] += 1;
+            index += index & index.wrapping_neg();
+        }
+        self.count += 1;
+    }
+
+    fn remove(&mut self, fee: &U256) {
+        let mut index = FeeIndex::bucket(fee) + 1;
+        while index <= FEE_BUCKETS {
+            self.tree[index] -= 1;
+            index += index & index.wrapping_neg();
+        }
+        self.count -= 1;
+    }
+
+    /// The lowest fee of the bucket holding the rank-th lowest fee
+    fn nth_lowest(&self, rank: usize) -> Option<U256> {
+        if rank >= self.count {
+            return None;
+        }
+        // Walk down the tree for the last bucket with no more than rank fees up to it
+        let mut index = 0;
+        let mut remaining = rank as u32;
+        let mut step = FEE_BUCKETS;
+        while step > 0 {
+            let next = index + step;
+            if next <= FEE_BUCKETS && self.tree[next] <= remaining {
+                index = next;
+                remaining -= self.tree[next];
+            }
+            step >>= 1;
+        }
+        Some(U256::from(index) * FEE_BUCKET_WEI)
+    }
+
+    fn nth_highest(&self, rank: usize) -> Option<U256> {
+        if rank >= self.count {
+            return None;
+        }
+        self.nth_lowest(self.count - 1 - rank)
+    }
+}
+
+/// Subscribe to pending transactions, fetched from their hashes in the background
+///
+/// The fetches run off the block loop, a bounded number at a time, and the transactions are
+/// queued for it on the returned channel.
+pub async fn watch(transport: &Web3<WebSocket>) -> Result<mpsc::Receiver<Transaction>> {
+    let subscription = transport
+        .eth_subscribe()
+        .subscribe_new_pending_transactions()
+        .await?;
+    let (mut sender, receiver) = mpsc::channel(PENDING_QUEUE_SIZE);
+    let transport = transport.clone();
+    tokio::spawn(async move {
+        let mut transactions = subscription
+            .filter_map(|hash| futures::future::ready(hash.ok()))
+            .map(|hash| {
+                let transport = transport.clone();
+                async move {
+                    let transaction = transport.eth().transaction(TransactionId::Hash(hash)).await;
+                    (hash, transaction)
+                }
+            })
+            .buffer_unordered(MAX_CONCURRENT_FETCHES);
+        while let Some((hash, transaction)) = transactions.next().await {
+            let transaction = match transaction {
+                Ok(Some(transaction)) => transaction,
+                // Already mined or dropped
+                Ok(None) => continue,
+                Err(error) => {
+                    debug!("Failed to fetch pending transaction {}: {:?}", hash, error);
+                    continue;
+                }
+            };
+            if let Err(error) = sender.try_send(transaction) {
+                if error.is_disconnected() {
+                    break;
+                }
+                debug!("Pending transaction queue full, dropping {}.", hash);
+            }
+        }
+        warn!("Pending transaction subscription ended.");
+    });
+    Ok(receiver)
+}
+
+/// A transaction waiting in the mempool
+#[derive(Debug, Clone)]
+pub struct PendingTransaction {
+    pub transaction: Transaction,
+    // Priority fee as of the base fee when it was first seen
+    pub priority_fee: U256,
+    pub first_seen: u64,
+}
+
+/// The pending transactions seen by the node, kept up to date from the pending subscription
+///
+/// Transactions are indexed by priority fee over the base fee when they arrive, which drifts from
+/// the fee they'd pay as the base fee moves.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    transactions: HashMap<H256, PendingTransaction>,
+    // Sender -> nonce -> transaction, for replacements and mined nonces
+    senders: HashMap<Address, BTreeMap<U256, H256>>,
+    fees: FeeIndex,
+    base_fee: U256,
+    block: u64,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool {
+            transactions: HashMap::new(),
+            senders: HashMap::new(),
+            fees: FeeIndex::new(),
+            base_fee: U256::zero(),
+            block: 0,
+        }
+    }
+
+    /// Seed the mempool from the pending transactions of the node's txpool
+    pub async fn load(&mut self, transport: &Web3<WebSocket>) {
+        match transport.txpool().content().await {
+            Ok(content) => {
+                for transactions in content.pending.into_values() {
+                    for transaction in transactions.into_values() {
+                        self.insert(transaction);
+                    }
+                }
+                debug!("Loaded {} pending transactions from txpool.", self.len());
+            }
+            Err(error) => warn!("Failed to load txpool: {:?}", error),
+        }
+    }
+
+    /// The priority fee of a transaction at the current base fee
+    fn priority_fee(&self, transaction: &Transaction) -> U256 {
+        match (
+            transaction.max_fee_per_gas,
+            transaction.max_priority_fee_per_gas,
+        ) {
+            (Some(max_fee), Some(max_priority_fee)) => {
+                std::cmp::min(max_priority_fee, max_fee.saturating_sub(self.base_fee))
+            }
+            _ => transaction
+                .gas_price
+                .unwrap_or_default()
+                .saturating_sub(self.base_fee),
+        }
+    }
+
+    /// Add a pending transaction, replacing any from the same sender with the same nonce
+    pub fn insert(&mut self, transaction: Transaction) {
+        if self.transactions.contains_key(&transaction.hash) {
+            return;
+        }
+        if let Some(from) = transaction.from {
+            let replaced = self
+                .senders
+                .entry(from)
+                .or_default()
+                .insert(transaction.nonce, transaction.hash);
+            if let Some(replaced) = replaced {
+                self.remove_transaction(&replaced);
+            }
+        }
+        let priority_fee = self.priority_fee(&transaction);
+        self.fees.insert(&priority_fee);
+        self.transactions.insert(
+            transaction.hash,
+            PendingTransaction {
+                transaction,
+                priority_fee,
+                first_seen: self.block,
+            },
+        );
+    }
+
+    /// Drop a transaction from the indexes, leaving the sender's nonces alone
+    fn remove_transaction(&mut self, hash: &H256) -> Option<PendingTransaction> {
+        let pending = self.transactions.remove(hash)?;
+        self.fees.remove(&pending.priority_fee);
+        Some(pending)
+    }
+
+    /// Remove a pending transaction
+    pub fn remove(&mut self, hash: &H256) -> Option<PendingTransaction> {
+        let pending = self.remove_transaction(hash)?;
+        if let Some(from) = pending.transaction.from {
+            if let Some(nonces) = self.senders.get_mut(&from) {
+                nonces.remove(&pending.transaction.nonce);
+                if nonces.is_empty() {
+                    self.senders.remove(&from);
+                }
+            }
+        }
+        Some(pending)
+    }
+
+    /// Remove the transactions of a mined block, along with those its nonces replaced
+    ///
+    /// Transactions pending for too long are assumed dropped by the network.
+    pub fn mined(&mut self, block: u64, base_fee: U256, transactions: &[Transaction]) {
+        self.block = block;
+        self.base_fee = base_fee;
+        for transaction in transactions {
+            self.remove(&transaction.hash);
+            let from = match transaction.from {
+                Some(from) => from,
+                None => continue,
+            };
+            let stale: Vec<H256> = match self.senders.get(&from) {
+                Some(nonces) => nonces
+                    .range(..=transaction.nonce)
+                    .map(|(_, hash)| *hash)
+                    .collect(),
+                None => continue,
+            };
+            for hash in stale {
+                self.remove(&hash);
+            }
+        }
+        let expired: Vec<H256> = self
+            .transactions
+            .values()
+            .filter(|pending| pending.first_seen + PENDING_BLOCKS < block)
+            .map(|pending| pending.transaction.hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&PendingTransaction> {
+        self.transactions.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// The rank-th highest priority fee pending, to 0.1 gwei
+    pub fn nth_highest_fee(&self, rank: usize) -> Option<U256> {
+        self.fees.nth_highest(rank)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn transaction(hash: u8, from: u8, nonce: u64, gas_price: u64) -> Transaction {
+        Transaction {
+            hash: H256::repeat_byte(hash),
+            from: Some(Address::repeat_byte(from)),
+            nonce: U256::from(nonce),
+            gas_price: Some(U256::from(gas_price * GWEI)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fee_index_ranks() {
+        let mut fees = FeeIndex::new();
+        assert_eq!(fees.nth_lowest(0), None);
+        for fee in [5, 1, 3, 3, 9] {
+            fees.insert(&U256::from(fee * GWEI));
+        }
+        // Fees over the last bucket land in it
+        fees.insert(&U256::from(1_000_000 * GWEI));
+        assert_eq!(fees.nth_lowest(0), Some(U256::from(GWEI)));
+        assert_eq!(fees.nth_lowest(2), Some(U256::from(3 * GWEI)));
+        assert_eq!(
+            fees.nth_highest(0),
+            Some(U256::from((FEE_BUCKETS as u64 - 1) * FEE_BUCKET_WEI))
+        );
+        assert_eq!(fees.nth_highest(1), Some(U256::from(9 * GWEI)));
+        fees.remove(&U256::from(9 * GWEI));
+        assert_eq!(fees.nth_highest(1), Some(U256::from(5 * GWEI)));
+        assert_eq!(fees.nth_highest(5), None);
+    }
+
+    #[test]
+    fn mempool_tracks_replacements_and_mined_nonces() {
+        let mut mempool = Mempool::new();
+        mempool.mined(1, U256::from(10 * GWEI), &[]);
+        mempool.insert(transaction(1, 1, 0, 20));
+        mempool.insert(transaction(2, 1, 1, 30));
+        mempool.insert(transaction(3, 2, 0, 15));
+        assert_eq!(mempool.nth_highest_fee(0), Some(U256::from(20 * GWEI)));
+        // Same sender and nonce replaces
+        mempool.insert(transaction(4, 2, 0, 50));
+        assert_eq!(mempool.len(), 3);
+        assert!(mempool.get(&H256::repeat_byte(3)).is_none());
+        assert_eq!(mempool.nth_highest_fee(0), Some(U256::from(40 * GWEI)));
+        // Mining nonce 1 clears nonce 0 too, even under another hash
+        mempool.mined(2, U256::from(10 * GWEI), &[transaction(5, 1, 1, 30)]);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.nth_highest_fee(1), None);
+        // And everything left is eventually assumed dropped
+        mempool.mined(1 + PENDING_BLOCKS, U256::zero(), &[]);
+        assert_eq!(mempool.len(), 1);
+        mempool.mined(2 + PENDING_BLOCKS, U256::zero(), &[]);
+        assert!(mempool.is_empty());
+    }
+}
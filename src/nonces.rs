@@ -0,0 +1,123 @@
+use std::collections::{BTreeSet, HashMap};
+
+use log::warn;
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockNumber, U256};
+use web3::Web3;
+
+#[derive(Debug, Default, Clone)]
+struct AccountNonces {
+    // The next nonce the chain will accept
+    confirmed: U256,
+    // Nonces held by our transactions in the public mempool
+    reserved: BTreeSet<U256>,
+}
+
+/// Confirmed and in flight nonces of each executor
+///
+/// Relay bundles only live for their block, so they don't hold nonces, and alternative bundles
+/// for a block share theirs. Transactions sent to the public mempool hold theirs until mined.
+#[derive(Debug, Default)]
+pub struct NonceManager {
+    accounts: HashMap<Address, AccountNonces>,
+}
+
+impl NonceManager {
+    pub fn new() -> NonceManager {
+        NonceManager::default()
+    }
+
+    /// Refresh the confirmed and pending nonces of an executor from the node
+    pub async fn sync(&mut self, transport: &Web3<WebSocket>, address: &Address) {
+        let confirmed = match transport.eth().transaction_count(*address, None).await {
+            Ok(confirmed) => confirmed,
+            Err(error) => {
+                warn!("Failed to get confirmed nonce of {}: {:?}", address, error);
+                return;
+            }
+        };
+        let pending = match transport
+            .eth()
+            .transaction_count(*address, Some(BlockNumber::Pending))
+            .await
+        {
+            Ok(pending) => pending,
+            Err(error) => {
+                warn!("Failed to get pending nonce of {}: {:?}", address, error);
+                return;
+            }
+        };
+        for nonce in self.observe(address, confirmed, pending) {
+            warn!(
+                "Nonce {} of {} is no longer pending, releasing it.",
+                nonce, address
+            );
+        }
+    }
+
+    /// Update an executor's nonces, returning the reservations dropped by the node
+    fn observe(&mut self, address: &Address, confirmed: U256, pending: U256) -> Vec<U256> {
+        let account = self.accounts.entry(*address).or_default();
+        account.confirmed = confirmed;
+        // Mined, by us or a replacement
+        account.reserved = account.reserved.split_off(&confirmed);
+        // The node has forgotten these, and everything after a gap is stuck behind it
+        let dropped = account
+            .reserved
+            .split_off(&std::cmp::max(pending, confirmed));
+        dropped.into_iter().collect()
+    }
+
+    /// The next nonce the chain will accept from an executor
+    pub fn confirmed(&self, address: &Address) -> Option<U256> {
+        self.accounts.get(address).map(|account| account.confirmed)
+    }
+
+    /// The nonce the next transactions of an executor should start at, after those in flight
+    pub fn next(&self, address: &Address) -> Option<U256> {
+        let account = self.accounts.get(address)?;
+        let mut nonce = account.confirmed;
+        while account.reserved.contains(&nonce) {
+            nonce += U256::one();
+        }
+        Some(nonce)
+    }
+
+    /// Hold a nonce used by a transaction sent to the public mempool
+    pub fn reserve(&mut self, address: &Address, nonce: U256) {
+        self.accounts
+            .entry(*address)
+            .or_default()
+            .reserved
+            .insert(nonce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_and_gaps() {
+        let address = Address::repeat_byte(1);
+        let mut nonces = NonceManager::new();
+        assert_eq!(nonces.next(&address), None);
+        nonces.observe(&address, U256::from(5), U256::from(5));
+        assert_eq!(nonces.next(&address), Some(U256::from(5)));
+        nonces.reserve(&address, U256::from(5));
+        nonces.reserve(&address, U256::from(6));
+        assert_eq!(nonces.next(&address), Some(U256::from(7)));
+        // 5 is mined, and the node still has 6
+        assert!(nonces
+            .observe(&address, U256::from(6), U256::from(7))
+            .is_empty());
+        assert_eq!(nonces.next(&address), Some(U256::from(7)));
+        // 6 was dropped, so it's released
+        assert_eq!(
+            nonces.observe(&address, U256::from(6), U256::from(6)),
+            vec![U256::from(6)]
+        );
+        assert_eq!(nonces.next(&address), Some(U256::from(6)));
+        assert_eq!(nonces.confirmed(&address), Some(U256::from(6)));
+    }
+}
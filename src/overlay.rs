@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use web3::types::{Address, U256};
+
+use crate::markets::{Market, MarketGraph};
+
+/// A copy on write view of the market graph with hypothetical swaps applied
+///
+/// Swaps are applied to copies of only the markets they touch, leaving the graph itself
+/// untouched. Queries see the copied state where a market has been swapped against, and the
+/// graph's state everywhere else, so a later swap sees the effect of the earlier ones.
+pub struct MarketOverlay<'a> {
+    base: &'a MarketGraph,
+    // Markets with swaps applied, by market address
+    pending: HashMap<Address, Box<dyn Market>>,
+}
+
+impl<'a> MarketOverlay<'a> {
+    pub fn new(base: &'a MarketGraph) -> MarketOverlay<'a> {
+        MarketOverlay {
+            base,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn base(&self) -> &'a MarketGraph {
+        self.base
+    }
+
+    /// Find a market on the edge between two tokens by its address, in its overlaid state
+    pub fn market(
+        &self,
+        token_a: &Address,
+        token_b: &Address,
+        market_address: &Address,
+    ) -> Option<&dyn Market> {
+        match self.pending.get(market_address) {
+            Some(market) => Some(market.as_ref()),
+            None => self.base.find_market(token_a, token_b, market_address),
+        }
+    }
+
+    /// All markets on the edge between two tokens, in their overlaid state
+    pub fn edge_markets(&self, token_a: &Address, token_b: &Address) -> Vec<&dyn Market> {
+        match self.base.graph.edge_weight(*token_a, *token_b) {
+            Some(token_markets) => token_markets
+                .markets
+                .iter()
+                .map(|market| match self.pending.get(&market.market_address()) {
+                    Some(pending) => pending.as_ref(),
+                    None => market.as_ref(),
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Markets which have had swaps applied
+    pub fn pending_markets(&self) -> impl Iterator<Item = &dyn Market> {
+        self.pending.values().map(|market| market.as_ref())
+    }
+
+    /// Has a swap been applied to the market?
+    pub fn is_pending(&self, market_address: &Address) -> bool {
+        self.pending.contains_key(market_address)
+    }
+
+    /// Tokens out of a market in its overlaid state
+    pub fn get_tokens_out(
+        &self,
+        market_address: &Address,
+        token_in: &Address,
+        token_out: &Address,
+        amount_in: &U256,
+    ) -> Option<U256> {
+        let market = self.market(token_in, token_out, market_address)?;
+        Some(market.get_tokens_out(token_in, token_out, amount_in))
+    }
+
+    /// Tokens into a market in its overlaid state
+    pub fn get_tokens_in(
+        &self,
+        market_address: &Address,
+        token_in: &Address,
+        token_out: &Address,
+        amount_out: &U256,
+    ) -> Option<U256> {
+        let market = self.market(token_in, token_out, market_address)?;
+        Some(market.get_tokens_in(token_in, token_out, amount_out))
+    }
+
+    /// Apply a swap to a market, returning the tokens out
+    ///
+    /// Markets which can't model a swap leave the overlay unchanged and return None.
+    pub fn swap(
+        &mut self,
+        market_address: &Address,
+        token_in: &Address,
+        token_out: &Address,
+        amount_in: &U256,
+    ) -> Option<U256> {
+        let market = self.market(token_in, token_out, market_address)?;
+        let amount_out = market.get_tokens_out(token_in, token_out, amount_in);
+        let swapped = market.with_swap(token_in, amount_in)?;
+        self.pending.insert(*market_address, swapped);
+        Some(amount_out)
+    }
+
+    /// Apply a swap along a path of tokens through a market for each hop, returning the tokens out
+    ///
+    /// The overlay is only changed if every hop can be applied.
+    pub fn swap_path(
+        &mut self,
+        market_addresses: &[Address],
+        path: &[Address],
+        amount_in: &U256,
+    ) -> Option<U256> {
+        if path.len() != market_addresses.len() + 1 {
+            return None;
+        }
+        let mut swapped = MarketOverlay {
+            base: self.base,
+            pending: HashMap::new(),
+        };
+        let mut amount = *amount_in;
+        for (hop, market_address) in path.windows(2).zip(market_addresses) {
+            let market = self.market(&hop[0], &hop[1], market_address);
+            let market = match swapped.pending.get(market_address) {
+                Some(market) => market.as_ref(),
+                None => market?,
+            };
+            let amount_out = market.get_tokens_out(&hop[0], &hop[1], &amount);
+            let market = market.with_swap(&hop[0], &amount)?;
+            swapped.pending.insert(*market_address, market);
+            amount = amount_out;
+        }
+        self.pending.extend(swapped.pending);
+        Some(amount)
+    }
+
+    /// The tokens in at the start of a path for an amount out at the end
+    pub fn path_amount_in(
+        &self,
+        market_addresses: &[Address],
+        path: &[Address],
+        amount_out: &U256,
+    ) -> Option<U256> {
+        if path.len() != market_addresses.len() + 1 {
+            return None;
+        }
+        let mut amount = *amount_out;
+        for (hop, market_address) in path.windows(2).zip(market_addresses).rev() {
+            amount = self.get_tokens_in(market_address, &hop[0], &hop[1], &amount)?;
+            if amount.is_zero() {
+                return None;
+            }
+        }
+        Some(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::markets::testing::{graph, market};
+
+    #[test]
+    fn swap_is_copy_on_write() {
+        let markets = graph(vec![market(0xa, 1, 2, 1_000_000)]);
+        let (pair, a, b) = (
+            Address::repeat_byte(0xa),
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+        );
+        let amount = U256::from(10_000);
+        let mut overlay = MarketOverlay::new(&markets);
+        let before = overlay.get_tokens_out(&pair, &a, &b, &amount).unwrap();
+        assert_eq!(overlay.swap(&pair, &a, &b, &amount), Some(before));
+        assert!(overlay.is_pending(&pair));
+        // The overlay sees the swap, the graph does not
+        assert!(overlay.get_tokens_out(&pair, &a, &b, &amount).unwrap() < before);
+        assert_eq!(
+            markets
+                .find_market(&a, &b, &pair)
+                .unwrap()
+                .get_tokens_out(&a, &b, &amount),
+            before
+        );
+        // And the other direction got cheaper
+        assert!(overlay.get_tokens_out(&pair, &b, &a, &amount).unwrap() > before);
+    }
+
+    #[test]
+    fn swap_path_sees_earlier_hops() {
+        // Both hops route through the same market, out and back
+        let markets = graph(vec![market(0xa, 1, 2, 1_000_000)]);
+        let pair = Address::repeat_byte(0xa);
+        let path = [
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(1),
+        ];
+        let amount = U256::from(10_000);
+        let mut overlay = MarketOverlay::new(&markets);
+        let amount_out = overlay.swap_path(&[pair, pair], &path, &amount).unwrap();
+        // The round trip pays the fee twice
+        assert!(amount_out < amount);
+        assert!(amount_out > amount * 99 / 100);
+        let (reserve_i, reserve_j) = overlay
+            .pending_markets()
+            .next()
+            .unwrap()
+            .reserves()
+            .unwrap();
+        assert_eq!(reserve_i, U256::from(1_000_000) + amount - amount_out);
+        // Every token j bought on the way out was sold on the way back
+        assert_eq!(reserve_j, U256::from(1_000_000));
+    }
+
+    #[test]
+    fn swap_path_is_all_or_nothing() {
+        let markets = graph(vec![market(0xa, 1, 2, 1_000_000)]);
+        let path = [
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+        ];
+        let mut overlay = MarketOverlay::new(&markets);
+        assert_eq!(
+            overlay.swap_path(
+                &[Address::repeat_byte(0xa), Address::repeat_byte(0xb)],
+                &path,
+                &U256::from(10_000)
+            ),
+            None
+        );
+        assert_eq!(overlay.pending_markets().count(), 0);
+    }
+
+    #[test]
+    fn edge_markets_are_overlaid() {
+        let markets = graph(vec![
+            market(0xa, 1, 2, 1_000_000),
+            market(0xb, 1, 2, 1_000_000),
+        ]);
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let amount = U256::from(10_000);
+        let mut overlay = MarketOverlay::new(&markets);
+        overlay.swap(&Address::repeat_byte(0xa), &a, &b, &amount);
+        let offers: Vec<U256> = overlay
+            .edge_markets(&a, &b)
+            .iter()
+            .map(|market| market.get_tokens_out(&b, &a, &amount))
+            .collect();
+        assert!(offers[0] > offers[1]);
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use web3::transports::WebSocket;
+use web3::types::{Address, Transaction, TransactionParameters, H256, U256, U64};
+use web3::Web3;
+
+use crate::flashbots::Bundle;
+use crate::gas::GasPrice;
+use crate::nonces::NonceManager;
+use crate::uniswap_router::{RouterDecoder, RouterIntent};
+use crate::wallet::LocalWallet;
+use crate::{constants, utilities};
+
+/// Most times a submission's priority fee is raised to outbid the mempool
+const MAX_BUMPS: u32 = 5;
+
+/// Minimum raise of both fees for a node to accept a same-nonce replacement, in percent
+const REPLACEMENT_BUMP_PERCENTAGE: u64 = 10;
+
+/// Gas of the self transfer which cancels an expired submission
+const CANCELLATION_GAS: u64 = 21_000;
+
+/// A transaction sent to the public mempool, tracked until its nonce is used
+#[derive(Debug, Clone)]
+struct Submission {
+    executor: LocalWallet,
+    transaction: utilities::Transaction,
+    // Every hash sent with this nonce, the last is live
+    hashes: Vec<H256>,
+    // The only block the multicall will succeed in
+    desired_block: u64,
+    // The highest priority fee the taken profit can pay for
+    max_priority_fee: U256,
+    bumps: u32,
+    cancelled: bool,
+}
+
+/// Fees for a same-nonce replacement paying priority_fee, which nodes will accept
+pub fn replacement_fees(
+    parameters: &TransactionParameters,
+    priority_fee: U256,
+    base_fee: U256,
+) -> (U256, U256) {
+    let bump = |fee: U256| fee * (100 + REPLACEMENT_BUMP_PERCENTAGE) / 100 + 1;
+    let max_fee = parameters.max_fee_per_gas.unwrap_or_default();
+    let old_priority_fee = parameters.max_priority_fee_per_gas.unwrap_or_default();
+    let priority_fee = std::cmp::max(priority_fee, bump(old_priority_fee));
+    let max_base_fee = base_fee + base_fee / 8 + 1;
+    let max_fee = std::cmp::max(bump(max_fee), max_base_fee + priority_fee);
+    (max_fee, priority_fee)
+}
+
+/// Priority gas auction fallback for bundles the relays would throw away
+///
+/// Transactions are sent to the public mempool at the top of the pending priority fees, and are
+/// only good for their desired block. They're outbid as higher fees arrive in the mempool, and
+/// cancelled if the desired block is mined without them, rather than reverting later.
+pub struct PgaSubmitter {
+    // By executor and nonce
+    submissions: BTreeMap<(Address, U256), Submission>,
+    decoder: RouterDecoder,
+}
+
+impl PgaSubmitter {
+    pub fn new() -> PgaSubmitter {
+        PgaSubmitter {
+            submissions: BTreeMap::new(),
+            decoder: RouterDecoder::new(),
+        }
+    }
+
+    /// The markets a pending transaction trades through, directly or through a router
+    fn markets(&self, transaction: &Transaction) -> Vec<Address> {
+        let to = match transaction.to {
+            Some(to) => to,
+            None => return vec![],
+        };
+        match self
+            .decoder
+            .decode(&to, &transaction.input.0, transaction.value)
+        {
+            Some(RouterIntent::Swap(intent)) => intent.pairs,
+            Some(RouterIntent::Liquidity(intent)) => vec![intent.pair],
+            None => vec![to],
+        }
+    }
+
+    /// Can the bundle be sent as a lone transaction, without relying on ordering?
+    pub fn eligible(bundle: &Bundle) -> bool {
+        bundle.transactions.len() == 1 && !bundle.transactions[0].external
+    }
+
+    /// Sign and send the transaction of a bundle at a competitive priority fee
+    pub async fn submit(
+        &mut self,
+        transport: &Web3<WebSocket>,
+        executor: &LocalWallet,
+        nonces: &mut NonceManager,
+        bundle: &Bundle,
+        gas_price: &GasPrice,
+    ) -> Result<H256> {
+        if !PgaSubmitter::eligible(bundle) {
+            return Err(anyhow!("Bundle can't be split into the public mempool"));
+        }
+        if self
+            .submissions
+            .keys()
+            .any(|(address, _)| *address == executor.public_key)
+        {
+            return Err(anyhow!("A public mempool submission is still pending"));
+        }
+        let mut transaction = bundle.transactions[0].clone();
+        let priority_fee = transaction
+            .parameters
+            .max_priority_fee_per_gas
+            .unwrap_or_default();
+        let max_priority_fee = priority_fee + bundle.taken_profit() / transaction.estimated_gas;
+        // The top of the mempool, if the profit stretches that far
+        let competitive = gas_price.high * (100 + REPLACEMENT_BUMP_PERCENTAGE) / 100;
+        let priority_fee =
+            std::cmp::max(priority_fee, std::cmp::min(competitive, max_priority_fee));
+        let max_base_fee = gas_price.base_fee + gas_price.base_fee / 8 + 1;
+        transaction.parameters.max_priority_fee_per_gas = Some(priority_fee);
+        transaction.parameters.max_fee_per_gas = Some(max_base_fee + priority_fee);
+        let mut transactions = vec![transaction];
+        executor
+            .sign_transactions(
+                transport,
+                &mut transactions,
+                nonces.next(&executor.public_key),
+            )
+            .await;
+        let transaction = transactions.remove(0);
+        let hash = PgaSubmitter::send(transport, &transaction).await?;
+        let nonce = transaction.parameters.nonce.unwrap_or_default();
+        // Held until mined, so bundles are numbered after it
+        nonces.reserve(&executor.public_key, nonce);
+        info!(
+            "Sent {} to the public mempool at a {} gwei priority fee.",
+            hash,
+            utilities::to_gwei(&priority_fee)
+        );
+        self.submissions.insert(
+            (executor.public_key, nonce),
+            Submission {
+                executor: *executor,
+                transaction,
+                hashes: vec![hash],
+                desired_block: bundle.block.as_u64() + 1,
+                max_priority_fee,
+                bumps: 0,
+                cancelled: false,
+            },
+        );
+        Ok(hash)
+    }
+
+    async fn send(
+        transport: &Web3<WebSocket>,
+        transaction: &utilities::Transaction,
+    ) -> Result<H256> {
+        let signed = transaction
+            .signed
+            .as_ref()
+            .ok_or_else(|| anyhow!("Transaction isn't signed"))?;
+        Ok(transport
+            .eth()
+            .send_raw_transaction(signed.raw_transaction.clone())
+            .await?)
+    }
+
+    /// Re-sign and send a submission with new parameters under the same nonce
+    async fn replace(
+        transport: &Web3<WebSocket>,
+        submission: &mut Submission,
+        parameters: TransactionParameters,
+    ) -> Result<H256> {
+        submission.transaction.parameters = parameters;
+        let private_key = submission.executor.private_key;
+        submission.transaction.sign(transport, &private_key).await;
+        let hash = PgaSubmitter::send(transport, &submission.transaction).await?;
+        submission.hashes.push(hash);
+        Ok(hash)
+    }
+
+    /// Outbid a pending transaction competing for the markets of our submissions
+    ///
+    /// Only transactions trading through a submission's route are competition, higher fees
+    /// elsewhere in the mempool don't stand between it and its markets.
+    pub async fn outbid(
+        &mut self,
+        transport: &Web3<WebSocket>,
+        transaction: &Transaction,
+        priority_fee: U256,
+        base_fee: U256,
+    ) {
+        if self.submissions.is_empty() {
+            return;
+        }
+        let hash = &transaction.hash;
+        let markets = self.markets(transaction);
+        for submission in self.submissions.values_mut() {
+            if submission.cancelled
+                || submission.bumps >= MAX_BUMPS
+                || submission.hashes.contains(hash)
+                || !submission
+                    .transaction
+                    .route
+                    .iter()
+                    .any(|address| markets.contains(address))
+            {
+                continue;
+            }
+            let parameters = &submission.transaction.parameters;
+            if priority_fee < parameters.max_priority_fee_per_gas.unwrap_or_default() {
+                continue;
+            }
+            let (max_fee, bumped) = replacement_fees(parameters, priority_fee + 1, base_fee);
+            if bumped > submission.max_priority_fee {
+                debug!(
+                    "Can't outbid {} at {} gwei.",
+                    hash,
+                    utilities::to_gwei(&priority_fee)
+                );
+                continue;
+            }
+            let mut parameters = parameters.clone();
+            parameters.max_fee_per_gas = Some(max_fee);
+            parameters.max_priority_fee_per_gas = Some(bumped);
+            submission.bumps += 1;
+            match PgaSubmitter::replace(transport, submission, parameters).await {
+                Ok(replacement) => info!(
+                    "Outbid {} with {} at a {} gwei priority fee.",
+                    hash,
+                    replacement,
+                    utilities::to_gwei(&bumped)
+                ),
+                Err(error) => warn!("Failed to send replacement: {:?}", error),
+            }
+        }
+    }
+
+    /// Settle submissions whose nonces were used, and cancel those past their desired block
+    pub async fn update(
+        &mut self,
+        transport: &Web3<WebSocket>,
+        nonces: &NonceManager,
+        block_number: u64,
+        base_fee: U256,
+    ) {
+        let settled: Vec<(Address, U256)> = self
+            .submissions
+            .keys()
+            .filter(|(address, nonce)| match nonces.confirmed(address) {
+                Some(confirmed) => *nonce < confirmed,
+                None => false,
+            })
+            .copied()
+            .collect();
+        for settled in settled {
+            let submission = self.submissions.remove(&settled).unwrap();
+            for hash in submission.hashes.iter() {
+                if let Ok(Some(receipt)) = transport.eth().transaction_receipt(*hash).await {
+                    match receipt.status {
+                        Some(status) if status == U64::from(1) => {
+                            info!("Public mempool transaction {} included.", hash)
+                        }
+                        _ => warn!("Public mempool transaction {} reverted.", hash),
+                    }
+                }
+            }
+        }
+        for submission in self.submissions.values_mut() {
+            if submission.cancelled || block_number < submission.desired_block {
+                continue;
+            }
+            // Mined any later, the multicall would only revert and burn the gas
+            let priority_fee = submission
+                .transaction
+                .parameters
+                .max_priority_fee_per_gas
+                .unwrap_or_default();
+            let (max_fee, priority_fee) =
+                replacement_fees(&submission.transaction.parameters, priority_fee, base_fee);
+            let parameters = TransactionParameters {
+                nonce: submission.transaction.parameters.nonce,
+                to: Some(submission.executor.public_key),
+                gas: U256::from(CANCELLATION_GAS),
+                gas_price: None,
+                value: constants::ZERO_U256,
+                data: Default::default(),
+                chain_id: Some(1_u64),
+                transaction_type: Some(U64::from(constants::EIP1559_TRANSACTION_TYPE)),
+                access_list: None,
+                max_fee_per_gas: Some(max_fee),
+                max_priority_fee_per_gas: Some(priority_fee),
+            };
+            submission.cancelled = true;
+            match PgaSubmitter::replace(transport, submission, parameters).await {
+                Ok(cancellation) => info!(
+                    "Cancelling public mempool transaction for block #{} with {}.",
+                    submission.desired_block, cancellation
+                ),
+                Err(error) => warn!("Failed to send cancellation: {:?}", error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacements_bump_both_fees() {
+        let parameters = TransactionParameters {
+            max_fee_per_gas: Some(U256::from(1000)),
+            max_priority_fee_per_gas: Some(U256::from(100)),
+            ..Default::default()
+        };
+        // Too small a raise is bumped to what nodes accept
+        let (max_fee, priority_fee) =
+            replacement_fees(&parameters, U256::from(105), U256::from(800));
+        assert_eq!(priority_fee, U256::from(111));
+        assert_eq!(max_fee, U256::from(1101));
+        // And the max fee covers the priority fee over a rising base fee
+        let (max_fee, priority_fee) =
+            replacement_fees(&parameters, U256::from(500), U256::from(800));
+        assert_eq!(priority_fee, U256::from(500));
+        assert_eq!(max_fee, U256::from(800 + 100 + 1 + 500));
+    }
+
+    #[test]
+    fn markets_of_pending_transactions() {
+        let pga = PgaSubmitter::new();
+        let pair = Address::repeat_byte(7);
+        // A call straight to a market trades through it
+        let transaction = Transaction {
+            to: Some(pair),
+            ..Default::default()
+        };
+        assert_eq!(pga.markets(&transaction), vec![pair]);
+        // Contract creations trade through nothing
+        assert!(pga.markets(&Transaction::default()).is_empty());
+    }
+}
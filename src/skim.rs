@@ -0,0 +1,487 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use web3::contract::Contract;
+use web3::ethabi;
+use web3::ethabi::Token;
+use web3::transports::WebSocket;
+use web3::types::{
+    Address, BlockId, BlockNumber, CallRequest, FilterBuilder, TransactionParameters, H256, U256,
+    U64,
+};
+use web3::Web3;
+
+use crate::bidding::{Bid, BidContext, BiddingStrategy};
+use crate::evm::{Call, Multicall, MulticallHeader};
+use crate::flashbots::{Bundle, BundleGenerator};
+use crate::gas::GasPrice;
+use crate::markets::{Market, MarketGraph, TokenPair};
+use crate::utilities::Transaction;
+use crate::{address_book, constants, evm, utilities};
+
+/// Number of balance queries to run concurrently
+const SKIM_BATCH_SIZE: usize = 250;
+
+/// Blocks between background scans of every pair, searches only check the pairs a scan flagged
+/// and pairs receiving transfers
+const FULL_SCAN_INTERVAL: u64 = 100;
+
+/// Gas a skim is expected to use, for bidding before it is estimated
+const SKIM_GAS: u64 = 200_000;
+
+/// keccak256("Transfer(address,address,uint256)")
+const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+/// Balances of a pair in excess of its stored reserves, if it holds any
+fn excess_reserves(reserves: (U256, U256), balances: (U256, U256)) -> Option<(U256, U256)> {
+    let excess_i = balances.0.saturating_sub(reserves.0);
+    let excess_j = balances.1.saturating_sub(reserves.1);
+    if excess_i.is_zero() && excess_j.is_zero() {
+        return None;
+    }
+    Some((excess_i, excess_j))
+}
+
+async fn balance_of(
+    transport: &Web3<WebSocket>,
+    erc20_abi: &ethabi::Contract,
+    token: Address,
+    owner: Address,
+    block_number: &U64,
+) -> Option<U256> {
+    let data = erc20_abi
+        .function("balanceOf")
+        .ok()?
+        .encode_input(&[Token::Address(owner)])
+        .ok()?;
+    let result = transport
+        .eth()
+        .call(
+            CallRequest {
+                to: Some(token),
+                data: Some(data.into()),
+                ..Default::default()
+            },
+            Some(BlockId::from(*block_number)),
+        )
+        .await;
+    let result = match result {
+        Ok(result) => result,
+        Err(error) => {
+            // Plenty of tokens revert on balance queries, so this is only worth a debug
+            debug!(
+                "Failed to get balance of {} in {}: {:?}",
+                owner, token, error
+            );
+            return None;
+        }
+    };
+    if result.0.len() < 32 {
+        return None;
+    }
+    Some(U256::from_big_endian(&result.0[..32]))
+}
+
+/// Balances of both tokens of a pair
+async fn pair_balances(
+    transport: &Web3<WebSocket>,
+    erc20_abi: &ethabi::Contract,
+    pair: Address,
+    tokens: TokenPair,
+    block_number: &U64,
+) -> Option<(U256, U256)> {
+    match futures::future::join(
+        balance_of(transport, erc20_abi, tokens.i, pair, block_number),
+        balance_of(transport, erc20_abi, tokens.j, pair, block_number),
+    )
+    .await
+    {
+        (Some(balance_i), Some(balance_j)) => Some((balance_i, balance_j)),
+        _ => None,
+    }
+}
+
+/// Tokens held by a pair in excess of its stored reserves
+pub struct SkimDetails<'a> {
+    market: &'a dyn Market,
+    excess_i: U256,
+    excess_j: U256,
+    // Value of the excess in wei
+    value: U256,
+}
+
+/// This engine skims token balances in excess of the reserves of Uniswap V2 pairs
+///
+/// Donations, airdrops and fee on transfer leftovers all leave a pair holding more than its
+/// reserves, which anyone can skim to themselves.
+pub struct SkimEngine {
+    bundle_executor_contract: Contract<WebSocket>,
+    pair_abi: ethabi::Contract,
+    erc20_abi: ethabi::Contract,
+    last_full_scan: Mutex<Option<U64>>,
+    // Set while a full scan is running
+    scanning: Arc<AtomicBool>,
+    // Pairs found holding an excess, checked by the next search
+    flagged: Arc<Mutex<HashSet<Address>>>,
+    bidding: Arc<dyn BiddingStrategy>,
+}
+
+impl SkimEngine {
+    pub fn new(transport: &Web3<WebSocket>, bidding: Arc<dyn BiddingStrategy>) -> SkimEngine {
+        let bundle_executor_contract = Contract::from_json(
+            transport.eth(),
+            address_book::MulticallEXECUTOR.parse().unwrap(),
+            include_bytes!("abis/Multicall.json"),
+        )
+        .unwrap();
+        let pair_abi =
+            ethabi::Contract::load(&include_bytes!("protocols/uniswap/v2/abis/pair.json")[..])
+                .unwrap();
+        let erc20_abi = ethabi::Contract::load(&include_bytes!("abis/IERC20.json")[..]).unwrap();
+        SkimEngine {
+            bundle_executor_contract,
+            pair_abi,
+            erc20_abi,
+            last_full_scan: Mutex::new(None),
+            scanning: Arc::new(AtomicBool::new(false)),
+            flagged: Arc::new(Mutex::new(HashSet::new())),
+            bidding,
+        }
+    }
+
+    /// All reserve based markets, keyed by address, less the blacklisted ones
+    fn reserve_markets<'a>(&self, markets: &'a MarketGraph) -> HashMap<Address, &'a dyn Market> {
+        let blacklisted_pools: HashSet<Address> = address_book::BLACKLISTED_POOLS
+            .iter()
+            .map(|pool| pool.parse().unwrap())
+            .collect();
+        let mut reserve_markets = HashMap::new();
+        for edge in markets.graph.all_edges() {
+            for market in edge.2.markets.iter() {
+                let tokens = market.tokens();
+                if market.reserves().is_some()
+                    && !blacklisted_pools.contains(&market.market_address())
+                    && !markets.tokens.flags(&tokens.i).blacklisted
+                    && !markets.tokens.flags(&tokens.j).blacklisted
+                {
+                    reserve_markets.insert(market.market_address(), market.as_ref());
+                }
+            }
+        }
+        reserve_markets
+    }
+
+    /// Pairs which received a token transfer in the block
+    async fn transfer_recipients(
+        &self,
+        transport: &Web3<WebSocket>,
+        block_number: &U64,
+    ) -> HashSet<Address> {
+        let transfer_topic: H256 = TRANSFER_TOPIC.parse().unwrap();
+        let logs = match transport
+            .eth()
+            .logs(
+                FilterBuilder::default()
+                    .from_block(BlockNumber::Number(*block_number))
+                    .to_block(BlockNumber::Number(*block_number))
+                    .topics(Some(vec![transfer_topic]), None, None, None)
+                    .build(),
+            )
+            .await
+        {
+            Ok(logs) => logs,
+            Err(error) => {
+                warn!(
+                    "Failed to get transfers of block #{}: {:?}",
+                    block_number, error
+                );
+                return HashSet::new();
+            }
+        };
+        logs.iter()
+            .filter(|log| log.topics.len() == 3)
+            .map(|log| Address::from_slice(&log.topics[2].as_bytes()[12..]))
+            .collect()
+    }
+
+    /// Value an amount of a token in wei, with the best market to sell it to weth
+    fn to_eth<'a>(
+        &self,
+        markets: &'a MarketGraph,
+        token: &Address,
+        amount: &U256,
+    ) -> (U256, Option<&'a dyn Market>) {
+        let weth: Address = address_book::WETH_ADDRESS.parse().unwrap();
+        if token.0 == weth.0 {
+            return (*amount, None);
+        }
+        match markets.graph.edge_weight(*token, weth) {
+            Some(token_markets) => {
+                let (market, value) = token_markets.best_ask_market(token, &weth, amount);
+                (value, Some(market))
+            }
+            None => (constants::ZERO_U256, None),
+        }
+    }
+
+    /// Is a full scan due at the block?
+    fn full_scan_due(&self, block_number: &U64) -> bool {
+        let mut last_full_scan = self.last_full_scan.lock().unwrap();
+        match *last_full_scan {
+            Some(last) if block_number.as_u64() < last.as_u64() + FULL_SCAN_INTERVAL => false,
+            _ => {
+                *last_full_scan = Some(*block_number);
+                true
+            }
+        }
+    }
+
+    /// Check the balances of every pair in the background, flagging those holding an excess
+    fn spawn_full_scan(
+        &self,
+        transport: &Web3<WebSocket>,
+        pairs: Vec<(Address, TokenPair, (U256, U256))>,
+        block_number: U64,
+    ) {
+        if self.scanning.swap(true, Ordering::SeqCst) {
+            debug!("The last full scan of pairs is still running.");
+            return;
+        }
+        let transport = transport.clone();
+        let erc20_abi = self.erc20_abi.clone();
+        let scanning = self.scanning.clone();
+        let flagged = self.flagged.clone();
+        tokio::spawn(async move {
+            let mut found = 0;
+            for batch in pairs.chunks(SKIM_BATCH_SIZE) {
+                let balances = futures::future::join_all(batch.iter().map(|(pair, tokens, _)| {
+                    pair_balances(&transport, &erc20_abi, *pair, *tokens, &block_number)
+                }))
+                .await;
+                for ((pair, _, reserves), balances) in batch.iter().zip(balances) {
+                    if balances
+                        .and_then(|balances| excess_reserves(*reserves, balances))
+                        .is_some()
+                    {
+                        flagged.lock().unwrap().insert(*pair);
+                        found += 1;
+                    }
+                }
+            }
+            info!(
+                "Full scan of {} pairs at block #{} found {} holding balances in excess of reserves.",
+                pairs.len(),
+                block_number,
+                found
+            );
+            scanning.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Compare the stored reserves of the pairs with their token balances
+    ///
+    /// Only pairs which received transfers in the block, or were flagged by a full scan, are
+    /// checked.
+    pub async fn evaluate_pairs<'a>(
+        &self,
+        markets: &'a MarketGraph,
+        transport: &Web3<WebSocket>,
+        block_number: &U64,
+    ) -> Vec<SkimDetails<'a>> {
+        let reserve_markets = self.reserve_markets(markets);
+        if self.full_scan_due(block_number) {
+            let pairs = reserve_markets
+                .values()
+                .map(|market| {
+                    (
+                        market.market_address(),
+                        market.tokens(),
+                        market.reserves().unwrap(),
+                    )
+                })
+                .collect();
+            self.spawn_full_scan(transport, pairs, *block_number);
+        }
+        let mut addresses = self.transfer_recipients(transport, block_number).await;
+        addresses.extend(self.flagged.lock().unwrap().drain());
+        let candidates: Vec<&dyn Market> = addresses
+            .iter()
+            .filter_map(|address| reserve_markets.get(address).copied())
+            .collect();
+        info!(
+            "Checking {} of {} pairs for balances in excess of reserves.",
+            candidates.len(),
+            reserve_markets.len()
+        );
+        let mut skims = vec![];
+        for batch in candidates.chunks(SKIM_BATCH_SIZE) {
+            let balances = futures::future::join_all(batch.iter().map(|market| {
+                pair_balances(
+                    transport,
+                    &self.erc20_abi,
+                    market.market_address(),
+                    market.tokens(),
+                    block_number,
+                )
+            }))
+            .await;
+            for (market, balances) in batch.iter().zip(balances) {
+                let (excess_i, excess_j) = match balances
+                    .and_then(|balances| excess_reserves(market.reserves().unwrap(), balances))
+                {
+                    Some(excess) => excess,
+                    None => continue,
+                };
+                let tokens = market.tokens();
+                let value = self.to_eth(markets, &tokens.i, &excess_i).0
+                    + self.to_eth(markets, &tokens.j, &excess_j).0;
+                debug!(
+                    "Pair {} holds {} and {} in excess of reserves, worth Ξ{}",
+                    market.market_address(),
+                    markets.tokens.format_amount(&tokens.i, &excess_i),
+                    markets.tokens.format_amount(&tokens.j, &excess_j),
+                    utilities::to_ether(&value)
+                );
+                if value > constants::FINNEY {
+                    // Checked again until it's skimmed
+                    self.flagged.lock().unwrap().insert(market.market_address());
+                    skims.push(SkimDetails {
+                        market: *market,
+                        excess_i,
+                        excess_j,
+                        value,
+                    })
+                }
+            }
+        }
+        skims.sort_by_key(|skim| std::cmp::Reverse(skim.value));
+        skims
+    }
+
+    pub async fn take_skim(
+        &self,
+        markets: &MarketGraph,
+        skim: &SkimDetails<'_>,
+        account: &Address,
+        gas_price: &GasPrice,
+    ) -> Option<(TransactionParameters, Bid)> {
+        let executor = self.bundle_executor_contract.address();
+        let raw_call = self
+            .pair_abi
+            .function("skim")
+            .ok()?
+            .encode_input(&[Token::Address(executor)])
+            .ok()?;
+        let mut calls = vec![Call::new(
+            skim.market.market_address(),
+            raw_call[0..4].to_vec(),
+            evm::Type::Call,
+            None,
+            raw_call[4..].to_vec(),
+        )];
+        // Swap any skimmed tokens other than weth back to weth
+        let tokens = skim.market.tokens();
+        for (token, excess) in [(tokens.i, skim.excess_i), (tokens.j, skim.excess_j)] {
+            if excess.is_zero() {
+                continue;
+            }
+            if let (_, Some(market)) = self.to_eth(markets, &token, &excess) {
+                if let Some(to_first_market) = market.to_first_market(&token, &excess).ok()? {
+                    calls.extend(to_first_market);
+                }
+                calls.extend(market.sell_tokens(&token, &excess, &executor).ok()?);
+            }
+        }
+        let bid = self.bidding.bid(&BidContext {
+            profit: skim.value,
+            gas: U256::from(SKIM_GAS),
+            gas_price,
+            route: &[skim.market.market_address()],
+            history: &markets.bids,
+        })?;
+        let miner_payment = gas_price.payment(bid.payment);
+        // The proceeds are all weth
+        let mch = MulticallHeader::new(true, false, miner_payment.coinbase_transfer().as_u128(), 0);
+        let params = Multicall::new(mch, calls).encode_parameters();
+        let tx = utilities::generate_contract_transaction(
+            &self.bundle_executor_contract,
+            "ostium",
+            params,
+            account,
+            true,
+            miner_payment,
+        )
+        .await?;
+        Some((tx, bid))
+    }
+}
+
+#[async_trait]
+impl BundleGenerator for SkimEngine {
+    async fn generate(
+        &self,
+        markets: &MarketGraph,
+        transport: &Web3<WebSocket>,
+        account: &Address,
+        gas_price: &GasPrice,
+        block_number: &U64,
+    ) -> Option<Bundle> {
+        let skims = self.evaluate_pairs(markets, transport, block_number).await;
+        // Take the most valuable skim
+        let skim = skims.first()?;
+        let (tx, bid) = self.take_skim(markets, skim, account, gas_price).await?;
+        let bundle = Bundle {
+            bundle_hash: None,
+            transactions: vec![Transaction {
+                raw_profit: skim.value,
+                taken_profit: skim.value - bid.payment,
+                delta_coinbase: gas_price.payment(bid.payment).coinbase_transfer(),
+                estimated_gas: tx.gas * U256::from(90) / U256::from(100),
+                parameters: tx,
+                signed: None,
+                signed_parameters: None,
+                external: false,
+                route: vec![skim.market.market_address()],
+                bid: Some(bid),
+            }],
+            block: *block_number,
+            base_fee: gas_price.base_fee,
+            executor: *account,
+        };
+        if bundle.effective_gas() > gas_price.low {
+            info!(
+                "Found skim of Ξ{} from pair {}",
+                utilities::to_ether(&skim.value),
+                skim.market.market_address()
+            );
+            return Some(bundle);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excess_over_reserves() {
+        let reserves = (U256::from(1000), U256::from(2000));
+        assert_eq!(
+            excess_reserves(reserves, (U256::from(1500), U256::from(2000))),
+            Some((U256::from(500), constants::ZERO_U256))
+        );
+        // A token short of its reserve, like after a rebase, holds no excess
+        assert_eq!(
+            excess_reserves(reserves, (U256::from(900), U256::from(2001))),
+            Some((constants::ZERO_U256, U256::one()))
+        );
+        assert_eq!(
+            excess_reserves(reserves, (U256::from(900), U256::from(2000))),
+            None
+        );
+    }
+}
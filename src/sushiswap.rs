@@ -24,7 +24,11 @@ impl MasterChef {
         MasterChef { contract }
     }
 
-    pub async fn pending_reward_balance(&self, pid: U256, address: Address) -> U256 {
+    pub async fn pending_reward_balance(
+        &self,
+        pid: U256,
+        address: Address,
+    ) -> web3::contract::Result<U256> {
         self.contract
             .query::<U256, _, _, _>(
                 "pendingSushi",
@@ -34,6 +38,5 @@ impl MasterChef {
                 None,
             )
             .await
-            .unwrap()
     }
 }
@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+
+use log::{debug, info, warn};
+use web3::ethabi;
+use web3::ethabi::Token;
+use web3::transports::WebSocket;
+use web3::types::{Address, CallRequest, U256};
+use web3::Web3;
+
+use crate::evm::{Call, Multicall, MulticallHeader};
+use crate::markets::{Market, MarketGraph};
+use crate::tokens::TokenFlags;
+use crate::{address_book, constants, evm};
+
+/// Number of tokens to check each block
+const CHECKS_PER_BLOCK: usize = 100;
+
+/// The outcome of a round trip through a token
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckResult {
+    // Delivers the quoted amounts both ways
+    Clean,
+    // Delivers less than the quote
+    FeeOnTransfer,
+    // Can be bought but not moved or sold
+    Honeypot,
+    // The buy itself failed, so nothing can be said about the token
+    Inconclusive,
+}
+
+impl CheckResult {
+    fn flags(&self) -> TokenFlags {
+        TokenFlags {
+            fee_on_transfer: *self == CheckResult::FeeOnTransfer,
+            blacklisted: *self == CheckResult::Honeypot,
+            ..TokenFlags::default()
+        }
+    }
+}
+
+/// Detects fee on transfer and honeypot tokens by simulating trades through them
+///
+/// Each token is bought from its deepest weth pair with a small amount of the executor's weth,
+/// all with eth_call, so nothing is ever sent. A token which can be bought but only moved in
+/// less than the quoted amount takes a fee on transfer, and one which can't be moved at all or
+/// can't be sold back to the pair is a honeypot. Results are recorded in the token registry,
+/// and honeypots are removed from the graph.
+pub struct TokenChecker {
+    executor_abi: ethabi::Contract,
+    erc20_abi: ethabi::Contract,
+    executor: Address,
+    weth: Address,
+    // Tokens checked this run, including those with inconclusive results
+    attempted: HashSet<Address>,
+}
+
+impl TokenChecker {
+    pub fn new() -> TokenChecker {
+        TokenChecker {
+            executor_abi: ethabi::Contract::load(&include_bytes!("abis/Multicall.json")[..])
+                .unwrap(),
+            erc20_abi: ethabi::Contract::load(&include_bytes!("abis/IERC20.json")[..]).unwrap(),
+            executor: address_book::MulticallEXECUTOR.parse().unwrap(),
+            weth: address_book::WETH_ADDRESS.parse().unwrap(),
+            attempted: HashSet::new(),
+        }
+    }
+
+    /// The weth pair with the most weth in reserve
+    fn weth_pair<'a>(&self, markets: &'a MarketGraph, token: &Address) -> Option<&'a dyn Market> {
+        markets
+            .graph
+            .edge_weight(self.weth, *token)?
+            .markets
+            .iter()
+            .filter_map(|market| {
+                let (reserve_i, reserve_j) = market.reserves()?;
+                let weth_reserve = if market.tokens().i.0 == self.weth.0 {
+                    reserve_i
+                } else {
+                    reserve_j
+                };
+                Some((market, weth_reserve))
+            })
+            .max_by_key(|(_, weth_reserve)| *weth_reserve)
+            .map(|(market, _)| market.as_ref())
+    }
+
+    /// Run the calls through the executor with eth_call, returning whether they succeeded
+    ///
+    /// Only an error from the node is a revert, a failed request says nothing about the token.
+    async fn simulate(
+        &self,
+        transport: &Web3<WebSocket>,
+        account: &Address,
+        calls: Vec<Call>,
+    ) -> Option<bool> {
+        let params =
+            Multicall::new(MulticallHeader::new(false, false, 0, 0), calls).encode_parameters();
+        let data = match self.executor_abi.function("ostium").and_then(|function| {
+            function.encode_input(&[Token::Array(params.into_iter().map(Token::Uint).collect())])
+        }) {
+            Ok(data) => data,
+            Err(_) => return None,
+        };
+        let result = transport
+            .eth()
+            .call(
+                CallRequest {
+                    from: Some(*account),
+                    to: Some(self.executor),
+                    gas_price: Some(constants::ZERO_U256),
+                    data: Some(data.into()),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await;
+        match result {
+            Ok(_) => Some(true),
+            Err(web3::Error::Rpc(_)) => Some(false),
+            Err(error) => {
+                debug!("Failed to simulate token check: {:?}", error);
+                None
+            }
+        }
+    }
+
+    fn transfer(&self, token: &Address, recipient: &Address, amount: &U256) -> Option<Call> {
+        let raw_call = self
+            .erc20_abi
+            .function("transfer")
+            .ok()?
+            .encode_input(&[Token::Address(*recipient), Token::Uint(*amount)])
+            .ok()?;
+        Some(Call::new(
+            *token,
+            raw_call[0..4].to_vec(),
+            evm::Type::Call,
+            None,
+            raw_call[4..].to_vec(),
+        ))
+    }
+
+    /// Buy a small amount of the token, move it, then sell it back
+    pub async fn check(
+        &self,
+        markets: &MarketGraph,
+        transport: &Web3<WebSocket>,
+        account: &Address,
+        token: &Address,
+    ) -> CheckResult {
+        let pair = match self.weth_pair(markets, token) {
+            Some(pair) => pair,
+            None => return CheckResult::Inconclusive,
+        };
+        let amount_in = constants::FINNEY;
+        let bought = pair.get_tokens_out(&self.weth, token, &amount_in);
+        if bought.is_zero() {
+            return CheckResult::Inconclusive;
+        }
+        let buy = || -> Option<Vec<Call>> {
+            let mut calls = pair.to_first_market(&self.weth, &amount_in).ok()??;
+            calls.extend(
+                pair.sell_tokens(&self.weth, &amount_in, &self.executor)
+                    .ok()?,
+            );
+            Some(calls)
+        };
+        // Moving the amount bought fails if less than the quote arrived
+        let buy_and_move = |amount: &U256| -> Option<Vec<Call>> {
+            let mut calls = buy()?;
+            calls.push(self.transfer(token, account, amount)?);
+            Some(calls)
+        };
+        // Selling back is quoted against the reserves left by the buy
+        let round_trip = || -> Option<Vec<Call>> {
+            let bought_pair = pair.with_swap(&self.weth, &amount_in)?;
+            let mut calls = buy()?;
+            calls.extend(bought_pair.to_first_market(token, &bought).ok()??);
+            calls.extend(
+                bought_pair
+                    .sell_tokens(token, &bought, &self.executor)
+                    .ok()?,
+            );
+            Some(calls)
+        };
+        // Half of the amount bought still arrives if the token only takes a fee
+        let (buy, buy_and_move, buy_and_move_half, round_trip) = match (
+            buy(),
+            buy_and_move(&bought),
+            buy_and_move(&(bought / 2)),
+            round_trip(),
+        ) {
+            (Some(buy), Some(buy_and_move), Some(buy_and_move_half), Some(round_trip)) => {
+                (buy, buy_and_move, buy_and_move_half, round_trip)
+            }
+            _ => return CheckResult::Inconclusive,
+        };
+        let simulate = |calls| self.simulate(transport, account, calls);
+        let result = async {
+            Some(if !simulate(buy).await? {
+                CheckResult::Inconclusive
+            } else if !simulate(buy_and_move).await? {
+                if simulate(buy_and_move_half).await? {
+                    CheckResult::FeeOnTransfer
+                } else {
+                    // Bought tokens which can't be moved at all can't be sold either
+                    CheckResult::Honeypot
+                }
+            } else if !simulate(round_trip).await? {
+                CheckResult::Honeypot
+            } else {
+                CheckResult::Clean
+            })
+        };
+        // Without an answer from the node, nothing is recorded against the token
+        result.await.unwrap_or(CheckResult::Inconclusive)
+    }
+
+    /// Check a batch of unchecked tokens, recording the results and removing honeypots
+    pub async fn check_tokens(
+        &mut self,
+        markets: &mut MarketGraph,
+        transport: &Web3<WebSocket>,
+        account: &Address,
+    ) {
+        let unchecked: Vec<Address> = markets
+            .tokens
+            .unchecked()
+            .into_iter()
+            .filter(|token| {
+                !self.attempted.contains(token) && markets.graph.contains_edge(self.weth, *token)
+            })
+            .take(CHECKS_PER_BLOCK)
+            .collect();
+        if unchecked.is_empty() {
+            return;
+        }
+        let results = futures::future::join_all(
+            unchecked
+                .iter()
+                .map(|token| self.check(markets, transport, account, token)),
+        )
+        .await;
+        self.attempted.extend(unchecked.iter().copied());
+        let mut flagged = 0;
+        for (token, result) in unchecked.iter().zip(results) {
+            debug!("Round trip check of {}: {:?}", token, result);
+            match result {
+                CheckResult::Inconclusive => continue,
+                CheckResult::Clean => (),
+                CheckResult::FeeOnTransfer => flagged += 1,
+                CheckResult::Honeypot => {
+                    flagged += 1;
+                    markets.remove_token(token);
+                }
+            }
+            markets.tokens.record_check(token, result.flags());
+        }
+        info!(
+            "Checked {} tokens, {} flagged as fee on transfer or honeypots.",
+            unchecked.len(),
+            flagged
+        );
+        if let Err(error) = markets.tokens.save() {
+            warn!("Failed to persist token registry: {:?}", error);
+        }
+    }
+}
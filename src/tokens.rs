@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use bigdecimal::BigDecimal;
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use web3::ethabi;
+use web3::ethabi::ParamType;
+use web3::transports::WebSocket;
+use web3::types::{Address, CallRequest, U256};
+use web3::Web3;
+
+use crate::{address_book, utilities};
+
+/// Number of metadata queries to run concurrently
+const METADATA_BATCH_SIZE: usize = 250;
+
+/// Token behaviors which break the assumptions of quoting
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TokenFlags {
+    // Pairs receive less than was sent
+    pub fee_on_transfer: bool,
+    // Balances change without transfers
+    pub rebasing: bool,
+    // Transfers can be paused or frozen by an admin
+    pub pausable: bool,
+    // Not to be traded
+    pub blacklisted: bool,
+}
+
+/// Metadata about an ERC20 token
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenInfo {
+    pub decimals: u32,
+    pub symbol: String,
+    pub name: String,
+    pub flags: TokenFlags,
+    // Has the token been through a round trip check
+    pub checked: bool,
+}
+
+/// Sets a flag from a list of known tokens
+type FlagSetter = fn(&mut TokenFlags);
+
+/// Token metadata for every token in the market graph, persisted between runs
+#[derive(Debug, Default)]
+pub struct TokenRegistry {
+    tokens: HashMap<Address, TokenInfo>,
+    path: Option<PathBuf>,
+}
+
+/// Decode a string returned by a call, which some older tokens return as bytes32
+fn decode_string(data: &[u8]) -> Option<String> {
+    if data.len() == 32 {
+        let end = data.iter().position(|byte| *byte == 0).unwrap_or(32);
+        return String::from_utf8(data[..end].to_vec()).ok();
+    }
+    ethabi::decode(&[ParamType::String], data)
+        .ok()?
+        .pop()?
+        .into_string()
+}
+
+impl TokenRegistry {
+    /// An empty registry which is never persisted
+    pub fn new() -> TokenRegistry {
+        TokenRegistry::default()
+    }
+
+    /// Load the registry persisted at the path, if there is one
+    pub fn load(path: &Path) -> Result<TokenRegistry> {
+        let mut registry = TokenRegistry {
+            tokens: HashMap::new(),
+            path: Some(path.to_path_buf()),
+        };
+        if !path.exists() {
+            return Ok(registry);
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read token registry {}", path.display()))?;
+        let value: Value = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse token registry {}", path.display()))?;
+        let entries = value
+            .as_object()
+            .context("Token registry is not a JSON object")?;
+        for (address, entry) in entries {
+            let address: Address = match address.parse() {
+                Ok(address) => address,
+                Err(_) => continue,
+            };
+            let flag = |name: &str| entry[name].as_bool().unwrap_or(false);
+            registry.tokens.insert(
+                address,
+                TokenInfo {
+                    decimals: entry["decimals"].as_u64().unwrap_or(18) as u32,
+                    symbol: entry["symbol"].as_str().unwrap_or_default().to_string(),
+                    name: entry["name"].as_str().unwrap_or_default().to_string(),
+                    flags: TokenFlags {
+                        fee_on_transfer: flag("fee_on_transfer"),
+                        rebasing: flag("rebasing"),
+                        pausable: flag("pausable"),
+                        blacklisted: flag("blacklisted"),
+                    },
+                    checked: flag("checked"),
+                },
+            );
+        }
+        Ok(registry)
+    }
+
+    /// Persist the registry, if it was loaded from a path
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut entries = Map::new();
+        for (address, token) in self.tokens.iter() {
+            entries.insert(
+                format!("{:?}", address),
+                json!({
+                    "decimals": token.decimals,
+                    "symbol": token.symbol,
+                    "name": token.name,
+                    "fee_on_transfer": token.flags.fee_on_transfer,
+                    "rebasing": token.flags.rebasing,
+                    "pausable": token.flags.pausable,
+                    "blacklisted": token.flags.blacklisted,
+                    "checked": token.checked,
+                }),
+            );
+        }
+        std::fs::write(path, serde_json::to_string_pretty(&Value::Object(entries))?)
+            .with_context(|| format!("Failed to write token registry {}", path.display()))
+    }
+
+    async fn call(
+        transport: &Web3<WebSocket>,
+        erc20_abi: &ethabi::Contract,
+        token: Address,
+        function: &str,
+    ) -> Option<Vec<u8>> {
+        let data = erc20_abi.function(function).ok()?.encode_input(&[]).ok()?;
+        transport
+            .eth()
+            .call(
+                CallRequest {
+                    to: Some(token),
+                    data: Some(data.into()),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .ok()
+            .map(|result| result.0)
+    }
+
+    async fn fetch(
+        transport: &Web3<WebSocket>,
+        erc20_abi: &ethabi::Contract,
+        token: Address,
+    ) -> TokenInfo {
+        let (decimals, symbol, name) = futures::future::join3(
+            TokenRegistry::call(transport, erc20_abi, token, "decimals"),
+            TokenRegistry::call(transport, erc20_abi, token, "symbol"),
+            TokenRegistry::call(transport, erc20_abi, token, "name"),
+        )
+        .await;
+        let decimals = match decimals {
+            Some(decimals) if decimals.len() >= 32 => {
+                U256::from_big_endian(&decimals[..32]).low_u32()
+            }
+            // Tokens without decimals are most likely 18 decimals
+            _ => 18,
+        };
+        TokenInfo {
+            decimals,
+            symbol: symbol
+                .as_deref()
+                .and_then(decode_string)
+                .unwrap_or_default(),
+            name: name.as_deref().and_then(decode_string).unwrap_or_default(),
+            flags: TokenFlags::default(),
+            checked: false,
+        }
+    }
+
+    /// Fetch metadata for the tokens not yet in the registry, and apply the known flags
+    pub async fn update(&mut self, transport: &Web3<WebSocket>, tokens: &[Address]) {
+        let erc20_abi = ethabi::Contract::load(&include_bytes!("abis/IERC20.json")[..]).unwrap();
+        let eth: Address = address_book::ETH_ADDRESS.parse().unwrap();
+        let missing: Vec<Address> = tokens
+            .iter()
+            .filter(|token| !self.tokens.contains_key(token))
+            .copied()
+            .collect();
+        info!("Fetching metadata for {} tokens.", missing.len());
+        for batch in missing.chunks(METADATA_BATCH_SIZE) {
+            let infos = futures::future::join_all(
+                batch
+                    .iter()
+                    .map(|token| TokenRegistry::fetch(transport, &erc20_abi, *token)),
+            )
+            .await;
+            for (token, info) in batch.iter().zip(infos) {
+                self.tokens.insert(*token, info);
+            }
+        }
+        // Ether isn't a token contract
+        if let Some(info) = self.tokens.get_mut(&eth) {
+            info.decimals = 18;
+            info.symbol = "ETH".to_string();
+            info.name = "Ether".to_string();
+        }
+        let known_flags: [(&[&str], FlagSetter); 4] = [
+            (address_book::FEE_ON_TRANSFER_TOKENS, |flags| {
+                flags.fee_on_transfer = true
+            }),
+            (address_book::REBASING_TOKENS, |flags| flags.rebasing = true),
+            (address_book::PAUSABLE_TOKENS, |flags| flags.pausable = true),
+            (address_book::BLACKLISTED_TOKENS, |flags| {
+                flags.blacklisted = true
+            }),
+        ];
+        for (list, set_flag) in known_flags.iter() {
+            for token in list.iter() {
+                let token: Address = token.parse().unwrap();
+                if let Some(info) = self.tokens.get_mut(&token) {
+                    set_flag(&mut info.flags);
+                }
+            }
+        }
+        if let Err(error) = self.save() {
+            warn!("Failed to persist token registry: {:?}", error);
+        }
+    }
+
+    /// Tokens which have not been through a round trip check
+    pub fn unchecked(&self) -> Vec<Address> {
+        self.tokens
+            .iter()
+            .filter(|(_, info)| !info.checked)
+            .map(|(token, _)| *token)
+            .collect()
+    }
+
+    /// Record the result of a round trip check, adding any flags found
+    pub fn record_check(&mut self, token: &Address, flags: TokenFlags) {
+        if let Some(info) = self.tokens.get_mut(token) {
+            info.checked = true;
+            info.flags.fee_on_transfer |= flags.fee_on_transfer;
+            info.flags.blacklisted |= flags.blacklisted;
+        }
+    }
+
+    pub fn decimals(&self, token: &Address) -> Option<u32> {
+        self.tokens.get(token).map(|info| info.decimals)
+    }
+
+    pub fn flags(&self, token: &Address) -> TokenFlags {
+        self.tokens
+            .get(token)
+            .map(|info| info.flags)
+            .unwrap_or_default()
+    }
+
+    /// Can the token be quoted from reserves?
+    ///
+    /// Fee on transfer and rebasing tokens deliver amounts other than the quote.
+    pub fn quotable(&self, token: &Address) -> bool {
+        let flags = self.flags(token);
+        !(flags.fee_on_transfer || flags.rebasing || flags.blacklisted)
+    }
+
+    /// The symbol of the token, or its address if it has none
+    pub fn symbol(&self, token: &Address) -> String {
+        match self.tokens.get(token) {
+            Some(info) if !info.symbol.is_empty() => info.symbol.clone(),
+            _ => format!("{:?}", token),
+        }
+    }
+
+    /// Format an amount of the token in whole tokens, with its symbol
+    pub fn format_amount(&self, token: &Address, amount: &U256) -> String {
+        let decimals = self.decimals(token).unwrap_or(18);
+        let whole = BigDecimal::from(utilities::u256_bigint(U256::exp10(decimals as usize)));
+        let amount = utilities::bigint_bigdecimal(utilities::u256_bigint(*amount));
+        format!("{} {}", amount / whole, self.symbol(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_string_and_bytes32() {
+        let encoded = ethabi::encode(&[ethabi::Token::String("USDC".to_string())]);
+        assert_eq!(decode_string(&encoded), Some("USDC".to_string()));
+        let mut bytes32 = [0_u8; 32];
+        bytes32[..3].copy_from_slice(b"MKR");
+        assert_eq!(decode_string(&bytes32), Some("MKR".to_string()));
+        assert_eq!(decode_string(&[]), None);
+    }
+
+    #[test]
+    fn format_amount_by_decimals() {
+        let usdc: Address = address_book::USDC_ADDRESS.parse().unwrap();
+        let mut registry = TokenRegistry::new();
+        registry.tokens.insert(
+            usdc,
+            TokenInfo {
+                decimals: 6,
+                symbol: "USDC".to_string(),
+                name: "USD Coin".to_string(),
+                flags: TokenFlags::default(),
+                checked: false,
+            },
+        );
+        assert_eq!(
+            registry.format_amount(&usdc, &U256::from(1_500_000)),
+            "1.5 USDC"
+        );
+        // Unknown tokens are assumed to have 18 decimals
+        let unknown = Address::repeat_byte(1);
+        assert_eq!(
+            registry.format_amount(&unknown, &U256::exp10(18)),
+            format!("1 {:?}", unknown)
+        );
+    }
+
+    #[test]
+    fn persist_checked_flags() {
+        // Unique to the run, so concurrent runs of the tests don't share a registry
+        let path = std::env::temp_dir().join(format!(
+            "bundle-generator-token-registry-test-{}.json",
+            std::process::id()
+        ));
+        let token = Address::repeat_byte(2);
+        let mut registry = TokenRegistry::load(&path).unwrap();
+        registry.tokens.insert(
+            token,
+            TokenInfo {
+                decimals: 9,
+                symbol: "FOT".to_string(),
+                name: "Fee On Transfer".to_string(),
+                flags: TokenFlags::default(),
+                checked: false,
+            },
+        );
+        assert_eq!(registry.unchecked(), vec![token]);
+        registry.record_check(
+            &token,
+            TokenFlags {
+                fee_on_transfer: true,
+                ..TokenFlags::default()
+            },
+        );
+        registry.save().unwrap();
+        let loaded = TokenRegistry::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.unchecked().is_empty());
+        assert_eq!(loaded.decimals(&token), Some(9));
+        assert!(loaded.flags(&token).fee_on_transfer);
+        assert!(!loaded.quotable(&token));
+    }
+}
@@ -8,13 +8,14 @@ use web3::api::Eth;
 use web3::contract::tokens::Tokenize;
 use web3::contract::{Contract, Options};
 use web3::ethabi::Uint;
+use web3::signing::keccak256;
 use web3::transports::WebSocket;
-use web3::types::{Address, BlockId, BlockNumber, U256};
+use web3::types::{Address, BlockId, BlockNumber, H256, U256};
 use web3::Web3;
 
 use crate::evm::Call;
 use crate::markets::{Market, Protocol, TokenPair};
-use crate::{address_book, constants, evm, markets};
+use crate::{address_book, constants, evm, markets, utilities};
 use std::sync::{Arc, Mutex};
 
 const BATCH_COUNT_LIMIT: u32 = 250;
@@ -93,6 +94,31 @@ impl UniswapV2Pair {
         }
     }
 
+    /// Derive the address of the pair for two tokens from the factory
+    pub fn pair_for(
+        factory: &Address,
+        init_code_hash: &H256,
+        token_a: &Address,
+        token_b: &Address,
+    ) -> Address {
+        let (token_0, token_1) = if token_a < token_b {
+            (token_a, token_b)
+        } else {
+            (token_b, token_a)
+        };
+        let salt = keccak256(&[token_0.as_bytes(), token_1.as_bytes()].concat());
+        let hash = keccak256(
+            &[
+                &[0xff],
+                factory.as_bytes(),
+                &salt,
+                init_code_hash.as_bytes(),
+            ]
+            .concat(),
+        );
+        Address::from_slice(&hash[12..])
+    }
+
     pub fn get_amount_in(reserve_in: &U256, reserve_out: &U256, amount_out: &U256) -> U256 {
         if reserve_out < amount_out {
             // Catch overflow
@@ -243,6 +269,19 @@ impl UniswapV2Pair {
         let all_pairs: Vec<UniswapV2Pair> = all_pairs.into_iter().flatten().collect();
         Ok(all_pairs)
     }
+
+    /// The amount0Out and amount1Out of a swap of amount_in of token_in
+    fn amounts_out(&self, token_in: &Address, amount_in: &U256) -> Result<(U256, U256)> {
+        if token_in.0 == self.tokens.i.0 {
+            let amount_1_out = self.get_tokens_out(token_in, &self.tokens.j, amount_in);
+            Ok((constants::ZERO_U256, amount_1_out))
+        } else if token_in.0 == self.tokens.j.0 {
+            let amount_0_out = self.get_tokens_out(token_in, &self.tokens.i, amount_in);
+            Ok((amount_0_out, constants::ZERO_U256))
+        } else {
+            Err(Error::from(markets::TokenInputError::InvalidToken))
+        }
+    }
 }
 
 #[async_trait]
@@ -263,8 +302,28 @@ impl Market for UniswapV2Pair {
         self.protocol
     }
 
-    fn miner_reward_percentage(&self) -> Option<U256> {
-        Some(U256::from(99))
+    fn reserves(&self) -> Option<(U256, U256)> {
+        Some((
+            self.token_balances[&self.tokens.i],
+            self.token_balances[&self.tokens.j],
+        ))
+    }
+
+    fn with_swap(&self, token_in: &Address, amount_in: &U256) -> Option<Box<dyn Market>> {
+        let token_out = if token_in.0 == self.tokens.i.0 {
+            self.tokens.j
+        } else if token_in.0 == self.tokens.j.0 {
+            self.tokens.i
+        } else {
+            return None;
+        };
+        let amount_out = self.get_tokens_out(token_in, &token_out, amount_in);
+        let mut pair = self.clone();
+        pair.token_balances
+            .insert(*token_in, self.token_balances[token_in] + amount_in);
+        pair.token_balances
+            .insert(token_out, self.token_balances[&token_out] - amount_out);
+        Some(Box::new(pair))
     }
 
     fn get_tokens_out(&self, token_in: &Address, token_out: &Address, amount_in: &U256) -> U256 {
@@ -285,17 +344,7 @@ impl Market for UniswapV2Pair {
         amount_in: &U256,
         recipient: &Address,
     ) -> Result<Vec<Call>> {
-        let mut amount_0_out = constants::ZERO_U256;
-        let mut amount_1_out = constants::ZERO_U256;
-        if token_in.0 == self.tokens.i.0 {
-            let token_out = self.tokens.j;
-            amount_1_out = self.get_tokens_out(token_in, &token_out, amount_in);
-        } else if token_in.0 == self.tokens.j.0 {
-            let token_out = self.tokens.i;
-            amount_0_out = self.get_tokens_out(token_in, &token_out, amount_in);
-        } else {
-            return Err(Error::from(markets::TokenInputError::InvalidToken));
-        }
+        let (amount_0_out, amount_1_out) = self.amounts_out(token_in, amount_in)?;
         let data: Vec<u8> = vec![];
         let params = (amount_0_out, amount_1_out, *recipient, data);
         let raw_call = self
@@ -316,6 +365,21 @@ impl Market for UniswapV2Pair {
         Ok(vec![calls])
     }
 
+    fn flash_sell_tokens(&self, token_in: &Address, amount_in: &U256) -> Result<Option<Vec<Call>>> {
+        let (amount_0_out, amount_1_out) = self.amounts_out(token_in, amount_in)?;
+        // The executor calls swap with the rest of the program as data, and repays in the callback
+        let params = (amount_0_out, amount_1_out, *token_in, *amount_in);
+        let method = utilities::selector(self.uniswap_interface.abi().function("swap")?).to_vec();
+        let payload = web3::ethabi::encode(&params.into_tokens());
+        Ok(Some(vec![Call::new(
+            self.uniswap_interface.address(),
+            method,
+            evm::Type::FlashSwap,
+            None,
+            payload,
+        )]))
+    }
+
     async fn update(&mut self) {
         let reserves: (Uint, Uint, Uint) = self
             .uniswap_interface
@@ -365,3 +429,24 @@ impl Market for UniswapV2Pair {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_for_weth_usdc() {
+        let pair = UniswapV2Pair::pair_for(
+            &address_book::UNISWAP_FACTORY_ADDRESS.parse().unwrap(),
+            &address_book::UNISWAP_INIT_CODE_HASH.parse().unwrap(),
+            &address_book::WETH_ADDRESS.parse().unwrap(),
+            &address_book::USDC_ADDRESS.parse().unwrap(),
+        );
+        assert_eq!(
+            pair,
+            "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"
+                .parse::<Address>()
+                .unwrap()
+        )
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+
+use web3::ethabi;
+use web3::ethabi::Token;
+use web3::types::{Address, H256, U256};
+
+use crate::markets::{Market, MarketGraph};
+use crate::uniswap::UniswapV2Pair;
+use crate::{address_book, utilities};
+
+/// The exact side of a swap, and the limit on the other side
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SwapAmount {
+    ExactIn {
+        amount_in: U256,
+        amount_out_min: U256,
+    },
+    ExactOut {
+        amount_out: U256,
+        amount_in_max: U256,
+    },
+}
+
+/// A swap through a Uniswap V2 router
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwapIntent {
+    pub router: Address,
+    // Tokens swapped through, with weth standing in for eth
+    pub path: Vec<Address>,
+    // The pair for each hop of the path
+    pub pairs: Vec<Address>,
+    pub amount: SwapAmount,
+    pub eth_in: bool,
+    pub eth_out: bool,
+    pub recipient: Address,
+    pub deadline: U256,
+    pub fee_on_transfer: bool,
+}
+
+impl SwapIntent {
+    /// Resolve the pairs of the path to markets in the graph
+    pub fn markets<'a>(&self, markets: &'a MarketGraph) -> Option<Vec<&'a dyn Market>> {
+        self.path
+            .windows(2)
+            .zip(self.pairs.iter())
+            .map(|(hop, pair)| markets.find_market(&hop[0], &hop[1], pair))
+            .collect()
+    }
+}
+
+/// The amounts of a liquidity change
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LiquidityAmount {
+    Add {
+        amount_a_desired: U256,
+        amount_b_desired: U256,
+        amount_a_min: U256,
+        amount_b_min: U256,
+    },
+    Remove {
+        liquidity: U256,
+        amount_a_min: U256,
+        amount_b_min: U256,
+    },
+}
+
+/// A liquidity change through a Uniswap V2 router
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiquidityIntent {
+    pub router: Address,
+    // Token b is weth for the eth variants
+    pub token_a: Address,
+    pub token_b: Address,
+    pub pair: Address,
+    pub amount: LiquidityAmount,
+    pub recipient: Address,
+    pub deadline: U256,
+    pub fee_on_transfer: bool,
+}
+
+impl LiquidityIntent {
+    /// Resolve the pair to a market in the graph
+    pub fn market<'a>(&self, markets: &'a MarketGraph) -> Option<&'a dyn Market> {
+        markets.find_market(&self.token_a, &self.token_b, &self.pair)
+    }
+}
+
+/// A decoded router call
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouterIntent {
+    Swap(SwapIntent),
+    Liquidity(LiquidityIntent),
+}
+
+/// Decodes calldata for UniswapV2Router02 and its forks
+pub struct RouterDecoder {
+    abi: ethabi::Contract,
+    // Selector -> function name
+    selectors: HashMap<[u8; 4], String>,
+    // Router -> (factory, pair init code hash)
+    routers: HashMap<Address, (Address, H256)>,
+    weth: Address,
+}
+
+impl RouterDecoder {
+    pub fn new() -> RouterDecoder {
+        let abi =
+            ethabi::Contract::load(&include_bytes!("protocols/uniswap/v2/abis/router.json")[..])
+                .unwrap();
+        let selectors = abi
+            .functions()
+            .map(|function| (utilities::selector(function), function.name.clone()))
+            .collect();
+        let routers = address_book::ROUTERS
+            .iter()
+            .map(|(router, factory, init_code_hash)| {
+                (
+                    router.parse().unwrap(),
+                    (factory.parse().unwrap(), init_code_hash.parse().unwrap()),
+                )
+            })
+            .collect();
+        RouterDecoder {
+            abi,
+            selectors,
+            routers,
+            weth: address_book::WETH_ADDRESS.parse().unwrap(),
+        }
+    }
+
+    pub fn is_router(&self, address: &Address) -> bool {
+        self.routers.contains_key(address)
+    }
+
+    /// Decode a call to a router, with the eth value sent
+    pub fn decode(&self, router: &Address, input: &[u8], value: U256) -> Option<RouterIntent> {
+        let (factory, init_code_hash) = self.routers.get(router)?;
+        if input.len() < 4 {
+            return None;
+        }
+        let name = self.selectors.get(&input[0..4])?;
+        let params = self
+            .abi
+            .function(name)
+            .ok()?
+            .decode_input(&input[4..])
+            .ok()?;
+        let uint = |index: usize| params.get(index).cloned()?.into_uint();
+        let address = |index: usize| params.get(index).cloned()?.into_address();
+        let path = |index: usize| -> Option<Vec<Address>> {
+            params
+                .get(index)
+                .cloned()?
+                .into_array()?
+                .into_iter()
+                .map(Token::into_address)
+                .collect()
+        };
+        let pair_for = |token_a: &Address, token_b: &Address| {
+            UniswapV2Pair::pair_for(factory, init_code_hash, token_a, token_b)
+        };
+        let fee_on_transfer = name.ends_with("SupportingFeeOnTransferTokens");
+        // The recipient and deadline follow the path
+        let swap = |path_index: usize, amount: SwapAmount, eth_in: bool, eth_out: bool| {
+            let path = path(path_index)?;
+            Some(RouterIntent::Swap(SwapIntent {
+                router: *router,
+                pairs: path
+                    .windows(2)
+                    .map(|hop| pair_for(&hop[0], &hop[1]))
+                    .collect(),
+                path,
+                amount,
+                eth_in,
+                eth_out,
+                recipient: address(path_index + 1)?,
+                deadline: uint(path_index + 2)?,
+                fee_on_transfer,
+            }))
+        };
+        let base = name.trim_end_matches("SupportingFeeOnTransferTokens");
+        match base {
+            "swapExactTokensForTokens" | "swapExactTokensForETH" => swap(
+                2,
+                SwapAmount::ExactIn {
+                    amount_in: uint(0)?,
+                    amount_out_min: uint(1)?,
+                },
+                false,
+                base.ends_with("ETH"),
+            ),
+            "swapExactETHForTokens" => swap(
+                1,
+                SwapAmount::ExactIn {
+                    amount_in: value,
+                    amount_out_min: uint(0)?,
+                },
+                true,
+                false,
+            ),
+            "swapTokensForExactTokens" | "swapTokensForExactETH" => swap(
+                2,
+                SwapAmount::ExactOut {
+                    amount_out: uint(0)?,
+                    amount_in_max: uint(1)?,
+                },
+                false,
+                base.ends_with("ETH"),
+            ),
+            "swapETHForExactTokens" => swap(
+                1,
+                SwapAmount::ExactOut {
+                    amount_out: uint(0)?,
+                    amount_in_max: value,
+                },
+                true,
+                false,
+            ),
+            "addLiquidity" => {
+                let (token_a, token_b) = (address(0)?, address(1)?);
+                Some(RouterIntent::Liquidity(LiquidityIntent {
+                    router: *router,
+                    token_a,
+                    token_b,
+                    pair: pair_for(&token_a, &token_b),
+                    amount: LiquidityAmount::Add {
+                        amount_a_desired: uint(2)?,
+                        amount_b_desired: uint(3)?,
+                        amount_a_min: uint(4)?,
+                        amount_b_min: uint(5)?,
+                    },
+                    recipient: address(6)?,
+                    deadline: uint(7)?,
+                    fee_on_transfer,
+                }))
+            }
+            "addLiquidityETH" => {
+                let token_a = address(0)?;
+                Some(RouterIntent::Liquidity(LiquidityIntent {
+                    router: *router,
+                    token_a,
+                    token_b: self.weth,
+                    pair: pair_for(&token_a, &self.weth),
+                    amount: LiquidityAmount::Add {
+                        amount_a_desired: uint(1)?,
+                        amount_b_desired: value,
+                        amount_a_min: uint(2)?,
+                        amount_b_min: uint(3)?,
+                    },
+                    recipient: address(4)?,
+                    deadline: uint(5)?,
+                    fee_on_transfer,
+                }))
+            }
+            "removeLiquidity" | "removeLiquidityWithPermit" => {
+                let (token_a, token_b) = (address(0)?, address(1)?);
+                Some(RouterIntent::Liquidity(LiquidityIntent {
+                    router: *router,
+                    token_a,
+                    token_b,
+                    pair: pair_for(&token_a, &token_b),
+                    amount: LiquidityAmount::Remove {
+                        liquidity: uint(2)?,
+                        amount_a_min: uint(3)?,
+                        amount_b_min: uint(4)?,
+                    },
+                    recipient: address(5)?,
+                    deadline: uint(6)?,
+                    fee_on_transfer,
+                }))
+            }
+            "removeLiquidityETH" | "removeLiquidityETHWithPermit" => {
+                let token_a = address(0)?;
+                Some(RouterIntent::Liquidity(LiquidityIntent {
+                    router: *router,
+                    token_a,
+                    token_b: self.weth,
+                    pair: pair_for(&token_a, &self.weth),
+                    amount: LiquidityAmount::Remove {
+                        liquidity: uint(1)?,
+                        amount_a_min: uint(2)?,
+                        amount_b_min: uint(3)?,
+                    },
+                    recipient: address(4)?,
+                    deadline: uint(5)?,
+                    fee_on_transfer,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(decoder: &RouterDecoder, name: &str, params: &[Token]) -> Vec<u8> {
+        decoder
+            .abi
+            .function(name)
+            .unwrap()
+            .encode_input(params)
+            .unwrap()
+    }
+
+    #[test]
+    fn decode_swap_exact_tokens_for_tokens() {
+        let decoder = RouterDecoder::new();
+        let router: Address = address_book::UNISWAP_ROUTER.parse().unwrap();
+        let weth: Address = address_book::WETH_ADDRESS.parse().unwrap();
+        let usdc: Address = address_book::USDC_ADDRESS.parse().unwrap();
+        let recipient = Address::repeat_byte(0x11);
+        let input = encode(
+            &decoder,
+            "swapExactTokensForTokens",
+            &[
+                Token::Uint(U256::from(1000)),
+                Token::Uint(U256::from(900)),
+                Token::Array(vec![Token::Address(weth), Token::Address(usdc)]),
+                Token::Address(recipient),
+                Token::Uint(U256::from(1234)),
+            ],
+        );
+        let intent = decoder.decode(&router, &input, U256::zero()).unwrap();
+        assert_eq!(
+            intent,
+            RouterIntent::Swap(SwapIntent {
+                router,
+                path: vec![weth, usdc],
+                pairs: vec!["0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"
+                    .parse()
+                    .unwrap()],
+                amount: SwapAmount::ExactIn {
+                    amount_in: U256::from(1000),
+                    amount_out_min: U256::from(900),
+                },
+                eth_in: false,
+                eth_out: false,
+                recipient,
+                deadline: U256::from(1234),
+                fee_on_transfer: false,
+            })
+        )
+    }
+
+    #[test]
+    fn decode_swap_exact_eth_supporting_fee_on_transfer() {
+        let decoder = RouterDecoder::new();
+        let router: Address = address_book::SUSHI_ROUTER.parse().unwrap();
+        let weth: Address = address_book::WETH_ADDRESS.parse().unwrap();
+        let dai: Address = address_book::DAI_ADDRESS.parse().unwrap();
+        let input = encode(
+            &decoder,
+            "swapExactETHForTokensSupportingFeeOnTransferTokens",
+            &[
+                Token::Uint(U256::from(5)),
+                Token::Array(vec![Token::Address(weth), Token::Address(dai)]),
+                Token::Address(Address::repeat_byte(0x22)),
+                Token::Uint(U256::from(99)),
+            ],
+        );
+        match decoder.decode(&router, &input, U256::from(7)).unwrap() {
+            RouterIntent::Swap(intent) => {
+                assert_eq!(
+                    intent.amount,
+                    SwapAmount::ExactIn {
+                        amount_in: U256::from(7),
+                        amount_out_min: U256::from(5),
+                    }
+                );
+                assert!(intent.eth_in);
+                assert!(intent.fee_on_transfer);
+                assert_eq!(intent.deadline, U256::from(99));
+            }
+            intent => panic!("Decoded {:?}", intent),
+        }
+    }
+
+    #[test]
+    fn decode_add_liquidity_eth() {
+        let decoder = RouterDecoder::new();
+        let router: Address = address_book::UNISWAP_ROUTER.parse().unwrap();
+        let usdc: Address = address_book::USDC_ADDRESS.parse().unwrap();
+        let input = encode(
+            &decoder,
+            "addLiquidityETH",
+            &[
+                Token::Address(usdc),
+                Token::Uint(U256::from(100)),
+                Token::Uint(U256::from(90)),
+                Token::Uint(U256::from(9)),
+                Token::Address(Address::repeat_byte(0x33)),
+                Token::Uint(U256::from(1)),
+            ],
+        );
+        match decoder.decode(&router, &input, U256::from(10)).unwrap() {
+            RouterIntent::Liquidity(intent) => {
+                assert_eq!(intent.token_b, decoder.weth);
+                assert_eq!(
+                    intent.pair,
+                    "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"
+                        .parse::<Address>()
+                        .unwrap()
+                );
+                assert_eq!(
+                    intent.amount,
+                    LiquidityAmount::Add {
+                        amount_a_desired: U256::from(100),
+                        amount_b_desired: U256::from(10),
+                        amount_a_min: U256::from(90),
+                        amount_b_min: U256::from(9),
+                    }
+                );
+            }
+            intent => panic!("Decoded {:?}", intent),
+        }
+    }
+
+    #[test]
+    fn decode_unknown_calls() {
+        let decoder = RouterDecoder::new();
+        let router: Address = address_book::UNISWAP_ROUTER.parse().unwrap();
+        let input = encode(&decoder, "factory", &[]);
+        assert_eq!(decoder.decode(&router, &input, U256::zero()), None);
+        // Not a known router
+        assert_eq!(
+            decoder.decode(&Address::repeat_byte(0x44), &input, U256::zero()),
+            None
+        );
+    }
+}
@@ -6,12 +6,15 @@ use num_bigint::{BigInt, BigUint};
 use secp256k1::SecretKey;
 use web3::contract::tokens::Tokenize;
 use web3::contract::{Contract, Options};
+use web3::signing::keccak256;
 use web3::transports::WebSocket;
 use web3::types::{Address, BlockId, SignedTransaction, TransactionParameters, U256, U64};
 use web3::Web3;
 
+use crate::bidding::Bid;
 // TODO(Add a quantity struct with a U256 base and all of these conversions built in)
 use crate::constants;
+use crate::gas::MinerPayment;
 
 // It would be ideal to write a 256 bit fixed point math library here using u256 and get
 // rid of bigdecimal, bigint, biguint.
@@ -56,7 +59,7 @@ pub async fn sushi_balance(
     sushi: &Contract<WebSocket>,
     public_key: Address,
     block_number: U64,
-) -> U256 {
+) -> web3::contract::Result<U256> {
     sushi
         .query::<U256, _, _, _>(
             "balanceOf",
@@ -66,7 +69,17 @@ pub async fn sushi_balance(
             BlockId::from(block_number),
         )
         .await
-        .unwrap()
+}
+
+/// Return the 4 byte selector of a contract function
+pub fn selector(function: &web3::ethabi::Function) -> [u8; 4] {
+    let params: Vec<String> = function
+        .inputs
+        .iter()
+        .map(|param| param.kind.to_string())
+        .collect();
+    let hash = keccak256(format!("{}({})", function.name, params.join(",")).as_bytes());
+    [hash[0], hash[1], hash[2], hash[3]]
 }
 
 /// Estimates gas, generates metadata and returns metadata and transaction parameters
@@ -76,10 +89,10 @@ pub async fn generate_contract_transaction(
     params: impl Tokenize + Clone + Debug,
     account: &Address,
     estimate_gas: bool,
-    miner_payment: U256,
+    miner_payment: MinerPayment,
 ) -> Option<TransactionParameters> {
-    // TODO(Maybe support this as an argument for non flashbots integration)
-    let mut gas_price = Some(constants::ZERO_U256);
+    // Estimate without fees, so the balance of the account doesn't matter
+    let gas_price = Some(constants::ZERO_U256);
     let estimated_gas = if estimate_gas {
         let estimated_gas = contract
             .estimate_gas(
@@ -94,6 +107,8 @@ pub async fn generate_contract_transaction(
                     condition: None,
                     transaction_type: None,
                     access_list: None,
+                    max_fee_per_gas: None,
+                    max_priority_fee_per_gas: None,
                 },
             )
             .await;
@@ -117,7 +132,8 @@ pub async fn generate_contract_transaction(
         // This is mainly for miner bribe txs
         U256::from(200001_u64)
     };
-    gas_price = Some(miner_payment / (estimated_gas / 90 * 100));
+    let (max_fee_per_gas, max_priority_fee_per_gas) =
+        miner_payment.fees(estimated_gas * U256::from(90) / U256::from(100));
     let data: web3::types::Bytes = contract
         .abi()
         .function(func)
@@ -130,15 +146,24 @@ pub async fn generate_contract_transaction(
         nonce: None,
         to: Option::from(contract.address()),
         gas: estimated_gas,
-        gas_price,
+        gas_price: None,
         value: Default::default(),
         data,
         chain_id: Some(1_u64),
-        transaction_type: None,
+        transaction_type: Some(U64::from(constants::EIP1559_TRANSACTION_TYPE)),
         access_list: None,
+        max_fee_per_gas: Some(max_fee_per_gas),
+        max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
     })
 }
 
+/// Intrinsic gas of transaction calldata, 4 a zero byte and 16 any other
+pub fn calldata_gas(data: &[u8]) -> u64 {
+    data.iter()
+        .map(|byte| if *byte == 0 { 4 } else { 16 })
+        .sum()
+}
+
 /// Aggregate data about a transaction for profit and loss.
 #[derive(Debug, Clone)]
 pub struct Transaction {
@@ -149,17 +174,29 @@ pub struct Transaction {
     pub estimated_gas: U256,
     pub parameters: TransactionParameters,
     pub signed: Option<SignedTransaction>,
+    // The parameters it was signed with, so it's only signed again when they change
+    pub signed_parameters: Option<TransactionParameters>,
+    // Signed by someone else, such as the target of a backrun, and passed through as is
+    pub external: bool,
+    // Markets and tokens the transaction depends on, which are blamed if it fails
+    pub route: Vec<Address>,
+    // The miner payment bid, for transactions which pay the miner out of their profit
+    pub bid: Option<Bid>,
 }
 
 impl Transaction {
     /// Signs and returns transaction.
     pub async fn sign(&mut self, transport: &Web3<WebSocket>, private_key: &SecretKey) {
+        if self.signed.is_some() && self.signed_parameters.as_ref() == Some(&self.parameters) {
+            return;
+        }
         let signed = transport
             .accounts()
             .sign_transaction(self.parameters.clone(), private_key)
             .await
             .unwrap();
         self.signed = Some(signed);
+        self.signed_parameters = Some(self.parameters.clone());
     }
 }
 
@@ -45,6 +45,9 @@ impl LocalWallet {
             None => utilities::nonce(&self.public_key, transport).await,
         };
         for transaction in transactions {
+            if transaction.external {
+                continue;
+            }
             transaction.parameters.nonce = Some(start_nonce);
             transaction.sign(transport, &self.private_key).await;
             start_nonce += U256::from(1);
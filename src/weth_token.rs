@@ -44,6 +44,10 @@ impl Market for WethEthMarket {
         self.bundle_executor
     }
 
+    fn failure_address(&self) -> Address {
+        self.weth.address()
+    }
+
     fn delta_contracts(&self) -> Vec<Address> {
         // No updates to be made here.
         return vec![];
@@ -53,7 +57,11 @@ impl Market for WethEthMarket {
         Protocol::ERC20
     }
 
-    fn miner_reward_percentage(&self) -> Option<U256> {
+    fn reserves(&self) -> Option<(U256, U256)> {
+        None
+    }
+
+    fn with_swap(&self, _token_in: &Address, _amount_in: &U256) -> Option<Box<dyn Market>> {
         None
     }
 
@@ -120,6 +128,15 @@ impl Market for WethEthMarket {
         }
     }
 
+    // Wrapping is paid for up front
+    fn flash_sell_tokens(
+        &self,
+        _token_in: &Address,
+        _amount_in: &U256,
+    ) -> anyhow::Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
     async fn update(&mut self) {
         // This is a noop, always 1:1
     }
@@ -0,0 +1,259 @@
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use log::warn;
+use web3::contract::{Contract, Options};
+use web3::ethabi::ethereum_types::U256;
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockId, BlockNumber};
+use web3::Web3;
+
+use crate::evm;
+use crate::evm::Call;
+use crate::markets::{Market, Protocol, TokenPair};
+use crate::{address_book, constants, markets};
+
+/// A market for token <-> shares of a Yearn V2 vault
+pub struct YearnVaultMarket {
+    tokens: TokenPair,
+    bundle_executor: Address,
+    token: Contract<WebSocket>,
+    vault: Contract<WebSocket>,
+    // One whole share, 10^decimals
+    share_unit: U256,
+    // Tokens per whole share
+    price_per_share: U256,
+}
+
+impl YearnVaultMarket {
+    pub async fn new(
+        transport: &Web3<WebSocket>,
+        vault_address: Address,
+    ) -> Result<YearnVaultMarket> {
+        let vault = Contract::from_json(
+            transport.eth(),
+            vault_address,
+            include_bytes!("protocols/yearn/v2/abis/vault.json"),
+        )?;
+        let token_address: Address = vault
+            .query("token", (), None, Options::default(), None)
+            .await?;
+        let decimals: U256 = vault
+            .query("decimals", (), None, Options::default(), None)
+            .await?;
+        let token = Contract::from_json(
+            transport.eth(),
+            token_address,
+            include_bytes!("abis/IERC20.json"),
+        )?;
+        let bundle_executor: Address = address_book::MulticallEXECUTOR.parse().unwrap();
+        Ok(YearnVaultMarket {
+            tokens: TokenPair {
+                i: token_address,
+                j: vault_address,
+            },
+            bundle_executor,
+            token,
+            vault,
+            share_unit: U256::exp10(decimals.as_usize()),
+            price_per_share: constants::ZERO_U256,
+        })
+    }
+
+    async fn update_exchange_rate(&mut self) {
+        let price_per_share = self
+            .vault
+            .query::<U256, _, _, _>(
+                "pricePerShare",
+                (),
+                None,
+                Options::default(),
+                BlockId::from(BlockNumber::Latest),
+            )
+            .await;
+        match price_per_share {
+            Ok(price_per_share) => self.price_per_share = price_per_share,
+            Err(error) => warn!(
+                "Failed to update Yearn vault rate for {}: {:?}",
+                self.vault.address(),
+                error
+            ),
+        }
+    }
+
+    /// Shares minted for depositing tokens
+    fn get_deposit_shares(tokens: &U256, share_unit: &U256, price_per_share: &U256) -> U256 {
+        tokens * share_unit / price_per_share
+    }
+
+    /// Tokens paid out for withdrawing shares
+    fn get_withdraw_tokens(shares: &U256, share_unit: &U256, price_per_share: &U256) -> U256 {
+        shares * price_per_share / share_unit
+    }
+}
+
+#[async_trait]
+impl Market for YearnVaultMarket {
+    fn tokens(&self) -> TokenPair {
+        self.tokens
+    }
+
+    fn market_address(&self) -> Address {
+        self.bundle_executor
+    }
+
+    fn failure_address(&self) -> Address {
+        self.vault.address()
+    }
+
+    fn delta_contracts(&self) -> Vec<Address> {
+        // Harvests emit StrategyReported from the vault
+        vec![self.vault.address()]
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::Yearn
+    }
+
+    fn reserves(&self) -> Option<(U256, U256)> {
+        None
+    }
+
+    fn with_swap(&self, _token_in: &Address, _amount_in: &U256) -> Option<Box<dyn Market>> {
+        None
+    }
+
+    fn get_tokens_out(&self, token_in: &Address, token_out: &Address, amount_in: &U256) -> U256 {
+        if self.price_per_share.is_zero() {
+            return constants::ZERO_U256;
+        }
+        if token_in.0 == self.tokens.i.0 && token_out.0 == self.tokens.j.0 {
+            // Token in is the underlying, deposit
+            YearnVaultMarket::get_deposit_shares(amount_in, &self.share_unit, &self.price_per_share)
+        } else if token_in.0 == self.tokens.j.0 && token_out.0 == self.tokens.i.0 {
+            // Token in is the share, withdraw
+            YearnVaultMarket::get_withdraw_tokens(
+                amount_in,
+                &self.share_unit,
+                &self.price_per_share,
+            )
+        } else {
+            constants::ZERO_U256
+        }
+    }
+
+    fn get_tokens_in(&self, token_in: &Address, token_out: &Address, amount_out: &U256) -> U256 {
+        if self.price_per_share.is_zero() {
+            return constants::ZERO_U256;
+        }
+        if token_in.0 == self.tokens.i.0 && token_out.0 == self.tokens.j.0 {
+            // Token in is the underlying, deposit
+            amount_out * self.price_per_share / self.share_unit + constants::ONE_U256
+        } else if token_in.0 == self.tokens.j.0 && token_out.0 == self.tokens.i.0 {
+            // Token in is the share, withdraw
+            amount_out * self.share_unit / self.price_per_share + constants::ONE_U256
+        } else {
+            constants::ZERO_U256
+        }
+    }
+
+    fn sell_tokens(
+        &self,
+        token_in: &Address,
+        amount_in: &U256,
+        recipient: &Address,
+    ) -> Result<Vec<Call>> {
+        if token_in.0 == self.tokens.i.0 {
+            // Underlying
+            // Approve and deposit token -> shares, minted to the recipient
+            Ok(vec![
+                Call::from_contract(
+                    &self.token,
+                    "approve",
+                    (self.vault.address(), *amount_in),
+                    evm::Type::Call,
+                    None,
+                )?,
+                Call::from_contract(
+                    &self.vault,
+                    "deposit",
+                    (*amount_in, *recipient),
+                    evm::Type::Call,
+                    None,
+                )?,
+            ])
+        } else if token_in.0 == self.tokens.j.0 {
+            // Shares
+            // Withdraw shares -> token, sent to the recipient
+            Ok(vec![Call::from_contract(
+                &self.vault,
+                "withdraw",
+                (*amount_in, *recipient),
+                evm::Type::Call,
+                None,
+            )?])
+        } else {
+            Err(Error::from(markets::TokenInputError::InvalidToken))
+        }
+    }
+
+    // Deposits are paid for up front
+    fn flash_sell_tokens(
+        &self,
+        _token_in: &Address,
+        _amount_in: &U256,
+    ) -> Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
+    async fn update(&mut self) {
+        self.update_exchange_rate().await
+    }
+
+    fn receive_directly(&self, _token_address: &Address) -> bool {
+        // Yes because the market is the executor
+        true
+    }
+
+    fn to_first_market(
+        &self,
+        _token_address: &Address,
+        _amount: &U256,
+    ) -> Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+
+    fn prepare_receive(&self, _token_address: &Address) -> Result<Option<Vec<Call>>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deposits_and_withdrawals_at_the_share_price() {
+        // An 18 decimal vault at 1.25 tokens a share
+        let share_unit = constants::ETHER;
+        let price_per_share = constants::ETHER * 5 / 4;
+        let shares = YearnVaultMarket::get_deposit_shares(
+            &(constants::ETHER * 10),
+            &share_unit,
+            &price_per_share,
+        );
+        assert_eq!(shares, constants::ETHER * 8);
+        assert_eq!(
+            YearnVaultMarket::get_withdraw_tokens(&shares, &share_unit, &price_per_share),
+            constants::ETHER * 10
+        );
+        // Both directions round down
+        assert_eq!(
+            YearnVaultMarket::get_withdraw_tokens(&U256::one(), &share_unit, &price_per_share),
+            U256::one()
+        );
+        assert_eq!(
+            YearnVaultMarket::get_deposit_shares(&U256::one(), &share_unit, &price_per_share),
+            constants::ZERO_U256
+        );
+    }
+}